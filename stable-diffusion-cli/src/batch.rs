@@ -0,0 +1,291 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+use stable_diffusion::*;
+
+use crate::generate::{output_path, save, GenerationArguments};
+
+/// A batch entry. The missing parameters are the ones of the command line.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatchEntry {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub negative_prompt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub style_prompt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub negative_style_prompt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub steps: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub guidance_scale: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variation_seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variation_strength: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub img2img: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strength: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mask: Option<PathBuf>,
+    /// Output path pattern of the entry, overriding the one of the command line.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+}
+
+impl From<&GenerationArguments> for BatchEntry {
+    fn from(arguments: &GenerationArguments) -> Self {
+        let arguments = arguments.clone();
+        Self {
+            prompt: arguments.prompt,
+            negative_prompt: Some(arguments.negative_prompt).filter(|prompt| !prompt.is_empty()),
+            style_prompt: arguments.style_prompt,
+            negative_style_prompt: arguments.negative_style_prompt,
+            width: arguments.width,
+            height: arguments.height,
+            steps: arguments.steps,
+            guidance_scale: arguments.guidance_scale,
+            seed: arguments.seed,
+            variation_seed: arguments.variation_seed,
+            variation_strength: Some(arguments.variation_strength).filter(|strength| *strength != 0.0),
+            strength: arguments.img2img.is_some().then_some(arguments.strength),
+            img2img: arguments.img2img,
+            mask: arguments.mask,
+            output: None,
+        }
+    }
+}
+
+impl BatchEntry {
+    /// Get the generation arguments of the entry, falling back on the command line ones.
+    pub fn arguments(&self, defaults: &GenerationArguments) -> GenerationArguments {
+        let defaults = defaults.clone();
+        GenerationArguments {
+            prompt: self.prompt.clone().or(defaults.prompt),
+            negative_prompt: self.negative_prompt.clone().unwrap_or(defaults.negative_prompt),
+            style_prompt: self.style_prompt.clone().or(defaults.style_prompt),
+            negative_style_prompt: self.negative_style_prompt.clone().or(defaults.negative_style_prompt),
+            width: self.width.or(defaults.width),
+            height: self.height.or(defaults.height),
+            steps: self.steps.or(defaults.steps),
+            guidance_scale: self.guidance_scale.or(defaults.guidance_scale),
+            seed: self.seed.or(defaults.seed),
+            variation_seed: self.variation_seed.or(defaults.variation_seed),
+            variation_strength: self.variation_strength.unwrap_or(defaults.variation_strength),
+            img2img: self.img2img.clone().or(defaults.img2img),
+            strength: self.strength.unwrap_or(defaults.strength),
+            mask: self.mask.clone().or(defaults.mask),
+        }
+    }
+}
+
+/// The result of a batch entry, appended to the manifest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchResult {
+    pub index: usize,
+    pub prompt: String,
+    pub seed: u64,
+    pub output: PathBuf,
+    pub seconds: f64,
+    /// The error of a failed entry, which is generated again when the batch is resumed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A list of batch entries read from a JSONL or a CSV file.
+#[derive(Debug, Clone, Default)]
+pub struct Batch {
+    pub entries: Vec<BatchEntry>,
+}
+
+impl Batch {
+    /// Read the entries from a CSV file if it has the `.csv` extension, from a JSONL file otherwise.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let is_csv = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("csv"));
+        let entries = if is_csv {
+            csv::Reader::from_path(path)?
+                .deserialize()
+                .enumerate()
+                .map(|(index, entry)| entry.map_err(|error| anyhow::anyhow!("entry {index} of {}: {error}", path.display())))
+                .collect::<anyhow::Result<Vec<_>>>()?
+        } else {
+            Self::from_jsonl(std::io::BufReader::new(std::fs::File::open(path)?))?
+        };
+        Ok(Self { entries })
+    }
+
+    /// Read the entries from JSON lines, skipping the empty lines.
+    fn from_jsonl(reader: impl BufRead) -> anyhow::Result<Vec<BatchEntry>> {
+        let mut entries = Vec::new();
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry = serde_json::from_str(&line).map_err(|error| anyhow::anyhow!("line {}: {error}", number + 1))?;
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    /// Read the results of a manifest whose outputs still exist, by entry index.
+    fn completed(manifest: &Path) -> anyhow::Result<HashSet<usize>> {
+        if !manifest.exists() {
+            return Ok(HashSet::new());
+        }
+        let reader = std::io::BufReader::new(std::fs::File::open(manifest)?);
+        let mut completed = HashSet::new();
+        for line in reader.lines() {
+            let line = line?;
+            if let Ok(result) = serde_json::from_str::<BatchResult>(&line) {
+                if result.error.is_none() && result.output.exists() {
+                    completed.insert(result.index);
+                }
+            }
+        }
+        Ok(completed)
+    }
+
+    /// Check that the entries sharing an output path pattern are told apart by their `{index}`.
+    fn check_outputs(&self, output: &str) -> anyhow::Result<()> {
+        let mut entries = HashMap::<&str, usize>::new();
+        for entry in &self.entries {
+            *entries.entry(entry.output.as_deref().unwrap_or(output)).or_default() += 1;
+        }
+        match entries.into_iter().find(|(pattern, count)| *count > 1 && !pattern.contains("{index}")) {
+            Some((pattern, count)) => anyhow::bail!("the output path {pattern} of {count} entries must contain {{index}}"),
+            None => Ok(()),
+        }
+    }
+
+    /// Generate an entry and save its image.
+    fn generate(stable_diffusion: &StableDiffusion, arguments: &GenerationArguments, seed: u64, output: &Path) -> anyhow::Result<()> {
+        let parameters = arguments.parameters()?.with_seed(Some(seed));
+        let image = stable_diffusion.generate(parameters)?;
+        save(&image, output)
+    }
+
+    /// Generate the entries, skipping the ones whose outputs already exist, and append their results to the manifest.
+    /// A failed entry is logged and recorded in the manifest, and the next entries are still generated.
+    pub fn execute(&self, stable_diffusion: &StableDiffusion, defaults: &GenerationArguments, output: &str, manifest: &Path) -> anyhow::Result<()> {
+        self.check_outputs(output)?;
+        let completed = Self::completed(manifest)?;
+        let mut manifest_file = std::fs::OpenOptions::new().create(true).append(true).open(manifest)?;
+        let start = Instant::now();
+        let (mut generated, mut failed) = (0, 0);
+        for (index, entry) in self.entries.iter().enumerate() {
+            let arguments = entry.arguments(defaults);
+            let pattern = entry.output.as_deref().unwrap_or(output);
+            // Without a random seed the output path is known before generating.
+            let known_output = arguments.seed.map(|seed| output_path(pattern, index, seed));
+            if completed.contains(&index) || known_output.is_some_and(|path| path.exists()) {
+                tracing::info!(index, "skipping completed entry");
+                continue;
+            }
+            let seed = arguments.seed.unwrap_or_else(|| rand::random::<u32>() as u64);
+            let output = output_path(pattern, index, seed);
+            let entry_start = Instant::now();
+            let error = Self::generate(stable_diffusion, &arguments, seed, &output).err().map(|error| error.to_string());
+            let prompt = arguments.prompt.unwrap_or_default();
+            let result = BatchResult { index, prompt, seed, output, seconds: entry_start.elapsed().as_secs_f64(), error };
+            writeln!(manifest_file, "{}", serde_json::to_string(&result)?)?;
+            match &result.error {
+                Some(error) => {
+                    failed += 1;
+                    tracing::error!(index, total = self.entries.len(), %error, "entry failed");
+                }
+                None => {
+                    generated += 1;
+                    tracing::info!(index, total = self.entries.len(), seconds = result.seconds, "entry generated");
+                }
+            }
+        }
+        let skipped = self.entries.len() - generated - failed;
+        tracing::info!(generated, failed, skipped, seconds = start.elapsed().as_secs_f64(), "batch done");
+        if failed > 0 {
+            anyhow::bail!("{failed} entries failed, their errors are in {}", manifest.display());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::generate::test::arguments;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("stable-diffusion-batch-{name}"));
+        std::fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    fn entries_inherit_the_command_line_arguments() {
+        let defaults = arguments(&["--prompt", "a cat", "--negative-prompt", "blurry", "--steps", "20", "--seed", "7"]);
+        let entry = BatchEntry { prompt: Some("a dog".into()), seed: Some(9), width: Some(768), ..Default::default() };
+        let arguments = entry.arguments(&defaults);
+        assert_eq!((arguments.prompt.as_deref(), arguments.negative_prompt.as_str()), (Some("a dog"), "blurry"));
+        assert_eq!((arguments.width, arguments.steps, arguments.seed), (Some(768), Some(20), Some(9)));
+        assert_eq!(BatchEntry::default().arguments(&defaults).prompt.as_deref(), Some("a cat"));
+    }
+
+    #[test]
+    fn entries_are_read_from_jsonl_and_csv() {
+        let directory = temp_dir("read");
+        let jsonl = directory.join("prompts.jsonl");
+        std::fs::write(&jsonl, "{\"prompt\": \"a cat\", \"seed\": 1}\n\n{\"prompt\": \"a dog\", \"steps\": 4}\n").unwrap();
+        let entries = Batch::from_file(&jsonl).unwrap().entries;
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].prompt.as_deref(), entries[0].seed), (Some("a cat"), Some(1)));
+        assert_eq!((entries[1].prompt.as_deref(), entries[1].steps), (Some("a dog"), Some(4)));
+
+        let csv = directory.join("prompts.CSV");
+        std::fs::write(&csv, "prompt,seed,guidance_scale\na cat,1,\na dog,,5.5\n").unwrap();
+        let entries = Batch::from_file(&csv).unwrap().entries;
+        assert_eq!((entries[0].seed, entries[0].guidance_scale), (Some(1), None));
+        assert_eq!((entries[1].seed, entries[1].guidance_scale), (None, Some(5.5)));
+
+        std::fs::write(&jsonl, "{\"prompt\": \"a cat\", \"sampler\": \"euler\"}\n").unwrap();
+        assert!(Batch::from_file(&jsonl).unwrap_err().to_string().starts_with("line 1:"));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn completed_entries_have_an_existing_output() {
+        let directory = temp_dir("completed");
+        let manifest = directory.join("prompts.results.jsonl");
+        assert!(Batch::completed(&manifest).unwrap().is_empty());
+        let output = directory.join("0.png");
+        std::fs::write(&output, "").unwrap();
+        let result = |index, output: PathBuf| serde_json::to_string(&BatchResult { index, prompt: "a cat".into(), seed: 1, output, seconds: 1.0, error: None }).unwrap();
+        let failed = serde_json::to_string(&BatchResult { error: Some("out of memory".into()), ..serde_json::from_str(&result(3, output.clone())).unwrap() }).unwrap();
+        let lines = [result(0, output), result(1, directory.join("1.png")), "{\"index\": 2".to_string(), failed];
+        std::fs::write(&manifest, lines.join("\n")).unwrap();
+        assert_eq!(Batch::completed(&manifest).unwrap(), HashSet::from([0]));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn shared_output_paths_need_an_index() {
+        let batch = Batch { entries: vec![BatchEntry::default(), BatchEntry::default()] };
+        assert!(batch.check_outputs("out-{index}.png").is_ok());
+        assert!(batch.check_outputs("out-{seed}.png").is_err());
+        let entry = |output: &str| BatchEntry { output: Some(output.into()), ..Default::default() };
+        assert!(Batch { entries: vec![entry("cat.png"), entry("dog.png")] }.check_outputs("out.png").is_ok());
+        assert!(Batch { entries: vec![entry("cat.png"), BatchEntry::default()] }.check_outputs("out.png").is_ok());
+        assert!(Batch { entries: vec![entry("cat.png"), entry("cat.png")] }.check_outputs("out-{index}.png").is_err());
+    }
+}
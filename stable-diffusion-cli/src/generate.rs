@@ -0,0 +1,194 @@
+use std::path::{Path, PathBuf};
+
+use stable_diffusion::*;
+
+use clap::Args;
+
+use crate::batch::Batch;
+use crate::model::ModelArguments;
+
+#[derive(Args, Debug, Clone)]
+pub struct GenerationArguments {
+    /// Prompt describing the image.
+    #[arg(short, long)]
+    pub prompt: Option<String>,
+
+    /// Negative prompt describing what the image shouldn't look like.
+    #[arg(long, default_value = "")]
+    pub negative_prompt: String,
+
+    /// Prompt of the second text encoder of the XL versions, defaults to the prompt.
+    #[arg(long)]
+    pub style_prompt: Option<String>,
+
+    /// Negative prompt of the second text encoder of the XL versions, defaults to the negative prompt.
+    #[arg(long)]
+    pub negative_style_prompt: Option<String>,
+
+    /// Width of the image, defaults to the version's size.
+    #[arg(long)]
+    pub width: Option<usize>,
+
+    /// Height of the image, defaults to the version's size.
+    #[arg(long)]
+    pub height: Option<usize>,
+
+    /// Number of denoising steps, defaults to the version's number of steps.
+    #[arg(long)]
+    pub steps: Option<usize>,
+
+    /// Classifier-free guidance scale, defaults to the version's guidance scale.
+    #[arg(long)]
+    pub guidance_scale: Option<f64>,
+
+    /// Seed of the first image, the next images increase it by one. Random if not set.
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    /// Seed of the noise mixed into the seed noise.
+    #[arg(long)]
+    pub variation_seed: Option<u64>,
+
+    /// Strength of the variation noise, from 0 to 1.
+    #[arg(long, default_value_t = 0.0)]
+    pub variation_strength: f64,
+
+    /// Image to start from instead of pure noise.
+    #[arg(long)]
+    pub img2img: Option<PathBuf>,
+
+    /// How much the image to image input is changed, from 0 to 1.
+    #[arg(long, default_value_t = 0.5)]
+    pub strength: f64,
+
+    /// Mask of the pixels to repaint, white, for the inpainting versions.
+    #[arg(long)]
+    pub mask: Option<PathBuf>,
+}
+
+impl GenerationArguments {
+    pub fn parameters(&self) -> anyhow::Result<GenerationParameters> {
+        let img2img = match &self.img2img {
+            Some(path) => Some(image::open(path)?.to_rgb8()),
+            None => None,
+        };
+        let mask = match &self.mask {
+            Some(path) => Some(image::open(path)?.to_luma8()),
+            None => None,
+        };
+        let parameters = GenerationParameters::new(self.prompt.clone().unwrap_or_default())
+            .with_uncond_prompt(self.negative_prompt.clone())
+            .with_style_prompt(self.style_prompt.clone())
+            .with_uncond_style_prompt(self.negative_style_prompt.clone())
+            .with_width(self.width)
+            .with_height(self.height)
+            .with_n_steps(self.steps)
+            .with_guidance_scale(self.guidance_scale)
+            .with_seed(self.seed)
+            .with_variation_seed(self.variation_seed)
+            .with_variation_strength(self.variation_strength)
+            .with_img2img(img2img)
+            .with_img2img_strength(self.strength)
+            .with_mask(mask);
+        parameters.validate()?;
+        Ok(parameters)
+    }
+}
+
+/// Get the output path of an image from a pattern with the `{index}` and `{seed}` placeholders.
+pub fn output_path(pattern: &str, index: usize, seed: u64) -> PathBuf {
+    pattern
+        .replace("{index}", &index.to_string())
+        .replace("{seed}", &seed.to_string())
+        .into()
+}
+
+/// Save an image, creating its folder if needed.
+pub fn save(image: &image::RgbImage, path: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    image.save(path)?;
+    tracing::info!(path = %path.display(), "image saved");
+    Ok(())
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct Arguments {
+    #[command(flatten)]
+    model: ModelArguments,
+
+    #[command(flatten)]
+    generation: GenerationArguments,
+
+    /// Number of images to generate.
+    #[arg(short = 'n', long, default_value_t = 1, conflicts_with = "batch")]
+    count: usize,
+
+    /// Output path pattern, where `{index}` is the image number, or the batch entry number, and `{seed}` its seed.
+    #[arg(short, long, default_value = "output-{index}-{seed}.png")]
+    output: String,
+
+    /// JSONL or CSV file with a parameter set per entry, the missing parameters being the ones of the command line.
+    #[arg(long)]
+    batch: Option<PathBuf>,
+
+    /// JSONL file the results of the batch entries are appended to, defaults to the batch file with a `.results.jsonl` extension.
+    #[arg(long, requires = "batch")]
+    manifest: Option<PathBuf>,
+}
+
+impl Arguments {
+    pub fn execute(self) -> anyhow::Result<()> {
+        if let Some(path) = &self.batch {
+            let batch = Batch::from_file(path)?;
+            let manifest = self.manifest.clone().unwrap_or_else(|| path.with_extension("results.jsonl"));
+            let stable_diffusion = self.model.load()?;
+            return batch.execute(&stable_diffusion, &self.generation, &self.output, &manifest);
+        }
+        if self.generation.prompt.is_none() {
+            anyhow::bail!("a prompt or a batch file is required");
+        }
+        let parameters = self.generation.parameters()?;
+        let stable_diffusion = self.model.load()?;
+        let seed = parameters.seed.unwrap_or_else(|| rand::random::<u32>() as u64);
+        for index in 0 .. self.count {
+            let seed = seed.wrapping_add(index as u64);
+            let image = stable_diffusion.generate(parameters.clone().with_seed(Some(seed)))?;
+            save(&image, &output_path(&self.output, index, seed))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Command {
+        #[command(flatten)]
+        generation: GenerationArguments,
+    }
+
+    /// Parse the generation arguments of a command line.
+    pub fn arguments(args: &[&str]) -> GenerationArguments {
+        Command::parse_from(std::iter::once("test").chain(args.iter().copied())).generation
+    }
+
+    #[test]
+    fn output_paths_replace_the_placeholders() {
+        assert_eq!(output_path("output-{index}-{seed}.png", 3, 42), PathBuf::from("output-3-42.png"));
+        assert_eq!(output_path("{seed}/{index}/{seed}.png", 0, 7), PathBuf::from("7/0/7.png"));
+        assert_eq!(output_path("output.png", 3, 42), PathBuf::from("output.png"));
+    }
+
+    #[test]
+    fn arguments_are_turned_into_parameters() {
+        let parameters = arguments(&["--prompt", "a cat", "--width", "512", "--seed", "7"]).parameters().unwrap();
+        assert_eq!((parameters.prompt.as_str(), parameters.uncond_prompt.as_str()), ("a cat", ""));
+        assert_eq!((parameters.width, parameters.height, parameters.seed), (Some(512), None, Some(7)));
+        assert!(arguments(&["--width", "500"]).parameters().is_err());
+    }
+}
@@ -1,31 +1,69 @@
 #![doc=include_str!("../README.md")]
 
 use clap::{Parser, Subcommand};
+use tracing_subscriber::EnvFilter;
 
+mod batch;
+mod generate;
+mod model;
+mod models;
+mod repl;
+mod serve;
 mod train;
 
 #[derive(Debug, Parser, Clone)]
 #[command(author, version, about, long_about = None)]
 pub struct Arguments {
+    /// Log as JSON lines instead of human readable text.
+    #[arg(long, global = true)]
+    log_json: bool,
+
     #[command(subcommand)]
     command: Command
 }
 
 #[derive(Debug, Subcommand, Clone)]
 #[command(author, version, about, long_about = None)]
+// The command is parsed once, its size doesn't matter.
+#[allow(clippy::large_enum_variant)]
 pub enum Command {
     /// Train a Stable Diffusion model
     Train(train::Arguments),
+    /// Generate images with a Stable Diffusion model
+    Generate(generate::Arguments),
+    /// Generate images interactively, keeping the model loaded
+    Repl(repl::Arguments),
+    /// Serve an A1111-compatible generation API
+    Serve(serve::Arguments),
+    /// Manage the local model library
+    Models(models::Arguments),
 }
 
 impl Arguments {
     pub fn execute(self) -> anyhow::Result<()> {
         match self.command {
             Command::Train(args) => args.execute(),
+            Command::Generate(args) => args.execute(),
+            Command::Repl(args) => args.execute(),
+            Command::Serve(args) => args.execute(),
+            Command::Models(args) => args.execute(),
+        }
+    }
+
+    fn init_logging(&self) {
+        let subscriber = tracing_subscriber::fmt()
+            .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+            .with_writer(std::io::stderr);
+        if self.log_json {
+            subscriber.json().init();
+        } else {
+            subscriber.init();
         }
     }
 }
 
 fn main() -> anyhow::Result<()> {
-    Arguments::parse().execute()
+    let arguments = Arguments::parse();
+    arguments.init_logging();
+    arguments.execute()
 }
@@ -0,0 +1,150 @@
+use stable_diffusion::*;
+
+use clap::Args;
+
+/// Parse a Stable Diffusion version from its name.
+fn parse_version(name: &str) -> anyhow::Result<StableDiffusionVersion> {
+    let version = match name.to_lowercase().as_str() {
+        "1.5" => StableDiffusionVersion::V1_5,
+        "1.5-inpaint" => StableDiffusionVersion::V1_5Inpaint,
+        "2.0" => StableDiffusionVersion::V2_0,
+        "2.0-base" => StableDiffusionVersion::V2_0Base,
+        "2.1" => StableDiffusionVersion::V2_1,
+        "2.1-base" => StableDiffusionVersion::V2_1Base,
+        "sd-turbo" => StableDiffusionVersion::SdTurbo,
+        "xl" => StableDiffusionVersion::XL,
+        "xl-inpaint" => StableDiffusionVersion::XLInpaint,
+        "xl-turbo" => StableDiffusionVersion::Turbo,
+        "lcm-1.5" => StableDiffusionVersion::LcmV1_5,
+        "lcm-xl" => StableDiffusionVersion::LcmXL,
+        _ => anyhow::bail!("unknown version {name}, expected one of 1.5, 1.5-inpaint, 2.0, 2.0-base, 2.1, 2.1-base, sd-turbo, xl, xl-inpaint, xl-turbo, lcm-1.5, lcm-xl"),
+    };
+    Ok(version)
+}
+
+/// Parse a data type from its name.
+fn parse_dtype(name: &str) -> anyhow::Result<DType> {
+    match name.to_lowercase().as_str() {
+        "f16" => Ok(DType::F16),
+        "bf16" => Ok(DType::BF16),
+        "f32" => Ok(DType::F32),
+        _ => anyhow::bail!("unknown data type {name}, expected f16, bf16 or f32"),
+    }
+}
+
+/// The device to run the models on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceArgument {
+    /// The first GPU if the CLI was built with GPU support, the CPU otherwise.
+    Auto,
+    Cpu,
+    Cuda(usize),
+    Metal(usize),
+}
+
+impl std::str::FromStr for DeviceArgument {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> anyhow::Result<Self> {
+        let (kind, ordinal) = match name.split_once(':') {
+            Some((kind, ordinal)) => (kind, ordinal.parse()?),
+            None => (name, 0),
+        };
+        match kind.to_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "cpu" => Ok(Self::Cpu),
+            "cuda" => Ok(Self::Cuda(ordinal)),
+            "metal" => Ok(Self::Metal(ordinal)),
+            _ => anyhow::bail!("unknown device {name}, expected auto, cpu, cuda[:N] or metal[:N]"),
+        }
+    }
+}
+
+impl DeviceArgument {
+    pub fn device(&self) -> anyhow::Result<Device> {
+        let device = match self {
+            Self::Auto if cfg!(feature = "cuda") => Device::new_cuda(0)?,
+            Self::Auto if cfg!(feature = "metal") => Device::new_metal(0)?,
+            Self::Auto | Self::Cpu => Device::Cpu,
+            Self::Cuda(ordinal) => Device::new_cuda(*ordinal)?,
+            Self::Metal(ordinal) => Device::new_metal(*ordinal)?,
+        };
+        Ok(device)
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ModelArguments {
+    /// Stable Diffusion version: 1.5, 1.5-inpaint, 2.0, 2.0-base, 2.1, 2.1-base, sd-turbo, xl, xl-inpaint, xl-turbo, lcm-1.5 or lcm-xl.
+    #[arg(long, default_value = "1.5", value_parser = parse_version)]
+    pub version: StableDiffusionVersion,
+
+    /// Hugging Face repository or local folder with the diffusers weights, defaults to the version's repository.
+    #[arg(long)]
+    pub model: Option<String>,
+
+    /// Data type of the weights: f16, bf16 or f32, defaults to f16 on GPUs and f32 on the CPU.
+    #[arg(long, value_parser = parse_dtype)]
+    pub dtype: Option<DType>,
+
+    /// Device to run the models on: auto, cpu, cuda[:N] or metal[:N].
+    #[arg(long, default_value = "auto")]
+    pub device: DeviceArgument,
+}
+
+impl ModelArguments {
+    /// The name the model is reported with.
+    pub fn name(&self) -> String {
+        self.model.clone().unwrap_or_else(|| format!("{:?}", self.version))
+    }
+
+    /// The data type of the weights on a device, since f16 is slow on the CPU.
+    pub fn dtype(&self, device: &Device) -> DType {
+        self.dtype.unwrap_or(if device.is_cpu() { DType::F32 } else { DType::F16 })
+    }
+
+    pub fn load(&self) -> anyhow::Result<StableDiffusion> {
+        let device = self.device.device()?;
+        let dtype = self.dtype(&device);
+        let weights = match &self.model {
+            Some(model) => StableDiffusionWeights::from_repository(self.version, Some(model.clone()), dtype),
+            None => StableDiffusionWeights::new(self.version, dtype),
+        };
+        let parameters = StableDiffusionParameters::new(weights, device, dtype)?;
+        tracing::info!(model = self.name(), "loading model");
+        Ok(StableDiffusion::new(parameters)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn devices_are_parsed() {
+        assert_eq!("auto".parse::<DeviceArgument>().unwrap(), DeviceArgument::Auto);
+        assert_eq!("CPU".parse::<DeviceArgument>().unwrap(), DeviceArgument::Cpu);
+        assert_eq!("cuda".parse::<DeviceArgument>().unwrap(), DeviceArgument::Cuda(0));
+        assert_eq!("cuda:1".parse::<DeviceArgument>().unwrap(), DeviceArgument::Cuda(1));
+        assert_eq!("metal:2".parse::<DeviceArgument>().unwrap(), DeviceArgument::Metal(2));
+        assert!("cuda:first".parse::<DeviceArgument>().is_err());
+        assert!("tpu".parse::<DeviceArgument>().is_err());
+    }
+
+    #[test]
+    fn versions_and_data_types_are_parsed() {
+        assert!(matches!(parse_version("XL").unwrap(), StableDiffusionVersion::XL));
+        assert!(matches!(parse_version("lcm-1.5").unwrap(), StableDiffusionVersion::LcmV1_5));
+        assert!(parse_version("3.0").is_err());
+        assert_eq!(parse_dtype("BF16").unwrap(), DType::BF16);
+        assert!(parse_dtype("f64").is_err());
+    }
+
+    #[test]
+    fn data_type_defaults_to_the_device() {
+        let arguments = ModelArguments { version: StableDiffusionVersion::V1_5, model: None, dtype: None, device: DeviceArgument::Cpu };
+        assert_eq!(arguments.dtype(&Device::Cpu), DType::F32);
+        let arguments = ModelArguments { dtype: Some(DType::BF16), ..arguments };
+        assert_eq!(arguments.dtype(&Device::Cpu), DType::BF16);
+    }
+}
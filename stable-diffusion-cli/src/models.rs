@@ -0,0 +1,109 @@
+use std::path::PathBuf;
+
+use stable_diffusion::*;
+
+use clap::{Args, Subcommand};
+
+/// Parse a model kind from its name.
+fn parse_kind(name: &str) -> anyhow::Result<ModelKind> {
+    Ok(name.parse()?)
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct Arguments {
+    #[command(subcommand)]
+    command: ModelsCommand,
+}
+
+#[derive(Debug, Subcommand, Clone)]
+enum ModelsCommand {
+    /// List the checkpoints, LoRAs, VAEs, embeddings and ControlNets of the model folders
+    List(ListArguments),
+}
+
+#[derive(Args, Debug, Clone)]
+struct ListArguments {
+    /// Folder to scan recursively for safetensors files. Can be repeated.
+    #[arg(short, long = "folder", required = true)]
+    folders: Vec<PathBuf>,
+
+    /// Index file caching the scan, defaults to `.models.json` in the first folder.
+    #[arg(long)]
+    index: Option<PathBuf>,
+
+    /// Only list the models of a kind: checkpoint, lora, vae, embedding or controlnet.
+    #[arg(long, value_parser = parse_kind)]
+    kind: Option<ModelKind>,
+
+    /// Print the entries as JSON lines, with their training metadata.
+    #[arg(long)]
+    json: bool,
+}
+
+impl ListArguments {
+    /// Get the line of an entry of the kind to list, if it is.
+    fn line(&self, entry: &ModelEntry) -> anyhow::Result<Option<String>> {
+        if self.kind.is_some_and(|kind| entry.kind != kind) {
+            return Ok(None);
+        }
+        if self.json {
+            return Ok(Some(serde_json::to_string(entry)?));
+        }
+        let base = entry.base.map_or("-", |base| base.as_str());
+        Ok(Some(format!("{:<10} {:<5} {} {}:{}  {}", entry.kind.as_str(), base, entry.short_hash(), entry.kind.as_str(), entry.name, entry.path.display())))
+    }
+
+    fn execute(self) -> anyhow::Result<()> {
+        let index = self.index.clone().unwrap_or_else(|| self.folders[0].join(".models.json"));
+        let mut library = ModelLibrary::new(self.folders.clone()).with_index(index);
+        for entry in library.scan()? {
+            if let Some(line) = self.line(entry)? {
+                println!("{line}");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Arguments {
+    pub fn execute(self) -> anyhow::Result<()> {
+        match self.command {
+            ModelsCommand::List(args) => args.execute(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Command {
+        #[command(flatten)]
+        models: Arguments,
+    }
+
+    fn list_arguments(args: &[&str]) -> ListArguments {
+        let ModelsCommand::List(arguments) = Command::parse_from(["test", "list"].iter().chain(args)).models.command;
+        arguments
+    }
+
+    fn entry(kind: ModelKind) -> ModelEntry {
+        let sha256 = "0123456789abcdef".into();
+        let metadata = Default::default();
+        ModelEntry { name: "detail".into(), path: "loras/detail.safetensors".into(), kind, base: None, sha256, size: 0, modified: 0, metadata }
+    }
+
+    #[test]
+    fn entries_are_filtered_by_kind() {
+        let arguments = list_arguments(&["--folder", "models", "--kind", "LoRA"]);
+        assert_eq!(arguments.line(&entry(ModelKind::Lora)).unwrap().unwrap(), "lora       -     0123456789 lora:detail  loras/detail.safetensors");
+        assert_eq!(arguments.line(&entry(ModelKind::Vae)).unwrap(), None);
+        assert!(list_arguments(&["--folder", "models"]).line(&entry(ModelKind::Vae)).unwrap().is_some());
+        let line = list_arguments(&["--folder", "models", "--json"]).line(&entry(ModelKind::Lora)).unwrap().unwrap();
+        assert_eq!(serde_json::from_str::<ModelEntry>(&line).unwrap().name, "detail");
+        assert!(Command::try_parse_from(["test", "list", "--folder", "models", "--kind", "upscaler"]).is_err());
+        assert!(Command::try_parse_from(["test", "list"]).is_err());
+    }
+}
@@ -0,0 +1,221 @@
+use std::io::Write;
+use std::time::Instant;
+
+use stable_diffusion::*;
+
+use clap::Args;
+
+use crate::batch::BatchEntry;
+use crate::generate::{output_path, save, GenerationArguments};
+use crate::model::ModelArguments;
+
+const HELP: &str = "\
+Type a prompt to generate an image with the current settings, or a command:
+  :set <name> <value>  Set a setting: negative_prompt, style_prompt, negative_style_prompt, width, height,
+                       steps, guidance_scale, seed, variation_seed, variation_strength, img2img, strength, mask or output
+  :unset <name>        Reset a setting to its default
+  :show                Show the settings
+  :history             Show the generated entries
+  :save <path>         Save the history as a JSONL batch file
+  :help                Show this help
+  :quit                Exit";
+
+#[derive(Args, Debug, Clone)]
+pub struct Arguments {
+    #[command(flatten)]
+    model: ModelArguments,
+
+    #[command(flatten)]
+    generation: GenerationArguments,
+
+    /// Output path pattern, where `{index}` is the image number in the session and `{seed}` its seed.
+    #[arg(short, long, default_value = "repl-{index}-{seed}.png")]
+    output: String,
+}
+
+/// The state of a REPL session.
+struct Session {
+    settings: GenerationArguments,
+    defaults: GenerationArguments,
+    output: String,
+    default_output: String,
+    history: Vec<BatchEntry>,
+}
+
+impl Session {
+    fn new(settings: GenerationArguments, output: String) -> Self {
+        let defaults = settings.clone();
+        let default_output = output.clone();
+        let history = Vec::new();
+        Self { settings, defaults, output, default_output, history }
+    }
+
+    /// Parse an optional value, where `none` unsets it.
+    fn optional<T: std::str::FromStr>(value: &str) -> anyhow::Result<Option<T>>
+    where T::Err: std::error::Error + Send + Sync + 'static {
+        if value.eq_ignore_ascii_case("none") {
+            Ok(None)
+        } else {
+            Ok(Some(value.parse()?))
+        }
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> anyhow::Result<()> {
+        let settings = &mut self.settings;
+        match name {
+            "prompt" => settings.prompt = Some(value.to_string()),
+            "negative_prompt" => settings.negative_prompt = value.to_string(),
+            "style_prompt" => settings.style_prompt = Self::optional(value)?,
+            "negative_style_prompt" => settings.negative_style_prompt = Self::optional(value)?,
+            "width" => settings.width = Self::optional(value)?,
+            "height" => settings.height = Self::optional(value)?,
+            "steps" => settings.steps = Self::optional(value)?,
+            "guidance_scale" => settings.guidance_scale = Self::optional(value)?,
+            "seed" => settings.seed = Self::optional(value)?,
+            "variation_seed" => settings.variation_seed = Self::optional(value)?,
+            "variation_strength" => settings.variation_strength = value.parse()?,
+            "img2img" => settings.img2img = Self::optional(value)?,
+            "strength" => settings.strength = value.parse()?,
+            "mask" => settings.mask = Self::optional(value)?,
+            "output" => self.output = value.to_string(),
+            _ => anyhow::bail!("unknown setting {name}, type :help to list them"),
+        }
+        Ok(())
+    }
+
+    fn unset(&mut self, name: &str) -> anyhow::Result<()> {
+        let defaults = self.defaults.clone();
+        let settings = &mut self.settings;
+        match name {
+            "prompt" => settings.prompt = defaults.prompt,
+            "negative_prompt" => settings.negative_prompt = defaults.negative_prompt,
+            "style_prompt" => settings.style_prompt = defaults.style_prompt,
+            "negative_style_prompt" => settings.negative_style_prompt = defaults.negative_style_prompt,
+            "width" => settings.width = defaults.width,
+            "height" => settings.height = defaults.height,
+            "steps" => settings.steps = defaults.steps,
+            "guidance_scale" => settings.guidance_scale = defaults.guidance_scale,
+            "seed" => settings.seed = defaults.seed,
+            "variation_seed" => settings.variation_seed = defaults.variation_seed,
+            "variation_strength" => settings.variation_strength = defaults.variation_strength,
+            "img2img" => settings.img2img = defaults.img2img,
+            "strength" => settings.strength = defaults.strength,
+            "mask" => settings.mask = defaults.mask,
+            "output" => self.output = self.default_output.clone(),
+            _ => anyhow::bail!("unknown setting {name}, type :help to list them"),
+        }
+        Ok(())
+    }
+
+    fn save_history(&self, path: &str) -> anyhow::Result<()> {
+        let mut file = std::fs::File::create(path)?;
+        for entry in &self.history {
+            writeln!(file, "{}", serde_json::to_string(entry)?)?;
+        }
+        println!("saved {} entries to {path}", self.history.len());
+        Ok(())
+    }
+
+    fn generate(&mut self, stable_diffusion: &StableDiffusion, prompt: &str) -> anyhow::Result<()> {
+        let mut arguments = self.settings.clone();
+        arguments.prompt = Some(prompt.to_string());
+        arguments.seed = Some(arguments.seed.unwrap_or_else(|| rand::random::<u32>() as u64));
+        let parameters = arguments.parameters()?;
+        let start = Instant::now();
+        let image = stable_diffusion.generate(parameters)?;
+        let index = self.history.len();
+        let path = output_path(&self.output, index, arguments.seed.unwrap_or_default());
+        save(&image, &path)?;
+        println!("{} ({:.1}s)", path.display(), start.elapsed().as_secs_f64());
+        self.history.push(BatchEntry::from(&arguments));
+        Ok(())
+    }
+
+    /// Execute a line, returning `false` when the session ends.
+    fn execute(&mut self, stable_diffusion: &StableDiffusion, line: &str) -> anyhow::Result<bool> {
+        let line = line.trim();
+        match line.strip_prefix(':') {
+            Some(command) => self.command(command),
+            None if line.is_empty() => Ok(true),
+            None => {
+                self.generate(stable_diffusion, line)?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Execute a command without its `:` prefix, returning `false` when the session ends.
+    fn command(&mut self, command: &str) -> anyhow::Result<bool> {
+        let mut words = command.splitn(3, char::is_whitespace);
+        match (words.next().unwrap_or_default(), words.next(), words.next()) {
+            ("set", Some(name), Some(value)) => self.set(name, value.trim())?,
+            ("unset", Some(name), None) => self.unset(name)?,
+            ("show", None, None) => println!("{:#?}\noutput: {}", self.settings, self.output),
+            ("history", None, None) => {
+                for (index, entry) in self.history.iter().enumerate() {
+                    println!("{index}: {}", serde_json::to_string(entry)?);
+                }
+            }
+            ("save", Some(path), None) => self.save_history(path)?,
+            ("help", None, None) => println!("{HELP}"),
+            ("quit" | "exit" | "q", None, None) => return Ok(false),
+            _ => anyhow::bail!("invalid command :{command}, type :help to list the commands"),
+        }
+        Ok(true)
+    }
+}
+
+impl Arguments {
+    pub fn execute(self) -> anyhow::Result<()> {
+        let stable_diffusion = self.model.load()?;
+        let mut session = Session::new(self.generation, self.output);
+        println!("{HELP}");
+        let stdin = std::io::stdin();
+        loop {
+            print!("> ");
+            std::io::stdout().flush()?;
+            let mut line = String::new();
+            if stdin.read_line(&mut line)? == 0 {
+                break;
+            }
+            match session.execute(&stable_diffusion, &line) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(error) => eprintln!("error: {error}"),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::generate::test::arguments;
+
+    #[test]
+    fn settings_are_set_and_unset() {
+        let mut session = Session::new(arguments(&["--steps", "20", "--negative-prompt", "blurry"]), "repl-{index}.png".into());
+        assert!(session.command("set steps 4").unwrap());
+        assert!(session.command("set negative_prompt low quality, jpeg").unwrap());
+        assert!(session.command("set seed none").unwrap());
+        assert!(session.command("set output  out/{seed}.png").unwrap());
+        assert_eq!((session.settings.steps, session.settings.negative_prompt.as_str()), (Some(4), "low quality, jpeg"));
+        assert_eq!((session.settings.seed, session.output.as_str()), (None, "out/{seed}.png"));
+
+        session.command("unset steps").unwrap();
+        session.command("unset output").unwrap();
+        assert_eq!((session.settings.steps, session.output.as_str()), (Some(20), "repl-{index}.png"));
+        assert_eq!(session.settings.negative_prompt, "low quality, jpeg");
+    }
+
+    #[test]
+    fn invalid_commands_are_rejected() {
+        let mut session = Session::new(arguments(&[]), "repl.png".into());
+        assert!(session.command("set steps many").is_err());
+        assert!(session.command("set sampler euler").is_err());
+        assert!(session.command("set steps").is_err());
+        assert!(session.command("unset steps now").is_err());
+        assert!(!session.command("quit").unwrap());
+    }
+}
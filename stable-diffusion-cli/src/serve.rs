@@ -0,0 +1,425 @@
+use std::io::{Cursor, Read};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Instant;
+
+use base64::Engine;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use stable_diffusion::*;
+
+use clap::Args;
+
+use crate::model::ModelArguments;
+
+#[derive(Args, Debug, Clone)]
+pub struct Arguments {
+    #[command(flatten)]
+    model: ModelArguments,
+
+    /// Address to listen on.
+    #[arg(long, default_value = "127.0.0.1")]
+    host: String,
+
+    /// Port to listen on.
+    #[arg(long, default_value_t = 7860)]
+    port: u16,
+
+    /// Number of requests waiting to be generated before new ones are rejected.
+    #[arg(long, default_value_t = 16)]
+    queue_size: usize,
+
+    /// Maximum number of images of a request, i.e. `batch_size * n_iter`.
+    #[arg(long, default_value_t = 16)]
+    max_images: usize,
+
+    /// Maximum size of a request body, in bytes.
+    #[arg(long, default_value_t = 32 * 1024 * 1024)]
+    max_body_size: usize,
+
+    /// Number of threads handling the connections. A request waiting for its images holds a thread.
+    #[arg(long, default_value_t = 32)]
+    threads: usize,
+}
+
+/// The body of the `txt2img` and `img2img` requests. The fields the engine doesn't support are ignored.
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+struct GenerationRequest {
+    prompt: String,
+    negative_prompt: String,
+    width: Option<usize>,
+    height: Option<usize>,
+    steps: Option<usize>,
+    cfg_scale: Option<f64>,
+    seed: Option<i64>,
+    batch_size: Option<usize>,
+    n_iter: Option<usize>,
+    init_images: Vec<String>,
+    denoising_strength: Option<f64>,
+    mask: Option<String>,
+    inpainting_mask_invert: u8,
+}
+
+/// A generation waiting in the queue, answered with the images and their seeds.
+struct Job {
+    /// The endpoint of the request, `txt2img` or `img2img`.
+    name: &'static str,
+    parameters: GenerationParameters,
+    seeds: Vec<u64>,
+    reply: mpsc::Sender<Result<Vec<(image::RgbImage, u64)>, HttpError>>,
+}
+
+/// The progress of the job being generated.
+#[derive(Default)]
+struct Progress {
+    job: &'static str,
+    job_count: usize,
+    job_no: usize,
+    sampling_step: usize,
+    sampling_steps: usize,
+    started: Option<Instant>,
+}
+
+impl Progress {
+    /// Lock the progress, which stays readable if a generation panicked while holding it.
+    fn lock(progress: &Mutex<Self>) -> MutexGuard<'_, Self> {
+        progress.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn to_json(&self) -> Value {
+        let fraction = if self.job_count == 0 || self.sampling_steps == 0 {
+            0.0
+        } else {
+            (self.job_no as f64 + self.sampling_step as f64 / self.sampling_steps as f64) / self.job_count as f64
+        };
+        let eta_relative = match self.started {
+            Some(started) if fraction > 0.0 => {
+                let elapsed = started.elapsed().as_secs_f64();
+                elapsed / fraction - elapsed
+            }
+            _ => 0.0,
+        };
+        json!({
+            "progress": fraction,
+            "eta_relative": eta_relative,
+            "state": {
+                "skipped": false,
+                "interrupted": false,
+                "job": self.job,
+                "job_count": self.job_count,
+                "job_timestamp": "0",
+                "job_no": self.job_no,
+                "sampling_step": self.sampling_step,
+                "sampling_steps": self.sampling_steps,
+            },
+            "current_image": null,
+            "textinfo": null,
+        })
+    }
+}
+
+/// The state shared by the request handlers.
+#[derive(Clone)]
+struct Server {
+    queue: SyncSender<Job>,
+    progress: Arc<Mutex<Progress>>,
+    model_name: String,
+    max_images: usize,
+    max_body_size: usize,
+}
+
+/// An HTTP error response.
+#[derive(Debug)]
+struct HttpError {
+    status: u16,
+    message: String,
+}
+
+impl HttpError {
+    fn new(status: u16, message: impl ToString) -> Self {
+        let message = message.to_string();
+        Self { status, message }
+    }
+}
+
+impl From<StableDiffusionError> for HttpError {
+    fn from(error: StableDiffusionError) -> Self {
+        match error {
+            StableDiffusionError::InvalidParameters(_) | StableDiffusionError::ShapeMismatch(_) | StableDiffusionError::VersionMismatch(_) => Self::new(422, error),
+            _ => Self::new(500, error),
+        }
+    }
+}
+
+fn decode_image(data: &str) -> Result<image::DynamicImage, HttpError> {
+    // Clients may send data URLs, e.g. `data:image/png;base64,...`.
+    let data = data.split_once(";base64,").map_or(data, |(_, data)| data);
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(data.trim())
+        .map_err(|error| HttpError::new(422, format!("invalid base64 image: {error}")))?;
+    image::load_from_memory(&bytes).map_err(|error| HttpError::new(422, format!("invalid image: {error}")))
+}
+
+fn encode_image(image: &image::RgbImage) -> Result<String, HttpError> {
+    let mut bytes = Cursor::new(Vec::new());
+    image.write_to(&mut bytes, image::ImageOutputFormat::Png).map_err(|error| HttpError::new(500, error))?;
+    Ok(base64::engine::general_purpose::STANDARD.encode(bytes.into_inner()))
+}
+
+impl GenerationRequest {
+    /// Get the parameters of the request and the seed of every image, increasing from the requested one.
+    fn parameters(&self, max_images: usize) -> Result<(GenerationParameters, Vec<u64>), HttpError> {
+        let count = self.batch_size.unwrap_or(1).max(1)
+            .checked_mul(self.n_iter.unwrap_or(1).max(1))
+            .filter(|count| *count <= max_images)
+            .ok_or_else(|| HttpError::new(422, format!("a request can generate at most {max_images} images")))?;
+        if self.mask.is_some() && self.init_images.is_empty() {
+            return Err(HttpError::new(422, "a mask requires an init image"));
+        }
+        let mut parameters = GenerationParameters::new(&self.prompt)
+            .with_uncond_prompt(self.negative_prompt.clone())
+            .with_width(self.width)
+            .with_height(self.height)
+            .with_n_steps(self.steps)
+            .with_guidance_scale(self.cfg_scale);
+        if let Some(init_image) = self.init_images.first() {
+            let mut image = decode_image(init_image)?;
+            if let (Some(width), Some(height)) = (self.width, self.height) {
+                image = image.resize_to_fill(width as u32, height as u32, image::imageops::FilterType::Lanczos3);
+            }
+            let image = image.to_rgb8();
+            let mask = match &self.mask {
+                Some(mask) => {
+                    let mut mask = decode_image(mask)?
+                        .resize_exact(image.width(), image.height(), image::imageops::FilterType::Nearest)
+                        .to_luma8();
+                    if self.inpainting_mask_invert == 1 {
+                        image::imageops::invert(&mut mask);
+                    }
+                    Some(mask)
+                }
+                None => None,
+            };
+            parameters = parameters
+                .with_img2img(Some(image))
+                .with_img2img_strength(self.denoising_strength.unwrap_or(0.75))
+                .with_mask(mask);
+        }
+        let seed = match self.seed {
+            Some(seed) if seed >= 0 => seed as u64,
+            _ => rand::random::<u32>() as u64,
+        };
+        parameters.validate()?;
+        let seeds = (0 .. count as u64).map(|index| seed.wrapping_add(index)).collect();
+        Ok((parameters, seeds))
+    }
+}
+
+impl Server {
+    fn generate(&self, name: &'static str, body: Value) -> Result<Value, HttpError> {
+        let request = GenerationRequest::deserialize(&body).map_err(|error| HttpError::new(422, error))?;
+        let (parameters, seeds) = request.parameters(self.max_images)?;
+        let (reply, receiver) = mpsc::channel();
+        match self.queue.try_send(Job { name, parameters, seeds, reply }) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => return Err(HttpError::new(503, "the queue is full")),
+            Err(TrySendError::Disconnected(_)) => return Err(HttpError::new(500, "the generation worker stopped")),
+        }
+        let images = receiver
+            .recv()
+            .map_err(|_| HttpError::new(500, "the generation worker stopped"))??;
+        let seeds = images.iter().map(|(_, seed)| *seed).collect::<Vec<_>>();
+        let images = images.iter().map(|(image, _)| encode_image(image)).collect::<Result<Vec<_>, _>>()?;
+        let info = json!({
+            "prompt": request.prompt,
+            "negative_prompt": request.negative_prompt,
+            "seed": seeds.first(),
+            "all_seeds": seeds,
+            "sd_model_name": self.model_name,
+        });
+        Ok(json!({ "images": images, "parameters": body, "info": info.to_string() }))
+    }
+
+    fn options(&self) -> Value {
+        json!({ "sd_model_checkpoint": self.model_name, "samples_format": "png" })
+    }
+
+    fn handle(&self, mut request: tiny_http::Request) {
+        let method = request.method().clone();
+        let path = request.url().split('?').next().unwrap_or_default().to_string();
+        let mut body = String::new();
+        let result = match request.as_reader().take(self.max_body_size as u64 + 1).read_to_string(&mut body) {
+            Err(error) => Err(HttpError::new(400, error)),
+            Ok(size) if size > self.max_body_size => Err(HttpError::new(413, format!("the body is larger than {} bytes", self.max_body_size))),
+            Ok(_) => {
+                let json = || if body.trim().is_empty() {
+                    Ok(json!({}))
+                } else {
+                    serde_json::from_str::<Value>(&body).map_err(|error| HttpError::new(422, error))
+                };
+                match (&method, path.as_str()) {
+                    (tiny_http::Method::Post, "/sdapi/v1/txt2img") => json().and_then(|body| self.generate("txt2img", body)),
+                    (tiny_http::Method::Post, "/sdapi/v1/img2img") => json().and_then(|body| self.generate("img2img", body)),
+                    (tiny_http::Method::Get, "/sdapi/v1/options") => Ok(self.options()),
+                    // The model is loaded once, the options are accepted but not applied.
+                    (tiny_http::Method::Post, "/sdapi/v1/options") => json().map(|_| Value::Null),
+                    (tiny_http::Method::Get, "/sdapi/v1/progress") => Ok(Progress::lock(&self.progress).to_json()),
+                    _ => Err(HttpError::new(404, "not found")),
+                }
+            }
+        };
+        let (status, body) = match result {
+            Ok(body) => (200, body),
+            Err(error) => {
+                tracing::warn!(status = error.status, %method, %path, "{}", error.message);
+                (error.status, json!({ "error": error.message, "detail": error.message }))
+            }
+        };
+        let header = tiny_http::Header::from_bytes("Content-Type", "application/json").expect("valid header");
+        let response = tiny_http::Response::from_string(body.to_string()).with_status_code(status).with_header(header);
+        if let Err(error) = request.respond(response) {
+            tracing::warn!(%error, "failed to respond");
+        }
+    }
+}
+
+/// Generate the queued jobs one at a time. A panicking generation fails its job instead of stopping the worker.
+fn work(jobs: mpsc::Receiver<Job>, progress: &Mutex<Progress>, generate: impl Fn(GenerationParameters) -> Result<image::RgbImage>) {
+    for job in jobs {
+        *Progress::lock(progress) = Progress { job: job.name, job_count: job.seeds.len(), started: Some(Instant::now()), ..Default::default() };
+        let mut images = Vec::new();
+        let mut result = Ok(());
+        for (index, seed) in job.seeds.into_iter().enumerate() {
+            Progress::lock(progress).job_no = index;
+            let parameters = job.parameters.clone().with_seed(Some(seed));
+            match std::panic::catch_unwind(AssertUnwindSafe(|| generate(parameters))) {
+                Ok(Ok(image)) => images.push((image, seed)),
+                Ok(Err(error)) => {
+                    result = Err(HttpError::from(error));
+                    break;
+                }
+                Err(panic) => {
+                    let message = panic.downcast_ref::<&str>().copied()
+                        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                        .unwrap_or("unknown error");
+                    tracing::error!(job = job.name, seed, "the generation panicked: {message}");
+                    result = Err(HttpError::new(500, format!("the generation panicked: {message}")));
+                    break;
+                }
+            }
+        }
+        *Progress::lock(progress) = Progress::default();
+        // The client may have disconnected while waiting.
+        job.reply.send(result.map(|_| images)).ok();
+    }
+}
+
+impl Arguments {
+    pub fn execute(self) -> anyhow::Result<()> {
+        let progress = Arc::new(Mutex::new(Progress::default()));
+        let (queue, jobs) = mpsc::sync_channel(self.queue_size);
+        let (ready, loaded) = mpsc::channel();
+        {
+            let model = self.model.clone();
+            let progress = progress.clone();
+            std::thread::spawn(move || {
+                let mut stable_diffusion = match model.load() {
+                    Ok(stable_diffusion) => stable_diffusion,
+                    Err(error) => return ready.send(Err(error)).expect("the server waits for the model"),
+                };
+                let step_progress = progress.clone();
+                stable_diffusion.set_progress_callback(Some(Arc::new(move |step, steps| {
+                    let mut progress = Progress::lock(&step_progress);
+                    progress.sampling_step = step;
+                    progress.sampling_steps = steps;
+                })));
+                ready.send(Ok(())).expect("the server waits for the model");
+                work(jobs, &progress, |parameters| stable_diffusion.generate(parameters));
+            });
+        }
+        loaded.recv()??;
+
+        let address = format!("{}:{}", self.host, self.port);
+        let http = Arc::new(tiny_http::Server::http(&address).map_err(|error| anyhow::anyhow!("failed to listen on {address}: {error}"))?);
+        tracing::info!(%address, threads = self.threads, "serving");
+        let server = Server { queue, progress, model_name: self.model.name(), max_images: self.max_images, max_body_size: self.max_body_size };
+        let handlers = (0 .. self.threads.max(1)).map(|_| {
+            let (http, server) = (http.clone(), server.clone());
+            std::thread::spawn(move || {
+                for request in http.incoming_requests() {
+                    server.handle(request);
+                }
+            })
+        }).collect::<Vec<_>>();
+        for handler in handlers {
+            handler.join().map_err(|_| anyhow::anyhow!("a connection handler panicked"))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(body: Value) -> GenerationRequest {
+        GenerationRequest::deserialize(&body).unwrap()
+    }
+
+    #[test]
+    fn a1111_fields_are_mapped() {
+        let body = json!({ "prompt": "a cat", "negative_prompt": "blurry", "width": 512, "height": 768, "steps": 20, "cfg_scale": 5.5, "seed": 7, "batch_size": 2, "n_iter": 3, "sampler_name": "Euler a" });
+        let (parameters, seeds) = request(body).parameters(16).unwrap();
+        assert_eq!((parameters.prompt.as_str(), parameters.uncond_prompt.as_str()), ("a cat", "blurry"));
+        assert_eq!((parameters.width, parameters.height, parameters.n_steps, parameters.guidance_scale), (Some(512), Some(768), Some(20), Some(5.5)));
+        assert_eq!(seeds, [7, 8, 9, 10, 11, 12]);
+    }
+
+    #[test]
+    fn init_images_and_masks_are_decoded() {
+        let image = encode_image(&image::RgbImage::from_pixel(16, 8, image::Rgb([255, 0, 0]))).unwrap();
+        let mask = encode_image(&image::RgbImage::from_pixel(16, 8, image::Rgb([255, 255, 255]))).unwrap();
+        let body = json!({ "init_images": [format!("data:image/png;base64,{image}")], "mask": mask, "inpainting_mask_invert": 1, "denoising_strength": 0.4 });
+        let (parameters, _) = request(body).parameters(16).unwrap();
+        assert_eq!(parameters.img2img.map(|image| image.dimensions()), Some((16, 8)));
+        assert_eq!(parameters.img2img_strength, 0.4);
+        assert!(parameters.mask.unwrap().pixels().all(|pixel| pixel[0] == 0));
+    }
+
+    #[test]
+    fn image_counts_are_bounded() {
+        let status = |body: Value| request(body).parameters(16).err().map(|error| error.status);
+        assert_eq!(status(json!({ "batch_size": 4, "n_iter": 4 })), None);
+        assert_eq!(status(json!({ "batch_size": 4, "n_iter": 5 })), Some(422));
+        assert_eq!(status(json!({ "batch_size": usize::MAX, "n_iter": 2 })), Some(422));
+        assert_eq!(status(json!({ "width": 100 })), Some(422));
+        assert_eq!(status(json!({ "mask": "" })), Some(422));
+    }
+
+    #[test]
+    fn worker_survives_panicking_generations() {
+        let (queue, jobs) = mpsc::sync_channel(2);
+        let mut replies = Vec::new();
+        for name in ["txt2img", "img2img"] {
+            let (reply, receiver) = mpsc::channel();
+            queue.send(Job { name, parameters: GenerationParameters::new(name), seeds: vec![u64::MAX], reply }).unwrap();
+            replies.push(receiver);
+        }
+        drop(queue);
+        let progress = Mutex::new(Progress::default());
+        work(jobs, &progress, |parameters| {
+            let _progress = Progress::lock(&progress);
+            match parameters.prompt.as_str() {
+                "txt2img" => panic!("out of memory"),
+                _ => Ok(image::RgbImage::new(8, 8)),
+            }
+        });
+        let error = replies[0].recv().unwrap().unwrap_err();
+        assert_eq!((error.status, error.message.as_str()), (500, "the generation panicked: out of memory"));
+        assert_eq!(replies[1].recv().unwrap().unwrap()[0].1, u64::MAX);
+        assert!(progress.is_poisoned());
+        assert_eq!(Progress::lock(&progress).job, "");
+    }
+}
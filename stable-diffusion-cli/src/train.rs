@@ -64,7 +64,7 @@ impl Arguments {
         } else {
             let config = self.workflow.as_ref().ok_or_else(|| anyhow::anyhow!("No config file provided."))?;
             let workflow = Workflow::from_file(config, self.input)?;
-            println!("{:#?}", workflow);
+            tracing::debug!(?workflow, "workflow loaded");
             Trainer::new().start(&workflow);
         }
         Ok(())
@@ -38,6 +38,7 @@ impl Trainer {
     /// Start the training process.
     pub fn start(&mut self, parameters: &Workflow) {
         let training_dir = Self::training_dir();
+        let _span = tracing::info_span!("training", training_dir = %training_dir.display()).entered();
         self.activate();
         if let Some(captioning) = parameters.captioning.as_ref() {
             self.caption(parameters, captioning);
@@ -125,7 +126,7 @@ impl Trainer {
                     }
                 },
                 None => {
-                    println!("Failed to get extension of: {}", txt.display())
+                    tracing::warn!("Failed to get extension of: {}", txt.display())
                 }
             }
         }
@@ -172,7 +173,7 @@ impl Trainer {
         bucketing.set_parameters(&mut command);
     }
 
-    println!("{:?}", command);
+    tracing::debug!(?command, "launching training");
 
     command
         .status()
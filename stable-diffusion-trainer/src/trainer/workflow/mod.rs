@@ -23,10 +23,8 @@ pub struct Workflow {
 impl Workflow {
     /// Load workflow from a file.
     pub fn from_file(path: impl Into<std::path::PathBuf>, input: Option<std::path::PathBuf>) -> anyhow::Result<Self> {
-        let training = crate::Model::default();
-        println!("{}", serde_json::to_string_pretty(&training)?);
-
         let path = path.into().canonicalize()?;
+        tracing::debug!(path = %path.display(), "loading workflow");
         let parent = path.parent().unwrap().to_path_buf();
 
         let time: String = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs().to_string();
@@ -0,0 +1,123 @@
+//! Prompt and latent interpolation between keyframes for animations.
+
+use std::path::Path;
+
+use image::{codecs::gif::{GifEncoder, Repeat}, Delay, Frame};
+
+use crate::{slerp, Conditioning, GenerationParameters, Noise, Result, StableDiffusion, StableDiffusionError};
+
+/// The `Keyframe` struct is used to specify a prompt and a seed the animation passes through.
+#[derive(Debug, Clone)]
+pub struct Keyframe {
+    /// The prompt of the keyframe.
+    pub prompt: String,
+    /// The seed of the keyframe.
+    pub seed: u64,
+}
+
+impl Keyframe {
+    /// Create a new `Keyframe` instance from a prompt and a seed.
+    pub fn new(prompt: impl Into<String>, seed: u64) -> Self {
+        let prompt = prompt.into();
+        Self { prompt, seed }
+    }
+}
+
+/// The `Animation` struct is used to hold the rendered frames of an animation.
+///
+/// The `image` crate can't encode animated WebP, so WebP loops are encoded from the numbered PNGs by external tools.
+pub struct Animation {
+    /// The rendered frames.
+    pub frames: Vec<image::ImageBuffer<image::Rgb<u8>, Vec<u8>>>,
+}
+
+impl Animation {
+    /// Save the frames as numbered PNGs, e.g. `00000.png`, `00001.png`, in a directory.
+    pub fn save_frames(&self, directory: impl AsRef<Path>) -> Result<()> {
+        let directory = directory.as_ref();
+        std::fs::create_dir_all(directory)?;
+        for (index, frame) in self.frames.iter().enumerate() {
+            frame.save(directory.join(format!("{index:05}.png")))?;
+        }
+        Ok(())
+    }
+
+    /// Save the frames as a looping animated GIF with the given frames per second.
+    pub fn save_gif(&self, path: impl AsRef<Path>, fps: u32) -> Result<()> {
+        let file = std::fs::File::create(path)?;
+        let mut encoder = GifEncoder::new(std::io::BufWriter::new(file));
+        encoder.set_repeat(Repeat::Infinite)?;
+        let delay = Delay::from_numer_denom_ms(1000, fps.max(1));
+        let frames = self.frames.iter().map(|frame| {
+            let frame = image::DynamicImage::ImageRgb8(frame.clone()).into_rgba8();
+            Frame::from_parts(frame, 0, 0, delay)
+        });
+        encoder.encode_frames(frames)?;
+        Ok(())
+    }
+}
+
+impl StableDiffusion {
+    /// Render an animation interpolating the text embeddings linearly and the initial noise spherically between keyframes.
+    ///
+    /// The `parameters` are used for every frame, except for the prompt and the seeds which come from the keyframes.
+    pub fn animate(&self, keyframes: &[Keyframe], frames: usize, parameters: impl Into<GenerationParameters>) -> Result<Animation> {
+        if keyframes.len() < 2 {
+            return Err(StableDiffusionError::invalid_parameters("an animation requires at least two keyframes"));
+        }
+        let parameters = parameters.into();
+        parameters.validate()?;
+        let shape = self.latent_shape(&parameters);
+        let mut embeddings = Vec::with_capacity(keyframes.len());
+        let mut noises = Vec::with_capacity(keyframes.len());
+        for keyframe in keyframes {
+            let parameters = GenerationParameters { prompt: keyframe.prompt.clone(), prompt_embeds: None, ..parameters.clone() };
+            embeddings.push(self.text_embeddings(&parameters)?);
+            noises.push(Noise::new(keyframe.seed).generate(shape, &self.device)?);
+        }
+
+        let segments = keyframes.len() - 1;
+        let mut animation = Animation { frames: Vec::with_capacity(frames) };
+        for frame in 0 .. frames {
+            let (segment, t) = keyframe_position(frame, frames, segments);
+            let (from, to) = (&embeddings[segment], &embeddings[segment + 1]);
+            let text_embeddings = (from + ((to - from)? * t)?)?;
+            let noise = slerp(t, &noises[segment], &noises[segment + 1])?;
+            let _span = tracing::info_span!("frame", frame = frame + 1, frames).entered();
+            animation.frames.push(self.sample(&parameters, &Conditioning::from(text_embeddings), &noise)?);
+        }
+        Ok(animation)
+    }
+}
+
+/// Get the segment between two keyframes a frame is in and its interpolation factor, the first and last frames being on keyframes.
+fn keyframe_position(frame: usize, frames: usize, segments: usize) -> (usize, f64) {
+    let position = if frames > 1 { frame as f64 * segments as f64 / (frames - 1) as f64 } else { 0.0 };
+    let segment = (position.floor() as usize).min(segments - 1);
+    (segment, position - segment as f64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn frames_pass_through_keyframes() {
+        let positions = (0 .. 5).map(|frame| keyframe_position(frame, 5, 2)).collect::<Vec<_>>();
+        assert_eq!(positions, vec![(0, 0.0), (0, 0.5), (1, 0.0), (1, 0.5), (1, 1.0)]);
+        assert_eq!(keyframe_position(0, 1, 3), (0, 0.0));
+    }
+
+    #[test]
+    fn frames_are_saved() {
+        let directory = std::env::temp_dir().join("stable-diffusion-animation-test");
+        let frames = (0 .. 3).map(|index| image::ImageBuffer::from_pixel(4, 4, image::Rgb([index * 100, 0, 0]))).collect();
+        let animation = Animation { frames };
+        animation.save_frames(&directory).unwrap();
+        assert_eq!(image::open(directory.join("00002.png")).unwrap().to_rgb8().get_pixel(0, 0)[0], 200);
+        animation.save_gif(directory.join("animation.gif"), 12).unwrap();
+        let decoder = image::codecs::gif::GifDecoder::new(std::fs::File::open(directory.join("animation.gif")).unwrap()).unwrap();
+        assert_eq!(image::AnimationDecoder::into_frames(decoder).count(), 3);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
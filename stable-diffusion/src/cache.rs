@@ -0,0 +1,86 @@
+//! Text embedding cache for repeated prompts.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use candle::Tensor;
+
+/// An entry of the cache: the identity of the encoder and the tokens, with their embeddings.
+type Entry = ((usize, Vec<u32>), Tensor);
+
+/// The `EmbeddingCache` struct is used to keep the most recently used text embeddings.
+///
+/// The embeddings are keyed by the identity of the text encoder and the tokenized prompt.
+pub struct EmbeddingCache {
+    capacity: usize,
+    entries: Mutex<VecDeque<Entry>>,
+}
+
+impl EmbeddingCache {
+    /// Create a new `EmbeddingCache` instance holding at most `capacity` embeddings.
+    pub fn new(capacity: usize) -> Self {
+        let entries = Mutex::new(VecDeque::with_capacity(capacity));
+        Self { capacity, entries }
+    }
+
+    /// Get the capacity of the cache.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Get the embeddings of the tokens encoded by an encoder, marking them as the most recently used.
+    pub fn get(&self, encoder: usize, tokens: &[u32]) -> Option<Tensor> {
+        let mut entries = self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let index = entries.iter().position(|((id, key), _)| *id == encoder && key == tokens)?;
+        let entry = entries.remove(index)?;
+        let embeddings = entry.1.clone();
+        entries.push_front(entry);
+        Some(embeddings)
+    }
+
+    /// Insert the embeddings of the tokens encoded by an encoder, evicting the least recently used ones if the cache is full.
+    pub fn insert(&self, encoder: usize, tokens: Vec<u32>, embeddings: Tensor) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        entries.retain(|((id, key), _)| !(*id == encoder && *key == tokens));
+        entries.truncate(self.capacity - 1);
+        entries.push_front(((encoder, tokens), embeddings));
+    }
+
+    /// Remove every embedding.
+    pub fn clear(&self) {
+        self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clear();
+    }
+}
+
+impl Default for EmbeddingCache {
+    fn default() -> Self {
+        Self::new(32)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use candle::{DType, Device};
+
+    #[test]
+    fn least_recently_used_embeddings_are_evicted() {
+        let cache = EmbeddingCache::new(2);
+        let embeddings = |value: f64| Tensor::full(value as f32, (1, 2), &Device::Cpu).unwrap();
+        cache.insert(0, vec![1, 2], embeddings(1.0));
+        cache.insert(0, vec![3], embeddings(2.0));
+        assert!(cache.get(1, &[1, 2]).is_none());
+        let hit = cache.get(0, &[1, 2]).unwrap();
+        assert_eq!(hit.to_dtype(DType::F32).unwrap().to_vec2::<f32>().unwrap(), [[1.0, 1.0]]);
+
+        // `[3]` is now the least recently used entry.
+        cache.insert(0, vec![4], embeddings(3.0));
+        assert!(cache.get(0, &[3]).is_none());
+        assert!(cache.get(0, &[1, 2]).is_some());
+        cache.clear();
+        assert!(cache.get(0, &[4]).is_none());
+    }
+}
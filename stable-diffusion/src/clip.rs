@@ -1,11 +1,17 @@
 //! CLIP (Contrastive Language-Image Pretraining) model.
 
 use candle::{DType, Device, Module, Tensor};
-use candle_transformers::models::stable_diffusion::{self, clip::{self, ClipTextTransformer}};
 
-use crate::{File, StableDiffusionVersion};
+use crate::models::clip::ClipTextTransformer;
+use crate::models::nn::VarBuilder;
+use serde::{Deserialize, Serialize};
+
+use crate::{Architecture, File, Result, StableDiffusionVersion};
+
+pub use crate::models::clip::{Activation, ClipTextConfig};
 
 /// The `CLIPWeights` struct is used to specify the weights of the CLIP model.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CLIPWeights {
     /// The weights of the first CLIP model.
     pub clip: File,
@@ -42,7 +48,7 @@ impl CLIPWeights {
         let repo = repository.into();
         let filename = Self::clip_file(dtype);
         let clip = File::Repository(crate::Repository::new(repo.clone(), filename));
-        let clip2 = if matches!(version, StableDiffusionVersion::XL | StableDiffusionVersion::Turbo) {
+        let clip2 = if version.is_xl() {
             let filename = Self::clip2_file(dtype);
             let clip2 = File::Repository(crate::Repository::new(repo, filename));
             Some(clip2)
@@ -53,6 +59,45 @@ impl CLIPWeights {
     }
 }
 
+impl ClipTextConfig {
+    /// Get the configuration of the first text encoder of a version.
+    pub fn for_version(version: StableDiffusionVersion) -> Self {
+        match version.architecture() {
+            Architecture::V1 => Self::v1_5(),
+            Architecture::V2 => Self::v2_1(),
+            Architecture::XL => Self::sdxl(),
+        }
+    }
+
+    /// Get the configuration of the second text encoder of a version, if it has one.
+    pub fn second_for_version(version: StableDiffusionVersion) -> Option<Self> {
+        if version.is_xl() { Some(Self::sdxl2()) } else { None }
+    }
+}
+
+/// The `PromptEmbeddings` struct is used to hold the text embeddings of a prompt and its unconditional prompt.
+#[derive(Debug, Clone)]
+pub struct PromptEmbeddings {
+    /// The embeddings of the prompt.
+    pub cond: Tensor,
+    /// The embeddings of the unconditional prompt, required when the guidance scale is greater than 1.
+    pub uncond: Option<Tensor>,
+}
+
+impl PromptEmbeddings {
+    /// Create a new `PromptEmbeddings` instance from the embeddings of a prompt and its unconditional prompt.
+    pub fn new(cond: Tensor, uncond: Option<Tensor>) -> Self {
+        Self { cond, uncond }
+    }
+
+    /// Get the embeddings batched as `[uncond, cond]`, or only `cond` if there's no unconditional prompt.
+    pub fn to_tensor(&self) -> Result<Tensor> {
+        match &self.uncond {
+            Some(uncond) => Ok(Tensor::cat(&[uncond, &self.cond], 0)?),
+            None => Ok(self.cond.clone()),
+        }
+    }
+}
 
 /// The `CLIP` struct is used to specify the CLIP model.
 pub struct CLIP {
@@ -61,20 +106,23 @@ pub struct CLIP {
 
 impl CLIP {
     /// Create a new `CLIP` instance from a configuration, weights, device, and data type.
-    pub fn new(config: &clip::Config, weights: impl AsRef<std::path::Path>, device: &Device, dtype: DType) -> anyhow::Result<Self> {
-        let clip = stable_diffusion::build_clip_transformer(config, weights, device, dtype)?;
+    ///
+    /// GGUF weights, e.g. written by `quantize_weights`, are loaded with quantized linear layers.
+    pub fn new(config: &ClipTextConfig, weights: impl AsRef<std::path::Path>, device: &Device, dtype: DType) -> Result<Self> {
+        let vs = VarBuilder::from_file(weights, device, dtype)?;
+        let clip = ClipTextTransformer::new(vs, config)?;
         Ok(Self { clip })
     }
 
     /// Encode text into a tensor.
-    pub fn text_embeddings(&self, prompt_tokens: impl AsRef<[u32]>, device: &Device, dtype: DType) -> anyhow::Result<Tensor> {
+    pub fn text_embeddings(&self, prompt_tokens: impl AsRef<[u32]>, device: &Device, dtype: DType) -> Result<Tensor> {
         let tokens = Tensor::new(prompt_tokens.as_ref(), device)?.unsqueeze(0)?;
         let text_embeddings = self.clip.forward(&tokens)?;
         Ok(text_embeddings.to_dtype(dtype)?)
     }
 
     /// Encode text into a tensor pair.
-    pub fn text_embeddings_pair(&self, prompt_tokens: impl AsRef<[u32]>, uncond_prompt: Option<impl AsRef<[u32]>>, device: &Device, dtype: DType) -> anyhow::Result<Tensor> {
+    pub fn text_embeddings_pair(&self, prompt_tokens: impl AsRef<[u32]>, uncond_prompt: Option<impl AsRef<[u32]>>, device: &Device, dtype: DType) -> Result<Tensor> {
         let tokens = Tensor::new(prompt_tokens.as_ref(), device)?.unsqueeze(0)?;
         let text_embeddings = self.clip.forward(&tokens)?;
 
@@ -0,0 +1,151 @@
+//! Loaded models that can be shared between Stable Diffusion pipelines.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::models::nn::HostWeights;
+use crate::{ClipTextConfig, Component, ControlNet, IPAdapter, OffloadPolicy, Result, StableDiffusionError, StableDiffusionParameters, StableDiffusionVersion, Tokenizer, TokenizerWeights, CLIP, UNet, Upscaler, VAE};
+
+/// The `StableDiffusionComponents` struct is used to hold the models of a Stable Diffusion pipeline.
+///
+/// The models are reference counted, so cloning the components shares them instead of copying their weights.
+#[derive(Clone)]
+pub struct StableDiffusionComponents {
+    /// The UNet model.
+    pub unet: Component<UNet>,
+    /// The VAE model.
+    pub vae: Component<VAE>,
+    /// The first tokenizer.
+    pub tokenizer: Arc<Tokenizer>,
+    /// The first CLIP model.
+    pub clip: Component<CLIP>,
+    /// The second tokenizer, used by the XL versions.
+    pub tokenizer_2: Option<Arc<Tokenizer>>,
+    /// The second CLIP model, used by the XL versions.
+    pub clip_2: Option<Component<CLIP>>,
+    /// The ControlNet models, by the name `ControlImage`s refer to them with.
+    pub controlnets: HashMap<String, Component<ControlNet>>,
+    /// The IP-Adapter model, used by the image prompts.
+    pub ip_adapter: Option<Component<IPAdapter>>,
+    /// The upscaler the decoded images are post-processed with.
+    pub upscaler: Option<Component<Upscaler>>,
+}
+
+impl StableDiffusionComponents {
+    /// Create a new `StableDiffusionComponents` instance from already loaded models.
+    pub fn new(unet: impl Into<Component<UNet>>, vae: impl Into<Component<VAE>>, tokenizer: Arc<Tokenizer>, clip: impl Into<Component<CLIP>>) -> Self {
+        let unet = unet.into();
+        let vae = vae.into();
+        let clip = clip.into();
+        let tokenizer_2 = None;
+        let clip_2 = None;
+        let controlnets = Default::default();
+        let ip_adapter = None;
+        let upscaler = None;
+        Self { unet, vae, tokenizer, clip, tokenizer_2, clip_2, controlnets, ip_adapter, upscaler }
+    }
+
+    /// Load the models from the weights of the parameters, following their offload policy and device placement.
+    pub fn load(parameters: &StableDiffusionParameters) -> Result<Self> {
+        let StableDiffusionParameters { weights, config, dtype, offload, .. } = parameters;
+        let dtype = *dtype;
+        let _span = tracing::info_span!("load_model", version = ?weights.version, ?dtype, ?offload).entered();
+
+        let unet = {
+            let (weights, version, device, sliced_attention_size) = (weights.unet.file.fetch()?, weights.version, parameters.unet_device(), parameters.sliced_attention_size);
+            let _span = tracing::info_span!("load_unet").entered();
+            match offload {
+                OffloadPolicy::None => Component::from(Arc::new(UNet::with_sliced_attention(&weights, version, sliced_attention_size, &device, dtype)?)),
+                OffloadPolicy::Model => {
+                    let weights = HostWeights::load(&weights)?;
+                    Component::offloaded(move || {
+                        tracing::debug_span!("move_unet").in_scope(|| UNet::from_var_builder(weights.var_builder(&device, dtype)?, version, sliced_attention_size))
+                    })
+                }
+            }
+        };
+        let vae = {
+            let (weights, device) = (weights.vae.file.fetch()?, parameters.vae_device());
+            let _span = tracing::info_span!("load_vae").entered();
+            match offload {
+                OffloadPolicy::None => Component::from(Arc::new(VAE::new(config, &weights, &device, dtype)?)),
+                OffloadPolicy::Model => {
+                    let weights = HostWeights::load(&weights)?;
+                    Component::offloaded(move || tracing::debug_span!("move_vae").in_scope(|| VAE::from_host_weights(&weights, &device, dtype)))
+                }
+            }
+        };
+        // The text encoders stay loaded, on the CPU with the `OffloadPolicy::Model` policy.
+        let text_encoder = |config: &ClipTextConfig, weights: &crate::File| -> Result<Component<CLIP>> {
+            let _span = tracing::info_span!("load_text_encoder").entered();
+            Ok(Component::from(Arc::new(CLIP::new(config, weights.fetch()?, &parameters.text_encoder_device(), dtype)?)))
+        };
+        let tokenizer = Arc::new(Tokenizer::new(config, TokenizerWeights::fetch(&weights.tokenizer.tokenizer)?)?);
+        let clip = text_encoder(&ClipTextConfig::for_version(weights.version), &weights.clip.clip)?;
+        let tokenizer_2 = match &weights.tokenizer.tokenizer2 {
+            Some(weights) => Some(Arc::new(Tokenizer::new(config, TokenizerWeights::fetch(weights)?)?)),
+            None => None,
+        };
+        let clip_2 = match (ClipTextConfig::second_for_version(weights.version), &weights.clip.clip2) {
+            (Some(config), Some(weights)) => Some(text_encoder(&config, weights)?),
+            _ => None,
+        };
+        let controlnets = Default::default();
+        let ip_adapter = None;
+        let upscaler = None;
+        tracing::info!("model loaded");
+
+        Ok(Self { unet, vae, tokenizer, clip, tokenizer_2, clip_2, controlnets, ip_adapter, upscaler })
+    }
+
+    /// Sets the UNet model.
+    pub fn with_unet(self, unet: impl Into<Component<UNet>>) -> Self {
+        let unet = unet.into();
+        Self { unet, ..self }
+    }
+
+    /// Sets the VAE model.
+    pub fn with_vae(self, vae: impl Into<Component<VAE>>) -> Self {
+        let vae = vae.into();
+        Self { vae, ..self }
+    }
+
+    /// Sets the first tokenizer and CLIP model.
+    pub fn with_text_encoder(self, tokenizer: Arc<Tokenizer>, clip: impl Into<Component<CLIP>>) -> Self {
+        let clip = clip.into();
+        Self { tokenizer, clip, ..self }
+    }
+
+    /// Sets the second tokenizer and CLIP model.
+    pub fn with_text_encoder_2(self, tokenizer_2: Option<Arc<Tokenizer>>, clip_2: Option<Component<CLIP>>) -> Self {
+        Self { tokenizer_2, clip_2, ..self }
+    }
+
+    /// Adds a ControlNet model with a name. The model must be on the UNet device.
+    pub fn with_controlnet(mut self, name: impl Into<String>, controlnet: impl Into<Component<ControlNet>>) -> Self {
+        self.controlnets.insert(name.into(), controlnet.into());
+        self
+    }
+
+    /// Sets the IP-Adapter model. The model must be on the UNet device.
+    pub fn with_ip_adapter(self, ip_adapter: impl Into<Component<IPAdapter>>) -> Self {
+        let ip_adapter = Some(ip_adapter.into());
+        Self { ip_adapter, ..self }
+    }
+
+    /// Sets the upscaler the decoded images are post-processed with.
+    pub fn with_upscaler(self, upscaler: impl Into<Component<Upscaler>>) -> Self {
+        let upscaler = Some(upscaler.into());
+        Self { upscaler, ..self }
+    }
+
+    /// Check if the components have every model required by a version.
+    pub fn validate(&self, version: StableDiffusionVersion) -> Result<()> {
+        let requires_second_encoder = version.is_xl();
+        let has_second_encoder = self.tokenizer_2.is_some() && self.clip_2.is_some();
+        if requires_second_encoder && !has_second_encoder {
+            return Err(StableDiffusionError::version_mismatch(format!("{version:?} requires a second tokenizer and CLIP model")));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,164 @@
+//! ControlNet conditioning of the UNet on control images, e.g. poses or edges.
+
+use std::path::Path;
+
+use candle::{DType, Device, Tensor};
+
+use crate::models::controlnet::ControlNetModel;
+use crate::models::nn::VarBuilder;
+use serde::{Deserialize, Serialize};
+
+use crate::{File, Preprocessor, Result, StableDiffusionError, StableDiffusionVersion};
+
+/// The `ControlNetWeights` struct is used to specify the weights of a ControlNet model.
+pub struct ControlNetWeights {
+    /// The weights of the ControlNet model.
+    pub file: File,
+}
+
+impl ControlNetWeights {
+    /// Create a new `ControlNetWeights` instance from a file.
+    pub fn from_file(file: impl Into<File>) -> Self {
+        let file = file.into();
+        Self { file }
+    }
+
+    fn default_path(dtype: DType) -> &'static str {
+        if dtype == DType::F16 {
+            "diffusion_pytorch_model.fp16.safetensors"
+        } else {
+            "diffusion_pytorch_model.safetensors"
+        }
+    }
+
+    /// Create a new `ControlNetWeights` instance from a repository, e.g. `lllyasviel/control_v11p_sd15_canny`.
+    pub fn from_repository(repository: impl Into<String>, dtype: DType) -> Self {
+        let path = Self::default_path(dtype);
+        let file = File::Repository(crate::Repository::new(repository.into(), path));
+        Self::from_file(file)
+    }
+}
+
+/// The `ControlNet` struct is used to specify the ControlNet model.
+pub struct ControlNet {
+    controlnet: ControlNetModel,
+}
+
+impl ControlNet {
+    /// Create a new `ControlNet` instance from weights, the version of the UNet it conditions, device, and data type.
+    pub fn new(weights: impl AsRef<Path>, version: StableDiffusionVersion, device: &Device, dtype: DType) -> Result<Self> {
+        let vs = VarBuilder::from_file(weights, device, dtype)?;
+        let controlnet = ControlNetModel::new(vs, 4, 3, &version.unet_config())?;
+        Ok(Self { controlnet })
+    }
+
+    /// Predict the residuals of the UNet down and mid blocks for the latents and a control image tensor in `[0, 1]`.
+    pub fn forward(&self, latent: &Tensor, timestep: f64, text_embeddings: &Tensor, control: &Tensor, scale: f64) -> Result<(Vec<Tensor>, Tensor)> {
+        Ok(self.controlnet.forward(latent, timestep, text_embeddings, control, scale)?)
+    }
+}
+
+/// The `ControlImage` struct is used to condition a generation on an image with a registered ControlNet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ControlImage {
+    /// The name the ControlNet was registered with.
+    pub controlnet: String,
+    /// The control image. It's resized to the resolution of the generation.
+    #[serde(with = "crate::serialization::rgb_image")]
+    pub image: image::ImageBuffer<image::Rgb<u8>, Vec<u8>>,
+    /// The conditioning scale the residuals are multiplied by.
+    pub scale: f64,
+    /// The fraction of the steps the control starts at.
+    pub start: f64,
+    /// The fraction of the steps the control ends at.
+    pub end: f64,
+    /// The annotator turning the image into the control image at the resolution of the generation, if any.
+    #[serde(default)]
+    pub preprocessor: Option<Preprocessor>,
+}
+
+impl ControlImage {
+    /// Create a new `ControlImage` instance applied on every step with a scale of 1.
+    pub fn new(controlnet: impl Into<String>, image: image::ImageBuffer<image::Rgb<u8>, Vec<u8>>) -> Self {
+        let controlnet = controlnet.into();
+        let scale = 1.0;
+        let start = 0.0;
+        let end = 1.0;
+        let preprocessor = None;
+        Self { controlnet, image, scale, start, end, preprocessor }
+    }
+
+    /// Sets the conditioning scale.
+    pub fn with_scale(self, scale: f64) -> Self {
+        Self { scale, ..self }
+    }
+
+    /// Sets the fractions of the steps the control starts and ends at, e.g. `(0.0, 0.5)` for the first half of the steps.
+    pub fn with_range(self, start: f64, end: f64) -> Self {
+        Self { start, end, ..self }
+    }
+
+    /// Sets the annotator applied to the image before conditioning the generation on it.
+    pub fn with_preprocessor(self, preprocessor: Preprocessor) -> Self {
+        Self { preprocessor: Some(preprocessor), ..self }
+    }
+
+    /// Check if the control is applied at a step.
+    pub fn is_active(&self, step: usize, n_steps: usize) -> bool {
+        let n_steps = n_steps.max(1) as f64;
+        let (from, to) = (step as f64 / n_steps, (step + 1) as f64 / n_steps);
+        from >= self.start && to <= self.end
+    }
+
+    /// Check if the control is valid.
+    pub fn validate(&self) -> Result<()> {
+        if !self.scale.is_finite() || self.scale < 0.0 {
+            return Err(StableDiffusionError::invalid_parameters(format!("the conditioning scale of {} must be non-negative, got {}", self.controlnet, self.scale)));
+        }
+        if !(0.0 <= self.start && self.start <= self.end && self.end <= 1.0) {
+            return Err(StableDiffusionError::invalid_parameters(format!("the range of {} must satisfy 0 <= start <= end <= 1, got {}..{}", self.controlnet, self.start, self.end)));
+        }
+        if let Some(preprocessor) = &self.preprocessor {
+            preprocessor.validate()?;
+        }
+        Ok(())
+    }
+
+    /// Get the `(1, 3, height, width)` tensor of the image in `[0, 1]`, resized to the resolution of the generation.
+    pub fn to_tensor(&self, width: usize, height: usize, device: &Device, dtype: DType) -> Result<Tensor> {
+        let image = if let Some(preprocessor) = &self.preprocessor {
+            preprocessor.apply(&image::DynamicImage::ImageRgb8(self.image.clone()), width as u32, height as u32)?
+        } else if (self.image.width() as usize, self.image.height() as usize) == (width, height) {
+            self.image.clone()
+        } else {
+            image::imageops::resize(&self.image, width as u32, height as u32, image::imageops::FilterType::Triangle)
+        };
+        let tensor = Tensor::from_vec(image.into_raw(), (height, width, 3), device)?
+            .permute((2, 0, 1))?
+            .to_dtype(DType::F32)?
+            .affine(1. / 255., 0.)?
+            .unsqueeze(0)?
+            .to_dtype(dtype)?;
+        Ok(tensor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn range_selects_steps() {
+        let image = image::ImageBuffer::new(8, 8);
+        let control = ControlImage::new("canny", image).with_range(0.0, 0.5);
+        let active = (0 .. 10).filter(|step| control.is_active(*step, 10)).collect::<Vec<_>>();
+        assert_eq!(active, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn inverted_canny_thresholds_are_invalid() {
+        let image = image::ImageBuffer::new(8, 8);
+        let control = ControlImage::new("canny", image).with_preprocessor(Preprocessor::Canny { low: 200.0, high: 100.0 });
+        assert!(matches!(control.validate(), Err(StableDiffusionError::InvalidParameters(_))));
+    }
+}
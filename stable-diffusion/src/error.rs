@@ -0,0 +1,130 @@
+//! This module contains the `StableDiffusionError` enum, which is used to specify the errors of the library.
+
+use std::path::PathBuf;
+
+/// The `Result` type of the library.
+pub type Result<T, E = StableDiffusionError> = std::result::Result<T, E>;
+
+/// The `StableDiffusionError` enum is used to specify the errors of the library.
+#[derive(Debug)]
+pub enum StableDiffusionError {
+    /// A file couldn't be downloaded from its repository.
+    Download {
+        /// The repository of the file.
+        repository: String,
+        /// The path of the file in the repository.
+        path: String,
+        /// The download error.
+        source: hf_hub::api::sync::ApiError,
+    },
+    /// A file doesn't exist.
+    MissingFile(PathBuf),
+    /// The shape of a tensor or an image doesn't match what was expected.
+    ShapeMismatch(String),
+    /// The version or the weights of a model don't support what was requested.
+    VersionMismatch(String),
+    /// The tokenizer couldn't be loaded or couldn't tokenize a text.
+    Tokenizer(String),
+    /// The generation parameters are invalid.
+    InvalidParameters(String),
+    /// A device or tensor operation failed.
+    Device(candle::Error),
+    /// An I/O operation failed.
+    Io(std::io::Error),
+    /// An image couldn't be encoded or decoded.
+    Image(image::ImageError),
+}
+
+impl StableDiffusionError {
+    /// Create a new `StableDiffusionError::InvalidParameters` from a message.
+    pub fn invalid_parameters(message: impl Into<String>) -> Self {
+        Self::InvalidParameters(message.into())
+    }
+
+    /// Create a new `StableDiffusionError::ShapeMismatch` from a message.
+    pub fn shape_mismatch(message: impl Into<String>) -> Self {
+        Self::ShapeMismatch(message.into())
+    }
+
+    /// Create a new `StableDiffusionError::VersionMismatch` from a message.
+    pub fn version_mismatch(message: impl Into<String>) -> Self {
+        Self::VersionMismatch(message.into())
+    }
+}
+
+impl std::fmt::Display for StableDiffusionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Download { repository, path, source } => write!(f, "failed to download {path} from {repository}: {source}"),
+            Self::MissingFile(path) => write!(f, "file not found: {}", path.display()),
+            Self::ShapeMismatch(message) => write!(f, "shape mismatch: {message}"),
+            Self::VersionMismatch(message) => write!(f, "version mismatch: {message}"),
+            Self::Tokenizer(message) => write!(f, "tokenizer error: {message}"),
+            Self::InvalidParameters(message) => write!(f, "invalid parameters: {message}"),
+            Self::Device(error) => write!(f, "device error: {error}"),
+            Self::Io(error) => write!(f, "I/O error: {error}"),
+            Self::Image(error) => write!(f, "image error: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for StableDiffusionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Download { source, .. } => Some(source),
+            Self::Device(error) => Some(error),
+            Self::Io(error) => Some(error),
+            Self::Image(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<candle::Error> for StableDiffusionError {
+    fn from(error: candle::Error) -> Self {
+        Self::Device(error)
+    }
+}
+
+impl From<std::io::Error> for StableDiffusionError {
+    fn from(error: std::io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+impl From<image::ImageError> for StableDiffusionError {
+    fn from(error: image::ImageError) -> Self {
+        Self::Image(error)
+    }
+}
+
+impl From<tokenizers::Error> for StableDiffusionError {
+    fn from(error: tokenizers::Error) -> Self {
+        Self::Tokenizer(error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn errors_are_displayed_with_their_kind() {
+        assert_eq!(StableDiffusionError::shape_mismatch("expected 3 image channels, got 4").to_string(), "shape mismatch: expected 3 image channels, got 4");
+        assert_eq!(StableDiffusionError::version_mismatch("there's no IP-Adapter for V2_1").to_string(), "version mismatch: there's no IP-Adapter for V2_1");
+        assert_eq!(StableDiffusionError::invalid_parameters("the guidance scale must be finite").to_string(), "invalid parameters: the guidance scale must be finite");
+        assert_eq!(StableDiffusionError::MissingFile(PathBuf::from("unet.safetensors")).to_string(), "file not found: unet.safetensors");
+    }
+
+    #[test]
+    fn errors_are_converted_with_their_source() {
+        let error = StableDiffusionError::from(std::io::Error::new(std::io::ErrorKind::NotFound, "no such file"));
+        assert!(matches!(error, StableDiffusionError::Io(_)));
+        assert_eq!(error.source().map(ToString::to_string).as_deref(), Some("no such file"));
+        let error = StableDiffusionError::from(candle::Error::Msg("out of memory".into()));
+        assert!(matches!(error, StableDiffusionError::Device(_)));
+        assert!(error.source().is_some());
+        assert!(StableDiffusionError::shape_mismatch("").source().is_none());
+    }
+}
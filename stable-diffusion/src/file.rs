@@ -2,8 +2,13 @@
 
 use std::path::PathBuf;
 
+use serde::{Deserialize, Serialize};
+
+use crate::{Result, StableDiffusionError};
+
 
 /// A repository containing a file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Repository {
     /// The repository containing the file.
     pub repository: PathBuf,
@@ -20,17 +25,27 @@ impl Repository {
     }
 
     /// Fetch the file from the repository.
-    pub fn fetch(&self) -> anyhow::Result<PathBuf> {
+    pub fn fetch(&self) -> Result<PathBuf> {
         if self.repository.exists() {
-            Ok(self.repository.join(&self.path).into())
+            let path = self.repository.join(&self.path);
+            if path.exists() {
+                Ok(path)
+            } else {
+                Err(StableDiffusionError::MissingFile(path))
+            }
         } else {
-            let api = hf_hub::api::sync::Api::new()?;
-            Ok(api.model(self.repository.display().to_string()).get(&self.path.display().to_string())?)    
+            let repository = self.repository.display().to_string();
+            let path = self.path.display().to_string();
+            hf_hub::api::sync::Api::new()
+                .and_then(|api| api.model(repository.clone()).get(&path))
+                .map_err(|source| StableDiffusionError::Download { repository, path, source })
         }
     }
 }
 
-/// A file that can be fetched.
+/// A file that can be fetched, serialized as a path string or as a `{"repository", "path"}` object.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
 pub enum File {
     /// A local file.
     Path(std::path::PathBuf),
@@ -52,10 +67,19 @@ impl From<&str> for File {
 
 impl File {
     /// Fetch the file.
-    pub fn fetch(&self) -> anyhow::Result<PathBuf> {
+    pub fn fetch(&self) -> Result<PathBuf> {
         match self {
-            Self::Path(path) => Ok(path.clone()),
+            Self::Path(path) if path.exists() => Ok(path.clone()),
+            Self::Path(path) => Err(StableDiffusionError::MissingFile(path.clone())),
             Self::Repository(repository) => repository.fetch()
         }
     }
+
+    /// Get the file with another name in the same folder.
+    pub fn sibling(&self, name: impl AsRef<std::path::Path>) -> Self {
+        match self {
+            Self::Path(path) => Self::Path(path.with_file_name(name.as_ref())),
+            Self::Repository(repository) => Self::Repository(Repository::new(&repository.repository, repository.path.with_file_name(name.as_ref()))),
+        }
+    }
 }
@@ -0,0 +1,177 @@
+//! IP-Adapter image prompting, conditioning the UNet on the CLIP embeddings of reference images.
+
+use std::path::Path;
+
+use candle::{DType, Device, Module, Tensor};
+use candle_nn::LayerNorm;
+
+use crate::models::attention::{ImagePromptAttention, ImagePromptProjection};
+use crate::models::clip::{ClipVisionConfig, ClipVisionTransformer};
+use crate::models::nn::{self, Linear, VarBuilder};
+use crate::models::unet_2d::image_prompt_projections;
+use serde::{Deserialize, Serialize};
+
+use crate::{Architecture, File, Result, StableDiffusionError, StableDiffusionVersion};
+
+/// The number of cross-attention tokens an image is projected to.
+const NUM_TOKENS: usize = 4;
+
+/// The normalization of the images fed to the CLIP image encoder.
+const IMAGE_MEAN: [f32; 3] = [0.48145466, 0.4578275, 0.40821073];
+const IMAGE_STD: [f32; 3] = [0.26862954, 0.2613026, 0.2757771];
+
+/// The `IPAdapterWeights` struct is used to specify the weights of an IP-Adapter and of its image encoder.
+pub struct IPAdapterWeights {
+    /// The weights of the CLIP image encoder.
+    pub image_encoder: File,
+    /// The weights of the image projection and of the cross-attention projections.
+    pub ip_adapter: File,
+}
+
+impl IPAdapterWeights {
+    /// Create a new `IPAdapterWeights` instance from files.
+    pub fn from_files(image_encoder: impl Into<File>, ip_adapter: impl Into<File>) -> Self {
+        let image_encoder = image_encoder.into();
+        let ip_adapter = ip_adapter.into();
+        Self { image_encoder, ip_adapter }
+    }
+
+    /// Create a new `IPAdapterWeights` instance from the `h94/IP-Adapter` repository for a version.
+    pub fn from_repository(version: StableDiffusionVersion) -> Result<Self> {
+        let (image_encoder, ip_adapter) = match version.architecture() {
+            Architecture::V1 => ("models/image_encoder/model.safetensors", "models/ip-adapter_sd15.safetensors"),
+            Architecture::XL => ("sdxl_models/image_encoder/model.safetensors", "sdxl_models/ip-adapter_sdxl.safetensors"),
+            Architecture::V2 => return Err(StableDiffusionError::version_mismatch(format!("there's no IP-Adapter for {version:?}"))),
+        };
+        let repository = |path| File::Repository(crate::Repository::new("h94/IP-Adapter", path));
+        Ok(Self::from_files(repository(image_encoder), repository(ip_adapter)))
+    }
+}
+
+/// The `ImageProjection` struct is used to project an image embedding to cross-attention tokens.
+struct ImageProjection {
+    proj: Linear,
+    norm: LayerNorm,
+    cross_attention_dim: usize,
+}
+
+impl ImageProjection {
+    fn new(vs: VarBuilder, image_embeds_dim: usize, cross_attention_dim: usize) -> candle::Result<Self> {
+        let proj = nn::linear(image_embeds_dim, cross_attention_dim * NUM_TOKENS, vs.pp("proj"))?;
+        let norm = nn::layer_norm(cross_attention_dim, 1e-5, vs.pp("norm"))?;
+        Ok(Self { proj, norm, cross_attention_dim })
+    }
+}
+
+impl Module for ImageProjection {
+    fn forward(&self, image_embeds: &Tensor) -> candle::Result<Tensor> {
+        let batch_size = image_embeds.dim(0)?;
+        let tokens = self.proj.forward(image_embeds)?.reshape((batch_size, NUM_TOKENS, self.cross_attention_dim))?;
+        self.norm.forward(&tokens)
+    }
+}
+
+/// The `IPAdapter` struct is used to specify the IP-Adapter model with its image encoder.
+pub struct IPAdapter {
+    image_encoder: ClipVisionTransformer,
+    image_projection: ImageProjection,
+    projections: Vec<ImagePromptProjection>,
+    image_size: usize,
+}
+
+impl IPAdapter {
+    /// Create a new `IPAdapter` instance from weights, the version of the UNet it conditions, device, and data type.
+    pub fn new(image_encoder: impl AsRef<Path>, ip_adapter: impl AsRef<Path>, version: StableDiffusionVersion, device: &Device, dtype: DType) -> Result<Self> {
+        let config = if version.is_xl() { ClipVisionConfig::vit_big_g_14() } else { ClipVisionConfig::vit_h_14() };
+        let unet_config = version.unet_config();
+        let image_encoder = ClipVisionTransformer::new(VarBuilder::from_file(image_encoder, device, dtype)?, &config)?;
+        let vs = VarBuilder::from_file(ip_adapter, device, dtype)?;
+        let image_projection = ImageProjection::new(vs.pp("image_proj"), config.projection_dim, unet_config.cross_attention_dim)?;
+        let projections = image_prompt_projections(vs.pp("ip_adapter"), &unet_config)?;
+        let image_size = config.image_size;
+        Ok(Self { image_encoder, image_projection, projections, image_size })
+    }
+
+    /// Get the `(1, projection_dim)` CLIP embeddings of an image, resized and center cropped to the encoder resolution.
+    pub fn image_embeds(&self, image: &image::ImageBuffer<image::Rgb<u8>, Vec<u8>>, device: &Device, dtype: DType) -> Result<Tensor> {
+        let size = self.image_size as u32;
+        let image = image::DynamicImage::ImageRgb8(image.clone())
+            .resize_to_fill(size, size, image::imageops::FilterType::CatmullRom)
+            .to_rgb8();
+        let mean = Tensor::new(&IMAGE_MEAN, device)?.reshape((3, 1, 1))?;
+        let std = Tensor::new(&IMAGE_STD, device)?.reshape((3, 1, 1))?;
+        let pixel_values = Tensor::from_vec(image.into_raw(), (self.image_size, self.image_size, 3), device)?
+            .permute((2, 0, 1))?
+            .to_dtype(DType::F32)?
+            .affine(1. / 255., 0.)?
+            .broadcast_sub(&mean)?
+            .broadcast_div(&std)?
+            .unsqueeze(0)?
+            .to_dtype(dtype)?;
+        Ok(self.image_encoder.forward(&pixel_values)?)
+    }
+
+    /// Get the cross-attention tokens of image embeddings, batched as `[uncond, cond]` if `guidance` is set.
+    ///
+    /// The unconditional tokens are projected from zeroed embeddings.
+    pub fn image_tokens(&self, image_embeds: &Tensor, guidance: bool) -> Result<Tensor> {
+        let tokens = self.image_projection.forward(image_embeds)?;
+        if guidance {
+            let uncond = self.image_projection.forward(&image_embeds.zeros_like()?)?;
+            Ok(Tensor::cat(&[uncond, tokens], 0)?)
+        } else {
+            Ok(tokens)
+        }
+    }
+
+    /// Get the image prompt attended to by the UNet cross-attentions, from weighted image tokens.
+    pub(crate) fn attention(&self, tokens: Vec<(Tensor, f64)>) -> ImagePromptAttention<'_> {
+        ImagePromptAttention::new(&self.projections, tokens)
+    }
+}
+
+/// The `ImagePrompt` struct is used to condition a generation on a reference image with the IP-Adapter.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImagePrompt {
+    /// The reference image.
+    #[serde(with = "crate::serialization::rgb_image")]
+    pub image: image::ImageBuffer<image::Rgb<u8>, Vec<u8>>,
+    /// The weight the attention to the image is multiplied by.
+    pub weight: f64,
+}
+
+impl ImagePrompt {
+    /// Create a new `ImagePrompt` instance with a weight of 1.
+    pub fn new(image: image::ImageBuffer<image::Rgb<u8>, Vec<u8>>) -> Self {
+        let weight = 1.0;
+        Self { image, weight }
+    }
+
+    /// Sets the weight.
+    pub fn with_weight(self, weight: f64) -> Self {
+        Self { weight, ..self }
+    }
+
+    /// Check if the image prompt is valid.
+    pub fn validate(&self) -> Result<()> {
+        if !self.weight.is_finite() || self.weight < 0.0 {
+            return Err(StableDiffusionError::invalid_parameters(format!("the image prompt weight must be non-negative, got {}", self.weight)));
+        }
+        if self.image.width() == 0 || self.image.height() == 0 {
+            return Err(StableDiffusionError::invalid_parameters("the image prompt must not be empty"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn negative_weight_is_invalid() {
+        let prompt = ImagePrompt::new(image::ImageBuffer::new(8, 8));
+        assert!(prompt.clone().validate().is_ok());
+        assert!(prompt.with_weight(-1.0).validate().is_err());
+    }
+}
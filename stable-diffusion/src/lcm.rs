@@ -0,0 +1,142 @@
+//! Latent Consistency Model (LCM) scheduler, denoising distilled models in a few steps.
+
+use candle::{Result, Tensor};
+use candle_transformers::models::stable_diffusion::schedulers::{PredictionType, Scheduler, SchedulerConfig};
+
+use crate::Noise;
+
+/// The `LCMSchedulerConfig` struct is used to configure the LCM scheduler.
+#[derive(Debug, Clone, Copy)]
+pub struct LCMSchedulerConfig {
+    /// The first value of the scaled linear beta schedule.
+    pub beta_start: f64,
+    /// The last value of the scaled linear beta schedule.
+    pub beta_end: f64,
+    /// The number of timesteps the model was trained with.
+    pub train_timesteps: usize,
+    /// The number of steps of the schedule the model was distilled from, which the timesteps are picked from.
+    pub original_inference_steps: usize,
+    /// The factor the timesteps are scaled by in the boundary conditions.
+    pub timestep_scaling: f64,
+    /// The prediction type of the model.
+    pub prediction_type: PredictionType,
+    /// The seed of the noise injected between the steps.
+    pub seed: u64,
+}
+
+impl Default for LCMSchedulerConfig {
+    fn default() -> Self {
+        Self {
+            beta_start: 0.00085,
+            beta_end: 0.012,
+            train_timesteps: 1000,
+            original_inference_steps: 50,
+            timestep_scaling: 10.,
+            prediction_type: PredictionType::Epsilon,
+            seed: 0,
+        }
+    }
+}
+
+impl SchedulerConfig for LCMSchedulerConfig {
+    fn build(&self, inference_steps: usize) -> Result<Box<dyn Scheduler>> {
+        Ok(Box::new(LCMScheduler::new(inference_steps, *self)?))
+    }
+}
+
+/// The `LCMScheduler` struct is used to run the multi-step consistency sampling of the LCM models.
+#[derive(Debug, Clone)]
+pub struct LCMScheduler {
+    timesteps: Vec<usize>,
+    alphas_cumprod: Vec<f64>,
+    config: LCMSchedulerConfig,
+}
+
+impl LCMScheduler {
+    /// Create a new `LCMScheduler` instance for a number of inference steps.
+    pub fn new(inference_steps: usize, config: LCMSchedulerConfig) -> Result<Self> {
+        let original_steps = config.original_inference_steps;
+        if inference_steps == 0 || inference_steps > original_steps {
+            candle::bail!("the LCM scheduler supports 1 to {original_steps} steps, got {inference_steps}")
+        }
+        let (start, end) = (config.beta_start.sqrt(), config.beta_end.sqrt());
+        let n = config.train_timesteps;
+        let mut alphas_cumprod = Vec::with_capacity(n);
+        let mut alpha_cumprod = 1.0;
+        for index in 0 .. n {
+            let beta = (start + (end - start) * index as f64 / (n - 1) as f64).powi(2);
+            alpha_cumprod *= 1.0 - beta;
+            alphas_cumprod.push(alpha_cumprod);
+        }
+        // The timesteps of the original schedule, from the noisiest one, evenly picked for the number of inference steps.
+        let ratio = n / original_steps;
+        let timesteps = (0 .. inference_steps)
+            .map(|step| original_steps - step * original_steps / inference_steps)
+            .map(|index| index * ratio - 1)
+            .collect();
+        Ok(Self { timesteps, alphas_cumprod, config })
+    }
+
+    /// Get the `(c_skip, c_out)` boundary condition scalings of a timestep.
+    fn scalings(&self, timestep: usize) -> (f64, f64) {
+        let sigma_data = 0.5f64;
+        let scaled_timestep = timestep as f64 * self.config.timestep_scaling;
+        let c_skip = sigma_data.powi(2) / (scaled_timestep.powi(2) + sigma_data.powi(2));
+        let c_out = scaled_timestep / (scaled_timestep.powi(2) + sigma_data.powi(2)).sqrt();
+        (c_skip, c_out)
+    }
+}
+
+impl Scheduler for LCMScheduler {
+    fn timesteps(&self) -> &[usize] {
+        self.timesteps.as_slice()
+    }
+
+    fn add_noise(&self, original: &Tensor, noise: Tensor, timestep: usize) -> Result<Tensor> {
+        let alpha_prod_t = self.alphas_cumprod[timestep];
+        (original * alpha_prod_t.sqrt())? + (noise * (1. - alpha_prod_t).sqrt())?
+    }
+
+    fn init_noise_sigma(&self) -> f64 {
+        1.
+    }
+
+    fn scale_model_input(&self, sample: Tensor, _timestep: usize) -> Result<Tensor> {
+        Ok(sample)
+    }
+
+    fn step(&self, model_output: &Tensor, timestep: usize, sample: &Tensor) -> Result<Tensor> {
+        let index = self.timesteps.iter().position(|t| *t == timestep).unwrap_or(self.timesteps.len() - 1);
+        let prev_timestep = self.timesteps.get(index + 1).copied();
+        let alpha_prod_t = self.alphas_cumprod[timestep];
+        let beta_prod_t = 1. - alpha_prod_t;
+        let pred_original_sample = match self.config.prediction_type {
+            PredictionType::Epsilon => ((sample - (model_output * beta_prod_t.sqrt())?)? / alpha_prod_t.sqrt())?,
+            PredictionType::VPrediction => ((sample * alpha_prod_t.sqrt())? - (model_output * beta_prod_t.sqrt())?)?,
+            PredictionType::Sample => model_output.clone(),
+        };
+        let (c_skip, c_out) = self.scalings(timestep);
+        let denoised = ((pred_original_sample * c_out)? + (sample * c_skip)?)?;
+        match prev_timestep {
+            Some(prev_timestep) => {
+                let alpha_prod_prev = self.alphas_cumprod[prev_timestep];
+                let noise = Noise::new(self.config.seed.wrapping_add(index as u64 + 1))
+                    .generate(sample.dims4()?, sample.device())?
+                    .to_dtype(sample.dtype())?;
+                (denoised * alpha_prod_prev.sqrt())? + (noise * (1. - alpha_prod_prev).sqrt())?
+            }
+            None => Ok(denoised),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn timesteps_follow_the_original_schedule() {
+        let scheduler = LCMScheduler::new(4, Default::default()).unwrap();
+        assert_eq!(scheduler.timesteps(), &[999, 759, 499, 259]);
+    }
+}
@@ -7,44 +7,140 @@ mod tokenizer;
 mod unet;
 mod file;
 mod device;
+mod error;
+mod noise;
+mod components;
+mod offload;
+mod cache;
+mod animation;
+mod models;
+mod controlnet;
+mod ip_adapter;
+mod lcm;
+mod pipeline;
+mod wuerstchen;
+mod upscale;
+mod serialization;
+mod library;
+mod guidance;
+mod prompt;
+mod region;
+mod tiling;
+mod preprocess;
+mod quantization;
 
 pub use device::*;
+pub use error::*;
 pub use vae::*;
 pub use tokenizer::*;
 pub use clip::*;
 pub use dtype::*;
 pub use unet::*;
 pub use file::*;
+pub use noise::*;
+pub use components::*;
+pub use offload::*;
+pub use cache::*;
+pub use animation::*;
+pub use quantization::*;
+pub use controlnet::*;
+pub use ip_adapter::*;
+pub use lcm::*;
+pub use pipeline::*;
+pub use wuerstchen::*;
+pub use upscale::*;
+pub use library::*;
+pub use guidance::*;
+pub use prompt::*;
+pub use region::*;
+pub use tiling::*;
+pub use preprocess::*;
 
-use candle_transformers::models::stable_diffusion::{self, StableDiffusionConfig};
+use candle_transformers::models::stable_diffusion::StableDiffusionConfig;
+use candle_transformers::models::stable_diffusion::ddim::DDIMSchedulerConfig;
+use candle_transformers::models::stable_diffusion::euler_ancestral_discrete::EulerAncestralDiscreteSchedulerConfig;
+use candle_transformers::models::stable_diffusion::schedulers::{PredictionType, SchedulerConfig, TimestepSpacing};
+
+use std::sync::{Arc, RwLock};
+
+use serde::{Deserialize, Serialize};
 
-pub use anyhow::{Error, Result};
 use candle::{Tensor, D};
 
+use crate::models::embeddings::guidance_scale_embedding;
+use crate::models::unet_2d::UNetConditioning;
+
 /// The `StableDiffusionParameters` struct is used to specify the parameters of the Stable Diffusion model.
 pub struct StableDiffusionParameters {
     pub weights: StableDiffusionWeights,
     pub dtype: DType,
     pub config: StableDiffusionConfig,
-    pub device: Device
+    pub device: Device,
+    pub offload: OffloadPolicy,
+    pub devices: ComponentDevices,
+    pub sliced_attention_size: Option<usize>,
 }
 
 impl StableDiffusionParameters {
     /// Create a new `StableDiffusionParameters` instance.
-    pub fn new(weights: StableDiffusionWeights, device: Device, dtype: DType) -> anyhow::Result<Self> {
-        let config = match weights.version {
-            StableDiffusionVersion::V1_5 => stable_diffusion::StableDiffusionConfig::v1_5(None, None, None),
-            StableDiffusionVersion::V2_1 => stable_diffusion::StableDiffusionConfig::v2_1(None, None, None),
-            StableDiffusionVersion::XL => stable_diffusion::StableDiffusionConfig::sdxl(None, None, None),
-            StableDiffusionVersion::Turbo => stable_diffusion::StableDiffusionConfig::sdxl_turbo(None, None, None),
-        };
-        Ok(Self { device, weights, dtype, config })
+    pub fn new(weights: StableDiffusionWeights, device: Device, dtype: DType) -> Result<Self> {
+        let (width, height) = weights.version.default_size();
+        let config = weights.version.config(width, height);
+        let offload = Default::default();
+        let devices = Default::default();
+        let sliced_attention_size = None;
+        Ok(Self { device, weights, dtype, config, offload, devices, sliced_attention_size })
+    }
+
+    /// Sets the default resolution of the generations, which must be a multiple of 8.
+    pub fn with_size(self, width: usize, height: usize) -> Result<Self> {
+        if [width, height].into_iter().any(|size| size == 0 || size % 8 != 0) {
+            return Err(StableDiffusionError::invalid_parameters(format!("the resolution must be a positive multiple of 8, got {width}x{height}")));
+        }
+        let config = self.weights.version.config(width, height);
+        Ok(Self { config, ..self })
+    }
+
+    /// Sets the size of the attention slices of the UNet, `Some(0)` to pick it automatically, or `None` to disable slicing.
+    pub fn with_sliced_attention_size(self, sliced_attention_size: Option<usize>) -> Self {
+        Self { sliced_attention_size, ..self }
+    }
+
+    /// Sets the offload policy.
+    pub fn with_offload(self, offload: OffloadPolicy) -> Self {
+        Self { offload, ..self }
+    }
+
+    /// Sets the devices of the components.
+    pub fn with_devices(self, devices: ComponentDevices) -> Self {
+        Self { devices, ..self }
+    }
+
+    /// Get the device of the UNet.
+    pub fn unet_device(&self) -> Device {
+        self.devices.unet.clone().unwrap_or_else(|| self.device.clone())
+    }
+
+    /// Get the device of the VAE.
+    pub fn vae_device(&self) -> Device {
+        self.devices.vae.clone().unwrap_or_else(|| self.device.clone())
+    }
+
+    /// Get the device of the text encoders. The text encoders run on the CPU with the `OffloadPolicy::Model` policy.
+    pub fn text_encoder_device(&self) -> Device {
+        match (&self.devices.text_encoder, self.offload) {
+            (Some(device), _) => device.clone(),
+            (None, OffloadPolicy::Model) => Device::Cpu,
+            (None, _) => self.device.clone(),
+        }
     }
 }
 
 /// The `StableDiffusionWeights` struct is used to specify the weights of the Stable Diffusion model.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StableDiffusionWeights {
     pub version: StableDiffusionVersion,
+    #[serde(with = "crate::serialization::dtype")]
     pub dtype: DType,
     pub unet: UNetWeights,
     pub vae: VAEWeights,
@@ -55,7 +151,16 @@ pub struct StableDiffusionWeights {
 impl StableDiffusionWeights {
     /// Create a new `StableDiffusionWeights` instance from a version and dtype.
     pub fn new(version: StableDiffusionVersion, dtype: DType) -> Self {
-        Self::from_repository(version, Some(version.repo().into()), dtype)
+        let weights = Self::from_repository(version, Some(version.repo().into()), dtype);
+        match version {
+            // The LCM distilled UNet is published alone, the other models are the ones of Stable Diffusion XL.
+            StableDiffusionVersion::LcmXL => {
+                let path = if dtype == DType::F16 { "diffusion_pytorch_model.fp16.safetensors" } else { "diffusion_pytorch_model.safetensors" };
+                let unet = UNetWeights::from_file(File::Repository(Repository::new("latent-consistency/lcm-sdxl", path)));
+                weights.with_unet(unet)
+            }
+            _ => weights,
+        }
     }
 
     /// Create a new `StableDiffusionWeights` instance from a version, repository, and dtype.
@@ -64,7 +169,7 @@ impl StableDiffusionWeights {
         let unet = UNetWeights::from_repository(&repository, dtype);
         let vae = VAEWeights::from_repository(&repository, version, dtype);
         let clip = CLIPWeights::from_repository(&repository, version, dtype);
-        let tokenizer = TokenizerWeights::from_repository(version);
+        let tokenizer = TokenizerWeights::from_repository(&repository, version);
         Self { version, dtype, unet, vae, clip, tokenizer }
     }
 
@@ -87,23 +192,63 @@ impl StableDiffusionWeights {
     pub fn with_tokenizer(self, tokenizer: TokenizerWeights) -> Self {
         Self { tokenizer, ..self }
     }
+
+    /// Quantize the weights of the UNet and CLIP models into GGUF files in a directory and use them instead.
+    ///
+    /// Files that were already quantized in the directory from the same sources are reused.
+    pub fn quantize(self, directory: impl AsRef<std::path::Path>, quantization: Quantization) -> Result<Self> {
+        let directory = directory.as_ref();
+        let quantize = |file: &File, name: &str| -> Result<std::path::PathBuf> {
+            let input = file.fetch()?;
+            let output = directory.join(quantized_file_name(&input, name, quantization)?);
+            if !output.exists() {
+                quantize_weights(&input, &output, quantization)?;
+            }
+            Ok(output)
+        };
+        let unet = UNetWeights::from_file(quantize(&self.unet.file, "unet")?);
+        let clip = quantize(&self.clip.clip, "text_encoder")?;
+        let clip2 = match &self.clip.clip2 {
+            Some(file) => Some(quantize(file, "text_encoder_2")?),
+            None => None,
+        };
+        let clip = CLIPWeights::from_file(clip, clip2);
+        Ok(self.with_unet(unet).with_clip(clip))
+    }
+}
+
+/// Batch the `[uncond, cond]`, or `[cond]` without guidance, image tokens of an image prompt for a batch of text
+/// embeddings, repeating the conditional tokens for every conditional embedding after the unconditional ones, if any.
+fn batch_image_tokens(tokens: &Tensor, uncond: bool, conds: usize) -> Result<Tensor> {
+    let mut repeats = vec![1; tokens.rank()];
+    repeats[0] = conds;
+    let cond = tokens.narrow(0, tokens.dim(0)? - 1, 1)?.repeat(repeats)?;
+    if !uncond {
+        return Ok(cond);
+    }
+    Ok(Tensor::cat(&[&tokens.narrow(0, 0, 1)?, &cond], 0)?)
 }
 
 /// The `StableDiffusion` struct is used to specify the Stable Diffusion model.
 pub struct StableDiffusion {
     version: StableDiffusionVersion,
     device: Device,
+    vae_device: Device,
+    text_encoder_device: Device,
     dtype: DType,
-    config: StableDiffusionConfig,
-    unet: UNet,
-    vae: VAE,
-    tokenizer: Tokenizer,
-    tokenizer_2: Option<Tokenizer>,
-    clip: CLIP,
-    clip_2: Option<CLIP>,
+    /// The default `(width, height)` of the generations.
+    size: (usize, usize),
+    components: RwLock<StableDiffusionComponents>,
+    embedding_cache: EmbeddingCache,
+    progress: Option<ProgressCallback>,
 }
 
+/// The `ProgressCallback` type is used to report the denoising progress as `(step, n_steps)` after each step.
+pub type ProgressCallback = Arc<dyn Fn(usize, usize) + Send + Sync>;
+
 /// The `GenerationParameters` struct is used to specify the parameters of the generation process.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct GenerationParameters {
     pub prompt: String,
     pub uncond_prompt: String,
@@ -113,8 +258,32 @@ pub struct GenerationParameters {
     pub height: Option<usize>,
     pub n_steps: Option<usize>,
     pub guidance_scale: Option<f64>,
+    pub guidance_rescale: f64,
+    pub dynamic_thresholding: Option<DynamicThresholding>,
+    pub guidance_schedule: GuidanceSchedule,
+    pub guidance_interval: Option<(f64, f64)>,
+    #[serde(with = "crate::serialization::optional_rgb_image")]
     pub img2img: Option<image::ImageBuffer<image::Rgb<u8>, Vec<u8>>>,
     pub img2img_strength: f64,
+    #[serde(with = "crate::serialization::optional_luma_image")]
+    pub mask: Option<image::ImageBuffer<image::Luma<u8>, Vec<u8>>>,
+    pub seed: Option<u64>,
+    pub variation_seed: Option<u64>,
+    pub variation_strength: f64,
+    pub seed_resize_from: Option<(usize, usize)>,
+    #[serde(skip)]
+    pub prompt_embeds: Option<PromptEmbeddings>,
+    pub controls: Vec<ControlImage>,
+    pub image_prompts: Vec<ImagePrompt>,
+    pub regions: Vec<PromptRegion>,
+    pub region_base_weight: f64,
+    pub tiling: Option<TiledDiffusion>,
+}
+
+impl Default for GenerationParameters {
+    fn default() -> Self {
+        Self::new("")
+    }
 }
 
 impl From<String> for GenerationParameters {
@@ -132,11 +301,26 @@ impl GenerationParameters {
         let height = Default::default();
         let n_steps = Default::default();
         let guidance_scale = Default::default();
+        let guidance_rescale = 0.0;
+        let dynamic_thresholding = Default::default();
+        let guidance_schedule = Default::default();
+        let guidance_interval = Default::default();
         let style_prompt = Default::default();
         let uncond_style_prompt = Default::default();
         let img2img = Default::default();
         let img2img_strength = 0.5;
-        Self { prompt, uncond_prompt, style_prompt, uncond_style_prompt, width, height, n_steps, guidance_scale, img2img, img2img_strength }
+        let mask = Default::default();
+        let seed = Default::default();
+        let variation_seed = Default::default();
+        let variation_strength = 0.0;
+        let seed_resize_from = Default::default();
+        let prompt_embeds = Default::default();
+        let controls = Default::default();
+        let image_prompts = Default::default();
+        let regions = Default::default();
+        let region_base_weight = 0.2;
+        let tiling = Default::default();
+        Self { prompt, uncond_prompt, style_prompt, uncond_style_prompt, width, height, n_steps, guidance_scale, guidance_rescale, dynamic_thresholding, guidance_schedule, guidance_interval, img2img, img2img_strength, mask, seed, variation_seed, variation_strength, seed_resize_from, prompt_embeds, controls, image_prompts, regions, region_base_weight, tiling }
     }
 
     /// Sets the unconditional prompt.
@@ -174,6 +358,28 @@ impl GenerationParameters {
         Self { guidance_scale, ..self }
     }
 
+    /// Sets the guidance rescale factor, from 0 to 1, pulling the guided prediction back to the standard deviation of
+    /// the conditional one. It reduces the overexposure of high guidance scales, especially with v-prediction.
+    pub fn with_guidance_rescale(self, guidance_rescale: f64) -> Self {
+        Self { guidance_rescale, ..self }
+    }
+
+    /// Sets the dynamic thresholding of the guided predictions.
+    pub fn with_dynamic_thresholding(self, dynamic_thresholding: Option<DynamicThresholding>) -> Self {
+        Self { dynamic_thresholding, ..self }
+    }
+
+    /// Sets the schedule of the guidance scale along the steps.
+    pub fn with_guidance_schedule(self, guidance_schedule: GuidanceSchedule) -> Self {
+        Self { guidance_schedule, ..self }
+    }
+
+    /// Sets the `(start, end)` fractions of the steps the guidance is applied on, the other steps being unguided and
+    /// skipping the unconditional prediction.
+    pub fn with_guidance_interval(self, guidance_interval: Option<(f64, f64)>) -> Self {
+        Self { guidance_interval, ..self }
+    }
+
     /// Sets the image to image.
     pub fn with_img2img(self, img2img: Option<image::ImageBuffer<image::Rgb<u8>, Vec<u8>>>) -> Self {
         Self { img2img, ..self }
@@ -183,197 +389,862 @@ impl GenerationParameters {
     pub fn with_img2img_strength(self, img2img_strength: f64) -> Self {
         Self { img2img_strength, ..self }
     }
+
+    /// Sets the inpainting mask of the image to image input, where the white pixels are repainted.
+    pub fn with_mask(self, mask: Option<image::ImageBuffer<image::Luma<u8>, Vec<u8>>>) -> Self {
+        Self { mask, ..self }
+    }
+
+    /// Sets the seed. A random seed is used if not set.
+    pub fn with_seed(self, seed: Option<u64>) -> Self {
+        Self { seed, ..self }
+    }
+
+    /// Sets the variation seed.
+    pub fn with_variation_seed(self, variation_seed: Option<u64>) -> Self {
+        Self { variation_seed, ..self }
+    }
+
+    /// Sets the variation strength, where 0 is the noise of the seed and 1 is the noise of the variation seed.
+    pub fn with_variation_strength(self, variation_strength: f64) -> Self {
+        Self { variation_strength, ..self }
+    }
+
+    /// Sets the `(width, height)` resolution the seed noise is generated at, keeping the composition of that resolution.
+    pub fn with_seed_resize_from(self, seed_resize_from: Option<(usize, usize)>) -> Self {
+        Self { seed_resize_from, ..self }
+    }
+
+    /// Sets precomputed prompt embeddings, which are used instead of encoding the prompts.
+    pub fn with_prompt_embeds(self, prompt_embeds: Option<PromptEmbeddings>) -> Self {
+        Self { prompt_embeds, ..self }
+    }
+
+    /// Sets the control images, each conditioning the generation with a registered ControlNet.
+    pub fn with_controls(self, controls: Vec<ControlImage>) -> Self {
+        Self { controls, ..self }
+    }
+
+    /// Sets the image prompts, each attended to by the UNet through the IP-Adapter with its own weight.
+    pub fn with_image_prompts(self, image_prompts: Vec<ImagePrompt>) -> Self {
+        Self { image_prompts, ..self }
+    }
+
+    /// Sets the regional prompts. Each region is a UNet evaluation, whose guided prediction is blended into the base
+    /// one with its mask and weight.
+    pub fn with_regions(self, regions: Vec<PromptRegion>) -> Self {
+        Self { regions, ..self }
+    }
+
+    /// Sets the weight of the base prediction, from the prompt, against the regional predictions.
+    pub fn with_region_base_weight(self, region_base_weight: f64) -> Self {
+        Self { region_base_weight, ..self }
+    }
+
+    /// Sets the tiled diffusion, to generate canvases larger than the native size of the model in overlapping tiles.
+    pub fn with_tiling(self, tiling: Option<TiledDiffusion>) -> Self {
+        Self { tiling, ..self }
+    }
+
+    /// Check if the parameters are valid.
+    pub fn validate(&self) -> Result<()> {
+        let (width, height) = match &self.img2img {
+            Some(image) => (Some(image.width() as usize), Some(image.height() as usize)),
+            None => (self.width, self.height),
+        };
+        for (name, size) in [("width", width), ("height", height)] {
+            match size {
+                Some(size) if size == 0 || size % 8 != 0 => return Err(StableDiffusionError::invalid_parameters(format!("the {name} must be a positive multiple of 8, got {size}"))),
+                _ => {}
+            }
+        }
+        if self.n_steps == Some(0) {
+            return Err(StableDiffusionError::invalid_parameters("the number of steps must be positive"));
+        }
+        if let Some(mask) = &self.mask {
+            match &self.img2img {
+                Some(image) if image.dimensions() == mask.dimensions() => {}
+                Some(image) => return Err(StableDiffusionError::invalid_parameters(format!("the mask must have the resolution of the image, got {:?} and {:?}", mask.dimensions(), image.dimensions()))),
+                None => return Err(StableDiffusionError::invalid_parameters("the mask requires an image to inpaint")),
+            }
+        }
+        if !(0.0 ..= 1.0).contains(&self.img2img_strength) {
+            return Err(StableDiffusionError::invalid_parameters(format!("the image to image strength must be in [0, 1], got {}", self.img2img_strength)));
+        }
+        if !(0.0 ..= 1.0).contains(&self.variation_strength) {
+            return Err(StableDiffusionError::invalid_parameters(format!("the variation strength must be in [0, 1], got {}", self.variation_strength)));
+        }
+        if let Some((width, height)) = self.seed_resize_from {
+            if width < 8 || height < 8 {
+                return Err(StableDiffusionError::invalid_parameters(format!("the seed resize resolution must be at least 8x8, got {width}x{height}")));
+            }
+        }
+        if let Some(guidance_scale) = self.guidance_scale {
+            if !guidance_scale.is_finite() {
+                return Err(StableDiffusionError::invalid_parameters("the guidance scale must be finite"));
+            }
+        }
+        if !(0.0 ..= 1.0).contains(&self.guidance_rescale) {
+            return Err(StableDiffusionError::invalid_parameters(format!("the guidance rescale must be in [0, 1], got {}", self.guidance_rescale)));
+        }
+        if let Some(dynamic_thresholding) = &self.dynamic_thresholding {
+            dynamic_thresholding.validate()?;
+        }
+        self.guidance_schedule.validate()?;
+        if let Some((start, end)) = self.guidance_interval {
+            if !(0.0 <= start && start <= end && end <= 1.0) {
+                return Err(StableDiffusionError::invalid_parameters(format!("the guidance interval must satisfy 0 <= start <= end <= 1, got {start}..{end}")));
+            }
+        }
+        for control in &self.controls {
+            control.validate()?;
+        }
+        for image_prompt in &self.image_prompts {
+            image_prompt.validate()?;
+        }
+        for region in &self.regions {
+            region.validate()?;
+        }
+        if !self.regions.is_empty() && (!self.region_base_weight.is_finite() || self.region_base_weight <= 0.0) {
+            return Err(StableDiffusionError::invalid_parameters(format!("the region base weight must be positive, got {}", self.region_base_weight)));
+        }
+        if let Some(tiling) = &self.tiling {
+            tiling.validate()?;
+        }
+        Ok(())
+    }
+
+    /// Get the noise generator described by the seeds.
+    pub fn noise(&self) -> Noise {
+        let seed = self.seed.unwrap_or_else(rand::random);
+        let variation = self.variation_seed.map(|variation_seed| (variation_seed, self.variation_strength));
+        Noise::new(seed)
+            .with_variation(variation)
+            .with_resize_from(self.seed_resize_from)
+    }
 }
 
 impl StableDiffusion {
     /// Create a new `StableDiffusion` instance from parameters.
     pub fn new(parameters: StableDiffusionParameters) -> Result<Self> {
-        let device = parameters.device;
-        let config = parameters.config;
-        let dtype = parameters.dtype;
-        let version = parameters.weights.version;
-        let weights = parameters.weights;
-
-        println!("Building the unet.");
-        let unet = UNet::new(weights.unet.file.fetch()?, &config, &device, dtype)?;
-
-        println!("Building the autoencoder.");
-        let vae = VAE::new(&config, weights.vae.file.fetch()?, &device, dtype)?;
-        let tokenizer = Tokenizer::new(&config, &weights.tokenizer.tokenizer.fetch()?)?;
-        let clip = CLIP::new(&config.clip, weights.clip.clip.fetch()?, &device, dtype)?;
-        let tokenizer_2 = if let Some(weights) = &weights.tokenizer.tokenizer2 {
-            Some(Tokenizer::new(&config, weights.fetch()?)?)
-        } else {
-            None
-        };
-        let clip_2 = if let (Some(config), Some(weights)) = (&config.clip2, weights.clip.clip2) {
-            Some(CLIP::new(config, weights.fetch()?, &device, dtype)?)
-        } else {
-            None
-        };
+        let components = StableDiffusionComponents::load(&parameters)?;
+        let devices = ComponentDevices::new()
+            .with_unet(Some(parameters.unet_device()))
+            .with_vae(Some(parameters.vae_device()))
+            .with_text_encoder(Some(parameters.text_encoder_device()));
+        let StableDiffusionParameters { weights, config, device, dtype, .. } = parameters;
+        Self::from_components(weights.version, config, device, devices, dtype, components)
+    }
+
+    /// Create a new `StableDiffusion` instance from already loaded components, which must be on the given devices.
+    pub fn from_components(version: StableDiffusionVersion, config: StableDiffusionConfig, device: Device, devices: ComponentDevices, dtype: DType, components: StableDiffusionComponents) -> Result<Self> {
+        components.validate(version)?;
+        let vae_device = devices.vae.unwrap_or_else(|| device.clone());
+        let text_encoder_device = devices.text_encoder.unwrap_or_else(|| device.clone());
+        let device = devices.unet.unwrap_or(device);
+        // Only the resolution of the configuration is kept, its scheduler isn't thread safe.
+        let size = (config.width, config.height);
+        let components = RwLock::new(components);
+        let embedding_cache = Default::default();
+        let progress = None;
+        Ok(Self { version, device, vae_device, text_encoder_device, dtype, size, components, embedding_cache, progress })
+    }
+
+    /// Get the components of the model, which can be used to build other pipelines sharing the same models.
+    ///
+    /// The components are a snapshot: the models replaced afterwards aren't reflected in it.
+    pub fn components(&self) -> StableDiffusionComponents {
+        self.components.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+    }
 
-        Ok(Self { version, device, dtype, config, unet, vae, tokenizer, clip, tokenizer_2, clip_2 })
+    /// Get write access to the components, to replace them.
+    fn components_mut(&self) -> std::sync::RwLockWriteGuard<'_, StableDiffusionComponents> {
+        self.components.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Sets the number of text embeddings kept in the cache shared by the generations.
+    pub fn set_embedding_cache_capacity(&mut self, capacity: usize) {
+        self.embedding_cache = EmbeddingCache::new(capacity);
+    }
+
+    /// Sets the callback the denoising progress is reported to.
+    pub fn set_progress_callback(&mut self, progress: Option<ProgressCallback>) {
+        self.progress = progress;
+    }
+
+    /// Replace the UNet model, returning the previous one. The new model must be on the UNet device.
+    pub fn replace_unet(&self, unet: impl Into<Component<UNet>>) -> Component<UNet> {
+        std::mem::replace(&mut self.components_mut().unet, unet.into())
+    }
+
+    /// Replace the VAE model, returning the previous one. The new model must be on the VAE device.
+    pub fn replace_vae(&self, vae: impl Into<Component<VAE>>) -> Component<VAE> {
+        std::mem::replace(&mut self.components_mut().vae, vae.into())
+    }
+
+    /// Replace the first tokenizer and CLIP model, returning the previous ones. The new model must be on the text encoder device.
+    pub fn replace_text_encoder(&self, tokenizer: Arc<Tokenizer>, clip: impl Into<Component<CLIP>>) -> (Arc<Tokenizer>, Component<CLIP>) {
+        self.embedding_cache.clear();
+        let mut components = self.components_mut();
+        let tokenizer = std::mem::replace(&mut components.tokenizer, tokenizer);
+        let clip = std::mem::replace(&mut components.clip, clip.into());
+        (tokenizer, clip)
+    }
+
+    /// Replace the second tokenizer and CLIP model of the XL versions, returning the previous ones.
+    pub fn replace_text_encoder_2(&self, tokenizer_2: Arc<Tokenizer>, clip_2: impl Into<Component<CLIP>>) -> (Option<Arc<Tokenizer>>, Option<Component<CLIP>>) {
+        self.embedding_cache.clear();
+        let mut components = self.components_mut();
+        let tokenizer_2 = components.tokenizer_2.replace(tokenizer_2);
+        let clip_2 = components.clip_2.replace(clip_2.into());
+        (tokenizer_2, clip_2)
+    }
+
+    /// Register a ControlNet model with a name, returning the model previously registered with it.
+    /// The model must be on the UNet device.
+    pub fn add_controlnet(&self, name: impl Into<String>, controlnet: impl Into<Component<ControlNet>>) -> Option<Component<ControlNet>> {
+        self.components_mut().controlnets.insert(name.into(), controlnet.into())
+    }
+
+    /// Unregister a ControlNet model.
+    pub fn remove_controlnet(&self, name: &str) -> Option<Component<ControlNet>> {
+        self.components_mut().controlnets.remove(name)
+    }
+
+    /// Replace the IP-Adapter model, returning the previous one. The new model must be on the UNet device.
+    pub fn replace_ip_adapter(&self, ip_adapter: Option<Component<IPAdapter>>) -> Option<Component<IPAdapter>> {
+        std::mem::replace(&mut self.components_mut().ip_adapter, ip_adapter)
+    }
+
+    /// Replace the upscaler the decoded images are post-processed with, returning the previous one.
+    pub fn replace_upscaler(&self, upscaler: Option<Component<Upscaler>>) -> Option<Component<Upscaler>> {
+        std::mem::replace(&mut self.components_mut().upscaler, upscaler)
     }
 
     /// Generate an image from the model.
     pub fn generate(&self, args: impl Into<GenerationParameters>) -> Result<image::ImageBuffer<image::Rgb<u8>, Vec<u8>>> {
-        let GenerationParameters {
-            prompt,
-            uncond_prompt,
-            n_steps,
-            guidance_scale,
-            img2img,
-            img2img_strength,
-            width,
-            height,
-            style_prompt,
-            uncond_style_prompt
-        } = args.into();
-        let width = width.unwrap_or(self.config.width);
-        let height = height.unwrap_or(self.config.height);
-    
-        let guidance_scale = match guidance_scale {
-            Some(guidance_scale) => guidance_scale,
-            None => match self.version {
-                StableDiffusionVersion::V1_5
-                | StableDiffusionVersion::V2_1
-                | StableDiffusionVersion::XL => 7.5,
-                StableDiffusionVersion::Turbo => 0.,
-            },
-        };
-        let n_steps = match n_steps {
-            Some(n_steps) => n_steps,
-            None => match self.version {
-                StableDiffusionVersion::V1_5
-                | StableDiffusionVersion::V2_1
-                | StableDiffusionVersion::XL => 30,
-                StableDiffusionVersion::Turbo => 1,
-            },
-        };
-    
-        let scheduler = self.config.build_scheduler(n_steps)?;
-        let use_guide_scale = guidance_scale > 1.0;
-   
-
-        let (t_start, init_latent_dist) = match img2img {
-            None => (0, None),
-            Some(image) => {
-                let t_start = n_steps - (n_steps as f64 * img2img_strength) as usize;
-                (t_start, Some(self.vae.image_to_latent(image, &self.device, self.dtype)?))
+        let args = args.into();
+        args.validate()?;
+        let conditioning = self.conditioning(&args)?;
+        let noise = args.noise().generate(self.latent_shape(&args), &self.device)?;
+        self.sample(&args, &conditioning, &noise)
+    }
+
+    fn guidance_scale(&self, args: &GenerationParameters) -> f64 {
+        args.guidance_scale.unwrap_or_else(|| self.version.default_guidance_scale())
+    }
+
+    /// Check if the classifier-free guidance is applied, which isn't the case if the UNet embeds the guidance scale.
+    fn use_guide_scale(&self, args: &GenerationParameters) -> bool {
+        self.version.guidance_embedding_dim().is_none() && args.guidance_schedule.max_scale(self.guidance_scale(args)) > 1.0
+    }
+
+    /// Get the guidance of a step, or `None` if the step is outside of the guidance interval.
+    fn step_guidance<'a>(&self, args: &'a GenerationParameters, step: usize, n_steps: usize) -> Option<Guidance<'a>> {
+        if let Some((start, end)) = args.guidance_interval {
+            let n_steps = n_steps.max(1) as f64;
+            let (from, to) = (step as f64 / n_steps, (step + 1) as f64 / n_steps);
+            if from < start || to > end {
+                return None;
             }
+        }
+        let fraction = if n_steps > 1 { step as f64 / (n_steps - 1) as f64 } else { 0.0 };
+        let scale = args.guidance_schedule.scale(self.guidance_scale(args), fraction);
+        let rescale = args.guidance_rescale;
+        let thresholding = args.dynamic_thresholding.as_ref();
+        Some(Guidance { scale, rescale, thresholding })
+    }
+
+    fn n_steps(&self, args: &GenerationParameters) -> usize {
+        args.n_steps.unwrap_or_else(|| self.version.default_n_steps())
+    }
+
+    /// Get the `(batch, channels, height, width)` shape of the latents.
+    pub(crate) fn latent_shape(&self, args: &GenerationParameters) -> (usize, usize, usize, usize) {
+        let bsize = 1;
+        let (width, height) = match &args.img2img {
+            Some(image) => (image.width() as usize, image.height() as usize),
+            None => (args.width.unwrap_or(self.size.0), args.height.unwrap_or(self.size.1)),
         };
+        (bsize, 4, height / 8, width / 8)
+    }
+
+    /// Encode the prompts into the text embeddings used to condition the UNet.
+    ///
+    /// The embeddings can be reused for several generations with `GenerationParameters::with_prompt_embeds`.
+    /// The unconditional embeddings are only computed if the guidance scale is greater than 1.
+    /// The prompt is encoded as it is, without the composable and scheduling syntaxes.
+    pub fn encode_prompt(&self, args: &GenerationParameters) -> Result<PromptEmbeddings> {
+        self.encode_text(args, &args.prompt, &args.uncond_prompt)
+    }
 
-        let uncond_prompt = if use_guide_scale { Some(uncond_prompt.as_str()) } else { None };
-        let mut text_embeddings = Vec::new();
+    /// Encode a prompt and an unconditional prompt, with the style prompts of the parameters.
+    fn encode_text(&self, args: &GenerationParameters, prompt: &str, uncond_prompt: &str) -> Result<PromptEmbeddings> {
+        let _span = tracing::debug_span!("text_encoding").entered();
+        let use_guide_scale = self.use_guide_scale(args);
+        let components = self.components();
+        let mut cond = Vec::new();
+        let mut uncond = Vec::new();
         {
-            let (prompt, uncond_prompt) = self.tokenizer.tokenize_pair(&prompt, uncond_prompt)?;
-            text_embeddings.push(self.clip.text_embeddings_pair(
-                prompt,
-                uncond_prompt,
-                &self.device,
-                self.dtype
-            )?);
-        }
-        if matches!(self.version, StableDiffusionVersion::XL | StableDiffusionVersion::Turbo) {
-            let style_prompt = style_prompt.unwrap_or_default();
-            let uncond_style_prompt = Some(
-                uncond_style_prompt
-                .as_ref().map(|s| s.as_str())
-                .unwrap_or(""));
-            let (prompt, uncond_prompt) = self.tokenizer_2.as_ref().unwrap().tokenize_pair(&style_prompt, uncond_style_prompt)?;
-            text_embeddings.push(self.clip_2.as_ref().unwrap().text_embeddings_pair(
-                prompt,
-                uncond_prompt,
-                &self.device,
-                self.dtype
-            )?);
-        }
-
-        let text_embeddings = Tensor::cat(&text_embeddings, D::Minus1)?;
-        println!("{text_embeddings:?}");
-    
-        let bsize = 1;
+            let uncond_prompt = if use_guide_scale { Some(uncond_prompt) } else { None };
+            let (prompt, uncond_prompt) = components.tokenizer.tokenize_pair(prompt, uncond_prompt)?;
+            cond.push(self.encode_tokens(&components.clip, prompt)?);
+            if let Some(uncond_prompt) = uncond_prompt {
+                uncond.push(self.encode_tokens(&components.clip, uncond_prompt)?);
+            }
+        }
+        if self.version.is_xl() {
+            let style_prompt = args.style_prompt.clone().unwrap_or_default();
+            let uncond_style_prompt = if use_guide_scale {
+                Some(args.uncond_style_prompt.as_deref().unwrap_or(""))
+            } else {
+                None
+            };
+            let (tokenizer_2, clip_2) = match (&components.tokenizer_2, &components.clip_2) {
+                (Some(tokenizer_2), Some(clip_2)) => (tokenizer_2, clip_2),
+                _ => return Err(StableDiffusionError::version_mismatch(format!("{:?} requires a second tokenizer and CLIP model", self.version))),
+            };
+            let (prompt, uncond_prompt) = tokenizer_2.tokenize_pair(&style_prompt, uncond_style_prompt)?;
+            cond.push(self.encode_tokens(clip_2, prompt)?);
+            if let Some(uncond_prompt) = uncond_prompt {
+                uncond.push(self.encode_tokens(clip_2, uncond_prompt)?);
+            }
+        }
+
+        let cond = Tensor::cat(&cond, D::Minus1)?;
+        let uncond = if uncond.is_empty() { None } else { Some(Tensor::cat(&uncond, D::Minus1)?) };
+        tracing::trace!(shape = ?cond.shape(), "text embeddings");
+        Ok(PromptEmbeddings::new(cond, uncond))
+    }
+
+    /// Encode tokens with a text encoder, reusing the cached embeddings if they were already computed.
+    fn encode_tokens(&self, clip: &Component<CLIP>, tokens: Vec<u32>) -> Result<Tensor> {
+        let encoder = clip.id();
+        if let Some(embeddings) = self.embedding_cache.get(encoder, &tokens) {
+            tracing::trace!("text embeddings cache hit");
+            return Ok(embeddings);
+        }
+        let embeddings = clip
+            .get()?
+            .text_embeddings(&tokens, &self.text_encoder_device, self.dtype)?
+            .to_device(&self.device)?;
+        self.embedding_cache.insert(encoder, tokens, embeddings.clone());
+        Ok(embeddings)
+    }
+
+    /// Get the text embeddings used to condition the UNet, batched as `[uncond, cond]` when guidance is used.
+    pub(crate) fn text_embeddings(&self, args: &GenerationParameters) -> Result<Tensor> {
+        let embeddings = match &args.prompt_embeds {
+            Some(embeddings) => embeddings.clone(),
+            None => self.encode_prompt(args)?,
+        };
+        self.batch_embeddings(args, embeddings)
+    }
+
+    /// Batch the embeddings as `[uncond, cond]` when guidance is used.
+    fn batch_embeddings(&self, args: &GenerationParameters, embeddings: PromptEmbeddings) -> Result<Tensor> {
+        let use_guide_scale = self.use_guide_scale(args);
+        match (&embeddings.uncond, use_guide_scale) {
+            (None, true) => Err(StableDiffusionError::invalid_parameters("the prompt embeddings require unconditional embeddings when the guidance scale is greater than 1")),
+            (Some(_), false) => Ok(embeddings.cond),
+            _ => embeddings.to_tensor(),
+        }
+    }
+
+    /// Get the weighted text embeddings of each step, from the `AND` terms of the prompt and their schedules.
+    pub(crate) fn conditioning(&self, args: &GenerationParameters) -> Result<Conditioning> {
+        if args.prompt_embeds.is_some() {
+            return Ok(self.text_embeddings(args)?.into());
+        }
+        self.prompt_conditioning(args, &args.prompt, &args.uncond_prompt)
+    }
+
+    /// Get the weighted text embeddings of each step of a prompt and an unconditional prompt.
+    fn prompt_conditioning(&self, args: &GenerationParameters, prompt: &str, uncond_prompt: &str) -> Result<Conditioning> {
+        let n_steps = self.n_steps(args);
+        let terms = PromptTerm::parse(prompt);
+        let schedules = terms.iter().map(|term| PromptSchedule::parse(&term.text, n_steps)).collect::<Vec<_>>();
+        let mut ranges: Vec<(usize, Vec<(Tensor, f64)>)> = Vec::new();
+        let mut previous: Option<Vec<&str>> = None;
+        for step in 0 .. n_steps {
+            let texts = schedules.iter().map(|schedule| schedule.at(step)).collect::<Vec<_>>();
+            if previous.as_ref() == Some(&texts) {
+                if let Some((end, _)) = ranges.last_mut() {
+                    *end = step + 1;
+                }
+                continue;
+            }
+            let embeddings = texts
+                .iter()
+                .zip(&terms)
+                .map(|(text, term)| Ok((self.batch_embeddings(args, self.encode_text(args, text, uncond_prompt)?)?, term.weight)))
+                .collect::<Result<Vec<_>>>()?;
+            ranges.push((step + 1, embeddings));
+            previous = Some(texts);
+        }
+        Conditioning::new(ranges)
+    }
+
+    /// Get the mask and the masked image latents the inpainting UNets are conditioned on, concatenated along the channels.
+    ///
+    /// The image to image input is the image to inpaint, and a missing mask repaints the whole image.
+    fn inpainting_latents(&self, args: &GenerationParameters, vae: Option<&VAE>, latent_width: usize, latent_height: usize, vae_scale: f64) -> Result<Option<Tensor>> {
+        if !self.version.is_inpainting() {
+            return match args.mask {
+                Some(_) => Err(StableDiffusionError::invalid_parameters(format!("masks require an inpainting version, got {:?}", self.version))),
+                None => Ok(None),
+            };
+        }
+        let (image, vae) = args.img2img.as_ref().zip(vae).ok_or_else(|| StableDiffusionError::invalid_parameters("inpainting requires an image to inpaint"))?;
+        let mask = args.mask.clone().unwrap_or_else(|| image::ImageBuffer::from_pixel(image.width(), image.height(), image::Luma([255])));
+        // The masked pixels are set to the middle gray, which is 0 once normalized to [-1, 1].
+        let masked_image = image::ImageBuffer::from_fn(image.width(), image.height(), |x, y| {
+            if mask.get_pixel(x, y)[0] >= 128 { image::Rgb([128, 128, 128]) } else { *image.get_pixel(x, y) }
+        });
+        let masked_latents = (vae.image_to_latent(masked_image, &self.vae_device, self.dtype)?.sample()? * vae_scale)?.to_device(&self.device)?;
+        let mask = image::imageops::resize(&mask, latent_width as u32, latent_height as u32, image::imageops::FilterType::Nearest);
+        let mask = mask.pixels().map(|pixel| if pixel[0] >= 128 { 1f32 } else { 0f32 }).collect::<Vec<_>>();
+        let mask = Tensor::from_vec(mask, (1, 1, latent_height, latent_width), &self.device)?.to_dtype(masked_latents.dtype())?;
+        Ok(Some(Tensor::cat(&[&mask, &masked_latents], 1)?.to_dtype(self.dtype)?))
+    }
+
+    /// Run the denoising loop from the initial noise and decode the result.
+    pub(crate) fn sample(&self, args: &GenerationParameters, conditioning: &Conditioning, noise: &Tensor) -> Result<image::ImageBuffer<image::Rgb<u8>, Vec<u8>>> {
+        let components = self.components();
+        let guidance_scale = self.guidance_scale(args);
+        let n_steps = self.n_steps(args);
     
-        let vae_scale = match self.version {
-            StableDiffusionVersion::V1_5
-            | StableDiffusionVersion::V2_1
-            | StableDiffusionVersion::XL => 0.18215,
-            StableDiffusionVersion::Turbo => 0.13025,
+        let scheduler = self.version.scheduler_config(args.seed.unwrap_or_else(rand::random)).build(n_steps)?;
+        let use_guide_scale = self.use_guide_scale(args);
+
+        // The VAE encodes the image to image input and the inpainting latents at once, before the UNet is used.
+        let encoder = match &args.img2img {
+            Some(_) => Some(components.vae.get()?),
+            None => None,
+        };
+        let (t_start, init_latent_dist) = match (&args.img2img, &encoder) {
+            (Some(image), Some(vae)) => {
+                let t_start = n_steps - (n_steps as f64 * args.img2img_strength) as usize;
+                (t_start, Some(vae.image_to_latent(image.clone(), &self.vae_device, self.dtype)?))
+            }
+            _ => (0, None),
         };
+
+        let vae_scale = self.version.vae_scale();
     
         let timesteps = scheduler.timesteps();
         let latents = match &init_latent_dist {
             Some(init_latent_dist) => {
                 let latents = (init_latent_dist.sample()? * vae_scale)?.to_device(&self.device)?;
                 if t_start < timesteps.len() {
-                    let noise = latents.randn_like(0f64, 1f64)?;
+                    let noise = noise.to_dtype(latents.dtype())?;
                     scheduler.add_noise(&latents, noise, timesteps[t_start])?
                 } else {
                     latents
                 }
             }
             None => {
-                let latents = Tensor::randn(
-                    0f32,
-                    1f32,
-                    (bsize, 4, height / 8, width / 8),
-                    &self.device,
-                )?;
                 // scale the initial noise by the standard deviation required by the scheduler
-                (latents * scheduler.init_noise_sigma())?
+                (noise * scheduler.init_noise_sigma())?
             }
         };
         let mut latents = latents.to_dtype(self.dtype)?;
 
-        println!("starting sampling");
+        let (_, _, latent_height, latent_width) = latents.dims4()?;
+        let inpainting = self.inpainting_latents(args, encoder.as_deref(), latent_width, latent_height, vae_scale)?;
+        drop(encoder);
+
+        let regions = args.regions.iter().map(|region| {
+            let uncond_prompt = region.uncond_prompt.as_deref().unwrap_or(&args.uncond_prompt);
+            let conditioning = self.prompt_conditioning(args, &region.prompt, uncond_prompt)?;
+            Ok((conditioning, region.to_tensor(latent_width, latent_height, &self.device, self.dtype)?))
+        }).collect::<Result<Vec<_>>>()?;
+        // The base prediction covers the canvas with its weight, and the blended predictions are normalized by the total weight.
+        let region_weights = regions.iter().try_fold(
+            (Tensor::ones((1, 1, latent_height, latent_width), self.dtype, &self.device)? * args.region_base_weight)?,
+            |sum, (_, mask)| sum + mask,
+        )?;
+
+        let span = tracing::info_span!("sampling", n_steps, guidance_scale).entered();
+        let unet = components.unet.get()?;
+        let controls = args.controls.iter().map(|control| {
+            let controlnet = components.controlnets
+                .get(&control.controlnet)
+                .ok_or_else(|| StableDiffusionError::invalid_parameters(format!("no ControlNet is registered as {}", control.controlnet)))?
+                .get()?;
+            let image = control.to_tensor(latent_width * 8, latent_height * 8, &self.device, self.dtype)?;
+            Ok((control, controlnet, image))
+        }).collect::<Result<Vec<_>>>()?;
+        let ip_adapter = match (&components.ip_adapter, args.image_prompts.is_empty()) {
+            (_, true) => None,
+            (Some(ip_adapter), false) => Some(ip_adapter.get()?),
+            (None, false) => return Err(StableDiffusionError::invalid_parameters("image prompts require an IP-Adapter")),
+        };
+        let timestep_cond = match self.version.guidance_embedding_dim() {
+            Some(dim) => Some(guidance_scale_embedding(guidance_scale, latents.dim(0)?, dim, &self.device, self.dtype)?),
+            None => None,
+        };
+        let image_tokens = match &ip_adapter {
+            Some(ip_adapter) => args.image_prompts.iter().map(|image_prompt| {
+                let image_embeds = ip_adapter.image_embeds(&image_prompt.image, &self.device, self.dtype)?;
+                Ok((ip_adapter.image_tokens(&image_embeds, use_guide_scale)?, image_prompt.weight))
+            }).collect::<Result<Vec<_>>>()?,
+            None => Vec::new(),
+        };
         for (timestep_index, &timestep) in timesteps.iter().enumerate() {
             if timestep_index < t_start {
                 continue;
             }
+            let _span = tracing::debug_span!("denoising_step", step = timestep_index + 1, timestep).entered();
             let start_time = std::time::Instant::now();
-            let latent_model_input = if use_guide_scale {
-                Tensor::cat(&[&latents, &latents], 0)?
-            } else {
-                latents.clone()
+            let latent_model_input = scheduler.scale_model_input(latents.clone(), timestep)?;
+            // The latents are repeated for every text embedding of the batch, e.g. `[uncond, cond]` with guidance.
+            let predict = |text_embeddings: &Tensor, uncond: bool, window: &Window| -> Result<Tensor> {
+                let copies = text_embeddings.dim(0)? / latents.dim(0)?;
+                let latent_model_input = window.crop(&latent_model_input, 1)?.repeat((copies, 1, 1, 1))?;
+                let mut residuals: Option<(Vec<Tensor>, Tensor)> = None;
+                for (control, controlnet, image) in controls.iter() {
+                    if !control.is_active(timestep_index, timesteps.len()) {
+                        continue;
+                    }
+                    let image = window.crop(image, 8)?;
+                    let (down, mid) = controlnet.forward(&latent_model_input, timestep as f64, text_embeddings, &image, control.scale)?;
+                    residuals = Some(match residuals {
+                        None => (down, mid),
+                        Some((down_sum, mid_sum)) => {
+                            let down = down_sum.iter().zip(&down).map(|(sum, down)| sum + down).collect::<candle::Result<Vec<_>>>()?;
+                            (down, (mid_sum + mid)?)
+                        }
+                    });
+                }
+                let unet_input = match &inpainting {
+                    Some(inpainting) => Tensor::cat(&[&latent_model_input, &window.crop(inpainting, 1)?.repeat((copies, 1, 1, 1))?], 1)?,
+                    None => latent_model_input.clone(),
+                };
+                let image_prompt = match &ip_adapter {
+                    Some(ip_adapter) => {
+                        let tokens = image_tokens.iter()
+                            .map(|(tokens, weight)| Ok((batch_image_tokens(tokens, uncond, copies - usize::from(uncond))?, *weight)))
+                            .collect::<Result<Vec<_>>>()?;
+                        Some(ip_adapter.attention(tokens))
+                    }
+                    None => None,
+                };
+                let unet_conditioning = UNetConditioning {
+                    down_block_additional_residuals: residuals.as_ref().map(|(down, _)| down.as_slice()),
+                    mid_block_additional_residual: residuals.as_ref().map(|(_, mid)| mid),
+                    image_prompt: image_prompt.as_ref(),
+                    timestep_cond: timestep_cond.as_ref(),
+                };
+                unet.forward_with_conditioning(&unet_input, timestep as f64, text_embeddings, &unet_conditioning)
             };
 
-            let latent_model_input = scheduler.scale_model_input(latent_model_input, timestep)?;
-            let noise_pred =
-                self.unet.forward(&latent_model_input, timestep as f64, &text_embeddings)?;
-
-            let noise_pred = if use_guide_scale {
-                let noise_pred = noise_pred.chunk(2, 0)?;
-                let (noise_pred_uncond, noise_pred_text) = (&noise_pred[0], &noise_pred[1]);
-
-                (noise_pred_uncond + ((noise_pred_text - noise_pred_uncond)? * guidance_scale)?)?
-            } else {
-                noise_pred
+            // Outside of the guidance interval, the unconditional half of the batch isn't evaluated.
+            let guidance = if use_guide_scale { self.step_guidance(args, timestep_index, timesteps.len()) } else { None };
+            // The unconditional embeddings, shared by the terms of a composable prompt, and the conditional embeddings
+            // of every term are evaluated in a single batch when their shapes match. The terms are combined with their weights.
+            let guided = |terms: &[(Tensor, f64)], window: &Window| -> Result<Tensor> {
+                if terms.is_empty() {
+                    return Err(StableDiffusionError::invalid_parameters("the prompt has no terms"));
+                }
+                let uncond = guidance.is_some();
+                let mut embeddings = Vec::with_capacity(terms.len() + 1);
+                for (text_embeddings, _) in terms {
+                    if use_guide_scale {
+                        let halves = text_embeddings.chunk(2, 0)?;
+                        if uncond && embeddings.is_empty() {
+                            embeddings.push(halves[0].clone());
+                        }
+                        embeddings.push(halves[1].clone());
+                    } else {
+                        embeddings.push(text_embeddings.clone());
+                    }
+                }
+                let predictions = if embeddings.iter().all(|embedding| embedding.shape() == embeddings[0].shape()) {
+                    predict(&Tensor::cat(&embeddings, 0)?, uncond, window)?.chunk(embeddings.len(), 0)?
+                } else {
+                    // Embeddings of different lengths can't be batched, the first term is evaluated with the unconditional embeddings.
+                    let first = usize::from(uncond) + 1;
+                    let mut predictions = predict(&Tensor::cat(&embeddings[.. first], 0)?, uncond, window)?.chunk(first, 0)?;
+                    for embedding in &embeddings[first ..] {
+                        predictions.push(predict(embedding, false, window)?);
+                    }
+                    predictions
+                };
+                let weights = terms.iter().map(|(_, weight)| *weight);
+                if let Some(guidance) = &guidance {
+                    let noise_pred_uncond = &predictions[0];
+                    let mut noise_pred_text = noise_pred_uncond.clone();
+                    for (noise_pred, weight) in predictions[1 ..].iter().zip(weights) {
+                        noise_pred_text = (noise_pred_text + ((noise_pred - noise_pred_uncond)? * weight)?)?;
+                    }
+                    guidance.apply(noise_pred_uncond, &noise_pred_text)
+                } else if let [noise_pred] = predictions.as_slice() {
+                    Ok(noise_pred.clone())
+                } else {
+                    // Without guidance, the terms are averaged with their weights.
+                    let total_weight = weights.clone().sum::<f64>();
+                    if total_weight == 0.0 {
+                        return Err(StableDiffusionError::invalid_parameters("the weights of the prompt terms sum to zero"));
+                    }
+                    let mut noise_pred = (&predictions[0] * 0.0)?;
+                    for (prediction, weight) in predictions.iter().zip(weights) {
+                        noise_pred = (noise_pred + (prediction * (weight / total_weight))?)?;
+                    }
+                    Ok(noise_pred)
+                }
+            };
+            let step_prediction = |window: &Window| -> Result<Tensor> {
+                let noise_pred = guided(conditioning.at(timestep_index), window)?;
+                // The regions are blended with their masks after the guidance.
+                if regions.is_empty() {
+                    return Ok(noise_pred);
+                }
+                let mut blended = (noise_pred * args.region_base_weight)?;
+                for (conditioning, mask) in &regions {
+                    blended = (blended + guided(conditioning.at(timestep_index), window)?.broadcast_mul(&window.crop(mask, 1)?)?)?;
+                }
+                Ok(blended.broadcast_div(&window.crop(&region_weights, 1)?)?)
+            };
+            // The tiles are predicted separately and averaged where they overlap, as in MultiDiffusion.
+            let noise_pred = match &args.tiling {
+                Some(tiling) => tiling.blend(latent_height, latent_width, &self.device, step_prediction)?.to_dtype(self.dtype)?,
+                None => step_prediction(&Window { top: 0, left: 0, height: latent_height, width: latent_width })?,
             };
 
             latents = scheduler.step(&noise_pred, timestep, &latents)?;
             let dt = start_time.elapsed().as_secs_f32();
-            println!("step {}/{n_steps} done, {:.2}s", timestep_index + 1, dt);
+            tracing::debug!(elapsed = dt, "step {}/{n_steps} done", timestep_index + 1);
+            if let Some(progress) = &self.progress {
+                progress(timestep_index + 1 - t_start, timesteps.len() - t_start);
+            }
+        }
+        // Release the UNet before the VAE is loaded when the components are offloaded.
+        drop(unet);
+        drop(span);
+        let image = tracing::debug_span!("vae_decode").in_scope(|| {
+            let vae = components.vae.get()?;
+            let latents = latents.to_device(&self.vae_device)?;
+            match args.tiling.and_then(|tiling| tiling.vae_tiling) {
+                Some((tile_size, overlap)) => vae.latent_to_image_tiled(&latents, vae_scale, tile_size, overlap),
+                None => vae.latent_to_image(&latents, vae_scale),
+            }
+        })?;
+        match &components.upscaler {
+            Some(upscaler) => upscaler.get()?.upscale(&image),
+            None => Ok(image),
         }
-        Ok(self.vae.latent_to_image(&latents, vae_scale)?)
     }
 }
 
-/// The `StableDiffusion` struct is used to specify the Stable Diffusion model.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The `StableDiffusionVersion` enum is used to specify the version of the Stable Diffusion model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum StableDiffusionVersion {
+    /// Stable Diffusion 1.5.
     V1_5,
+    /// Stable Diffusion 1.5 inpainting.
+    V1_5Inpaint,
+    /// Stable Diffusion 2.0 at 768x768 with v-prediction.
+    V2_0,
+    /// Stable Diffusion 2.0 base at 512x512.
+    V2_0Base,
+    /// Stable Diffusion 2.1 at 768x768 with v-prediction.
     V2_1,
+    /// Stable Diffusion 2.1 base at 512x512.
+    V2_1Base,
+    /// SD-Turbo, distilled from Stable Diffusion 2.1 for single step generation.
+    SdTurbo,
+    /// Stable Diffusion XL 1.0.
     XL,
+    /// Stable Diffusion XL 1.0 inpainting.
+    XLInpaint,
+    /// SDXL-Turbo, distilled from Stable Diffusion XL for single step generation.
     Turbo,
+    /// The LCM distilled Dreamshaper v7, a Stable Diffusion 1.5 model embedding the guidance scale.
+    LcmV1_5,
+    /// The LCM distilled Stable Diffusion XL UNet.
+    LcmXL,
+}
+
+/// The `Architecture` enum is used to group the versions sharing their text encoders and VAE.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Architecture {
+    V1,
+    V2,
+    XL,
 }
 
 impl StableDiffusionVersion {
     fn repo(&self) -> &'static str {
         match self {
-            Self::XL => "stabilityai/stable-diffusion-xl-base-1.0",
-            Self::V2_1 => "stabilityai/stable-diffusion-2-1",
             Self::V1_5 => "runwayml/stable-diffusion-v1-5",
+            Self::V1_5Inpaint => "runwayml/stable-diffusion-inpainting",
+            Self::V2_0 => "stabilityai/stable-diffusion-2",
+            Self::V2_0Base => "stabilityai/stable-diffusion-2-base",
+            Self::V2_1 => "stabilityai/stable-diffusion-2-1",
+            Self::V2_1Base => "stabilityai/stable-diffusion-2-1-base",
+            Self::SdTurbo => "stabilityai/sd-turbo",
+            Self::XL | Self::LcmXL => "stabilityai/stable-diffusion-xl-base-1.0",
+            Self::XLInpaint => "diffusers/stable-diffusion-xl-1.0-inpainting-0.1",
             Self::Turbo => "stabilityai/sdxl-turbo",
+            Self::LcmV1_5 => "SimianLuo/LCM_Dreamshaper_v7",
+        }
+    }
+
+    pub(crate) fn architecture(&self) -> Architecture {
+        match self {
+            Self::V1_5 | Self::V1_5Inpaint | Self::LcmV1_5 => Architecture::V1,
+            Self::V2_0 | Self::V2_0Base | Self::V2_1 | Self::V2_1Base | Self::SdTurbo => Architecture::V2,
+            Self::XL | Self::XLInpaint | Self::Turbo | Self::LcmXL => Architecture::XL,
+        }
+    }
+
+    /// Check if the version has a second text encoder.
+    pub fn is_xl(&self) -> bool {
+        self.architecture() == Architecture::XL
+    }
+
+    /// Check if the UNet also gets a mask and the latents of the masked image.
+    pub fn is_inpainting(&self) -> bool {
+        matches!(self, Self::V1_5Inpaint | Self::XLInpaint)
+    }
+
+    /// Get what the UNet predicts: the noise, or the velocity of the 768x768 Stable Diffusion 2 models.
+    pub fn prediction_type(&self) -> PredictionType {
+        match self {
+            Self::V2_0 | Self::V2_1 => PredictionType::VPrediction,
+            _ => PredictionType::Epsilon,
+        }
+    }
+
+    /// Get the default `(width, height)` resolution.
+    pub fn default_size(&self) -> (usize, usize) {
+        match self {
+            Self::V2_0 | Self::V2_1 => (768, 768),
+            Self::XL | Self::XLInpaint | Self::LcmXL => (1024, 1024),
+            _ => (512, 512),
+        }
+    }
+
+    /// Get the default guidance scale. The guidance scale of the LCM Dreamshaper is embedded instead of applied.
+    pub fn default_guidance_scale(&self) -> f64 {
+        match self {
+            Self::SdTurbo | Self::Turbo => 0.,
+            Self::LcmV1_5 => 8.,
+            Self::LcmXL => 1.,
+            _ => 7.5,
         }
     }
+
+    /// Get the default number of steps.
+    pub fn default_n_steps(&self) -> usize {
+        match self {
+            Self::SdTurbo | Self::Turbo => 1,
+            Self::LcmV1_5 | Self::LcmXL => 4,
+            _ => 30,
+        }
+    }
+
+    /// Get the factor the latents of the VAE are scaled by.
+    pub fn vae_scale(&self) -> f64 {
+        match self.architecture() {
+            Architecture::V1 | Architecture::V2 => 0.18215,
+            Architecture::XL => 0.13025,
+        }
+    }
+
+    /// Get the dimension of the guidance scale embeddings added to the timestep embeddings, if the UNet has them.
+    pub(crate) fn guidance_embedding_dim(&self) -> Option<usize> {
+        match self {
+            Self::LcmV1_5 => Some(256),
+            _ => None,
+        }
+    }
+
+    /// Get the configuration of the scheduler, seeding the noise injected by the LCM scheduler.
+    pub(crate) fn scheduler_config(&self, seed: u64) -> Arc<dyn SchedulerConfig> {
+        let prediction_type = self.prediction_type();
+        match self {
+            Self::SdTurbo | Self::Turbo => Arc::new(EulerAncestralDiscreteSchedulerConfig {
+                timestep_spacing: TimestepSpacing::Trailing,
+                prediction_type,
+                ..Default::default()
+            }),
+            Self::LcmV1_5 | Self::LcmXL => Arc::new(LCMSchedulerConfig { prediction_type, seed, ..Default::default() }),
+            _ => Arc::new(DDIMSchedulerConfig { prediction_type, ..Default::default() }),
+        }
+    }
+
+    /// Get the Stable Diffusion configuration at a resolution.
+    pub fn config(&self, width: usize, height: usize) -> StableDiffusionConfig {
+        let (width, height) = (Some(width), Some(height));
+        match self.architecture() {
+            Architecture::V1 => StableDiffusionConfig::v1_5(None, height, width),
+            Architecture::V2 => StableDiffusionConfig::v2_1(None, height, width),
+            Architecture::XL => StableDiffusionConfig::sdxl(None, height, width),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Create a component whose model can't be loaded, for the tests that don't run the models.
+    fn unloaded<T: 'static>() -> Component<T> {
+        Component::offloaded(|| Err(StableDiffusionError::invalid_parameters("the test models aren't loaded")))
+    }
+
+    /// Create a pipeline with a tiny tokenizer and unloaded models.
+    fn pipeline() -> StableDiffusion {
+        let folder = std::env::temp_dir().join("stable-diffusion-pipeline-test");
+        std::fs::create_dir_all(&folder).unwrap();
+        std::fs::write(folder.join("vocab.json"), r#"{"<|startoftext|>": 0, "<|endoftext|>": 1, "a</w>": 2}"#).unwrap();
+        std::fs::write(folder.join("merges.txt"), "#version: 0.2\n").unwrap();
+        let version = StableDiffusionVersion::V1_5;
+        let config = version.config(512, 512);
+        let tokenizer = Arc::new(Tokenizer::new(&config, folder.join("vocab.json")).unwrap());
+        let components = StableDiffusionComponents::new(unloaded::<UNet>(), unloaded::<VAE>(), tokenizer, unloaded::<CLIP>());
+        StableDiffusion::from_components(version, config, Device::Cpu, ComponentDevices::new(), DType::F32, components).unwrap()
+    }
+
+    #[test]
+    fn shared_components() {
+        fn shareable<T: Send + Sync>() {}
+        shareable::<StableDiffusion>();
+        let base = Arc::new(pipeline());
+        let components = base.components().with_unet(unloaded::<UNet>());
+        let version = StableDiffusionVersion::V1_5;
+        let fine_tuned = StableDiffusion::from_components(version, version.config(512, 512), Device::Cpu, ComponentDevices::new(), DType::F32, components).unwrap();
+        assert_eq!(base.components().vae.id(), fine_tuned.components().vae.id());
+        assert_ne!(base.components().unet.id(), fine_tuned.components().unet.id());
+
+        // The components of a shared pipeline can be swapped without affecting the pipelines built from them.
+        let previous = base.replace_vae(unloaded::<VAE>());
+        assert_eq!(previous.id(), fine_tuned.components().vae.id());
+        assert_ne!(base.components().vae.id(), fine_tuned.components().vae.id());
+    }
+
+    #[test]
+    fn cached_embeddings_are_invalidated_with_the_text_encoder() {
+        let pipeline = pipeline();
+        let clip = pipeline.components().clip;
+        let embeddings = Tensor::zeros((1, 77, 768), DType::F32, &Device::Cpu).unwrap();
+        pipeline.embedding_cache.insert(clip.id(), vec![0, 2, 1], embeddings);
+        // The unloaded encoder fails if it's used, so a hit is served from the cache.
+        assert!(pipeline.encode_tokens(&clip, vec![0, 2, 1]).is_ok());
+        assert!(pipeline.encode_tokens(&clip, vec![0, 1]).is_err());
+
+        pipeline.replace_text_encoder(pipeline.components().tokenizer, clip.clone());
+        assert!(pipeline.encode_tokens(&clip, vec![0, 2, 1]).is_err());
+    }
+
+    #[test]
+    fn image_tokens_are_batched_with_the_terms() {
+        let tokens = Tensor::arange(0f32, 2., &Device::Cpu).unwrap().reshape((2, 1, 1)).unwrap();
+        let batched = |tokens: &Tensor, uncond, conds| batch_image_tokens(tokens, uncond, conds).unwrap().flatten_all().unwrap().to_vec1::<f32>().unwrap();
+        assert_eq!(batched(&tokens, true, 3), [0., 1., 1., 1.]);
+        assert_eq!(batched(&tokens, false, 2), [1., 1.]);
+        assert_eq!(batched(&tokens.narrow(0, 1, 1).unwrap(), false, 2), [1., 1.]);
+    }
 }
@@ -0,0 +1,394 @@
+//! A module for indexing the checkpoints, LoRAs, VAEs, embeddings and ControlNets of local folders.
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{Result, StableDiffusionError};
+
+/// The `ModelKind` enum is used to specify what a model file contains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModelKind {
+    /// A full model, or a diffusers UNet.
+    Checkpoint,
+    /// A LoRA, LoCon, LoHa or LoKr adapter.
+    Lora,
+    /// A Variational Autoencoder.
+    Vae,
+    /// A textual inversion embedding.
+    Embedding,
+    /// A ControlNet.
+    ControlNet,
+    /// A file whose tensors weren't recognized.
+    Unknown,
+}
+
+impl ModelKind {
+    /// The name of the kind, used as the prefix of the model references, e.g. `lora:name`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Checkpoint => "checkpoint",
+            Self::Lora => "lora",
+            Self::Vae => "vae",
+            Self::Embedding => "embedding",
+            Self::ControlNet => "controlnet",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
+impl std::str::FromStr for ModelKind {
+    type Err = StableDiffusionError;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "checkpoint" => Ok(Self::Checkpoint),
+            "lora" => Ok(Self::Lora),
+            "vae" => Ok(Self::Vae),
+            "embedding" => Ok(Self::Embedding),
+            "controlnet" => Ok(Self::ControlNet),
+            "unknown" => Ok(Self::Unknown),
+            _ => Err(StableDiffusionError::invalid_parameters(format!("unknown model kind {name}, expected checkpoint, lora, vae, embedding or controlnet"))),
+        }
+    }
+}
+
+/// The `BaseModel` enum is used to specify the model family a file was trained for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum BaseModel {
+    /// Stable Diffusion 1.x.
+    #[serde(rename = "sd1")]
+    V1,
+    /// Stable Diffusion 2.x.
+    #[serde(rename = "sd2")]
+    V2,
+    /// Stable Diffusion XL.
+    #[serde(rename = "sdxl")]
+    XL,
+}
+
+impl BaseModel {
+    /// The name of the base model.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::V1 => "sd1",
+            Self::V2 => "sd2",
+            Self::XL => "sdxl",
+        }
+    }
+
+    /// Get the base model from the dimension of the text embeddings it's conditioned on.
+    fn from_context_dim(dim: usize) -> Option<Self> {
+        match dim {
+            768 => Some(Self::V1),
+            1024 => Some(Self::V2),
+            2048 => Some(Self::XL),
+            _ => None,
+        }
+    }
+}
+
+/// The `ModelEntry` struct is used to describe a model file of the library.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelEntry {
+    /// The file name without its extension, used to resolve the model references.
+    pub name: String,
+    pub path: PathBuf,
+    pub kind: ModelKind,
+    pub base: Option<BaseModel>,
+    /// The SHA-256 of the file, in hexadecimal.
+    pub sha256: String,
+    pub size: u64,
+    /// The modification time of the file, in seconds since the Unix epoch.
+    pub modified: u64,
+    /// The kohya `ss_*` training metadata.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl ModelEntry {
+    /// The first 10 characters of the SHA-256, as shown by the web UIs.
+    pub fn short_hash(&self) -> &str {
+        &self.sha256[.. self.sha256.len().min(10)]
+    }
+
+    /// Create an entry by reading the header of a safetensors file and hashing it.
+    fn from_file(path: &Path, size: u64, modified: u64) -> Result<Self> {
+        let header = SafetensorsHeader::read(path)?;
+        let kind = header.kind();
+        let base = header.base(kind);
+        let metadata = header.metadata.into_iter().filter(|(key, _)| key.starts_with("ss_")).collect();
+        let sha256 = sha256(path)?;
+        let name = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+        let path = path.to_path_buf();
+        Ok(Self { name, path, kind, base, sha256, size, modified, metadata })
+    }
+}
+
+/// The maximum size of a safetensors header, as in the safetensors crate.
+const MAX_HEADER_SIZE: u64 = 100_000_000;
+
+/// The tensor shapes and metadata of a safetensors file.
+struct SafetensorsHeader {
+    shapes: BTreeMap<String, Vec<usize>>,
+    metadata: BTreeMap<String, String>,
+}
+
+impl SafetensorsHeader {
+    /// Read the header without reading the tensors.
+    fn read(path: &Path) -> Result<Self> {
+        let invalid = |message: String| StableDiffusionError::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{}: {message}", path.display())));
+        let mut file = std::fs::File::open(path)?;
+        let mut length = [0; 8];
+        file.read_exact(&mut length)?;
+        let length = u64::from_le_bytes(length);
+        if length > MAX_HEADER_SIZE || length > file.metadata()?.len() {
+            return Err(invalid(format!("invalid header length {length}")));
+        }
+        let mut bytes = vec![0; length as usize];
+        file.read_exact(&mut bytes)?;
+        let header: BTreeMap<String, serde_json::Value> = serde_json::from_slice(&bytes).map_err(|error| invalid(error.to_string()))?;
+        let mut shapes = BTreeMap::new();
+        let mut metadata = BTreeMap::new();
+        for (name, value) in header {
+            if name == "__metadata__" {
+                metadata = serde_json::from_value(value).map_err(|error| invalid(error.to_string()))?;
+            } else {
+                let shape = value
+                    .get("shape")
+                    .and_then(|shape| shape.as_array())
+                    .map(|shape| shape.iter().filter_map(|dim| dim.as_u64()).map(|dim| dim as usize).collect())
+                    .unwrap_or_default();
+                shapes.insert(name, shape);
+            }
+        }
+        Ok(Self { shapes, metadata })
+    }
+
+    fn contains(&self, pattern: &str) -> bool {
+        self.shapes.keys().any(|name| name.contains(pattern))
+    }
+
+    /// Classify the file from the names of its tensors.
+    fn kind(&self) -> ModelKind {
+        let is_lora = ["lora_up", "lora_down", "lora_A", "lora_B", ".hada_", ".lokr_"].iter().any(|pattern| self.contains(pattern));
+        let is_embedding = !self.shapes.is_empty()
+            && self.shapes.keys().all(|name| ["emb_params", "clip_l", "clip_g"].contains(&name.as_str()));
+        if is_lora {
+            ModelKind::Lora
+        } else if self.contains("control_model.") || self.contains("controlnet_cond_embedding.") {
+            ModelKind::ControlNet
+        } else if self.contains("model.diffusion_model.") || self.contains("time_embedding.") {
+            ModelKind::Checkpoint
+        } else if self.contains("encoder.") && self.contains("decoder.") && self.contains("quant_conv.") {
+            ModelKind::Vae
+        } else if is_embedding {
+            ModelKind::Embedding
+        } else {
+            ModelKind::Unknown
+        }
+    }
+
+    /// Detect the base model from the training metadata, or from the shapes of the cross-attention layers.
+    fn base(&self, kind: ModelKind) -> Option<BaseModel> {
+        if let Some(version) = self.metadata.get("ss_base_model_version") {
+            if version.starts_with("sdxl") {
+                return Some(BaseModel::XL);
+            } else if version.starts_with("sd_v2") {
+                return Some(BaseModel::V2);
+            } else if version.starts_with("sd_v1") {
+                return Some(BaseModel::V1);
+            }
+        }
+        if self.metadata.get("ss_v2").is_some_and(|v2| v2 == "True") {
+            return Some(BaseModel::V2);
+        }
+        match kind {
+            ModelKind::Embedding if self.shapes.contains_key("clip_g") => Some(BaseModel::XL),
+            ModelKind::Embedding => self.shapes.get("emb_params").and_then(|shape| shape.last()).and_then(|dim| BaseModel::from_context_dim(*dim)),
+            // The VAEs of the versions share their architecture.
+            ModelKind::Vae | ModelKind::Unknown => None,
+            _ if self.contains("lora_te2_") || self.contains("conditioner.embedders.1.") => Some(BaseModel::XL),
+            // The keys of the text projections are the input features, i.e. the dimension of the text embeddings.
+            _ => self
+                .shapes
+                .iter()
+                .filter(|(name, shape)| name.contains("attn2") && name.contains("to_k") && name.ends_with("weight") && shape.len() == 2)
+                .find(|(name, _)| !name.contains("lora_up") && !name.contains("lora_B"))
+                .and_then(|(_, shape)| BaseModel::from_context_dim(shape[1])),
+        }
+    }
+}
+
+/// Compute the SHA-256 of a file, in hexadecimal.
+fn sha256(path: &Path) -> Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1 << 20];
+    loop {
+        let count = file.read(&mut buffer)?;
+        if count == 0 {
+            break;
+        }
+        hasher.update(&buffer[.. count]);
+    }
+    Ok(hasher.finalize().iter().map(|byte| format!("{byte:02x}")).collect())
+}
+
+/// The content of the index file.
+#[derive(Default, Serialize, Deserialize)]
+struct Index {
+    entries: Vec<ModelEntry>,
+}
+
+/// The `ModelLibrary` struct is used to scan folders for model files and resolve references like `lora:name`.
+///
+/// Only safetensors files are inspected. The entries are cached in an optional index file, and the files whose size and
+/// modification time didn't change aren't hashed again.
+#[derive(Debug, Clone, Default)]
+pub struct ModelLibrary {
+    folders: Vec<PathBuf>,
+    index: Option<PathBuf>,
+    entries: Vec<ModelEntry>,
+}
+
+impl ModelLibrary {
+    /// Create a new `ModelLibrary` instance scanning folders recursively.
+    pub fn new(folders: impl IntoIterator<Item = impl Into<PathBuf>>) -> Self {
+        let folders = folders.into_iter().map(Into::into).collect();
+        Self { folders, ..Default::default() }
+    }
+
+    /// Sets the index file caching the entries.
+    pub fn with_index(self, index: impl Into<PathBuf>) -> Self {
+        let index = Some(index.into());
+        Self { index, ..self }
+    }
+
+    /// The entries found by the last scan.
+    pub fn entries(&self) -> &[ModelEntry] {
+        &self.entries
+    }
+
+    /// Scan the folders, reusing the cached entries of the unchanged files, and update the index file.
+    pub fn scan(&mut self) -> Result<&[ModelEntry]> {
+        let cached = match &self.index {
+            Some(index) if index.exists() => {
+                let index: Index = serde_json::from_slice(&std::fs::read(index)?).unwrap_or_else(|error| {
+                    tracing::warn!(path = %index.display(), %error, "ignoring the invalid index");
+                    Index::default()
+                });
+                index.entries.into_iter().map(|entry| (entry.path.clone(), entry)).collect()
+            }
+            _ => BTreeMap::new(),
+        };
+        let mut files = Vec::new();
+        for folder in &self.folders {
+            collect_files(folder, &mut files)?;
+        }
+        let mut entries = Vec::new();
+        for path in files {
+            // The index stores the paths as strings.
+            if path.to_str().is_none() {
+                tracing::warn!(path = %path.display(), "skipping model with a non UTF-8 path");
+                continue;
+            }
+            let metadata = std::fs::metadata(&path)?;
+            let size = metadata.len();
+            let modified = metadata
+                .modified()?
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default();
+            match cached.get(&path) {
+                Some(entry) if entry.size == size && entry.modified == modified => entries.push(entry.clone()),
+                _ => match ModelEntry::from_file(&path, size, modified) {
+                    Ok(entry) => entries.push(entry),
+                    Err(error) => tracing::warn!(path = %path.display(), %error, "skipping unreadable model"),
+                },
+            }
+        }
+        if let Some(index) = &self.index {
+            let index_entries = Index { entries: entries.clone() };
+            let json = serde_json::to_vec_pretty(&index_entries).map_err(std::io::Error::from)?;
+            std::fs::write(index, json)?;
+        }
+        self.entries = entries;
+        Ok(&self.entries)
+    }
+
+    /// Find a model by kind and name.
+    pub fn find(&self, kind: ModelKind, name: &str) -> Option<&ModelEntry> {
+        self.entries.iter().find(|entry| entry.kind == kind && entry.name == name)
+    }
+
+    /// Resolve a reference like `lora:name` or `checkpoint:name` to the path of the model.
+    pub fn resolve(&self, reference: &str) -> Result<PathBuf> {
+        let (kind, name) = reference
+            .split_once(':')
+            .ok_or_else(|| StableDiffusionError::invalid_parameters(format!("invalid model reference {reference}, expected kind:name")))?;
+        let kind = kind.parse()?;
+        self.find(kind, name)
+            .map(|entry| entry.path.clone())
+            .ok_or_else(|| StableDiffusionError::MissingFile(reference.into()))
+    }
+}
+
+/// Collect the safetensors files of a folder and its sub-folders, sorted by path.
+fn collect_files(folder: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let mut paths = std::fs::read_dir(folder)?.map(|entry| entry.map(|entry| entry.path())).collect::<std::io::Result<Vec<_>>>()?;
+    paths.sort();
+    for path in paths {
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else if path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("safetensors")) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use candle::{DType, Device, Tensor};
+
+    #[test]
+    fn scan_classifies_and_resolves() {
+        let folder = std::env::temp_dir().join("stable-diffusion-library-test");
+        std::fs::create_dir_all(folder.join("loras")).unwrap();
+        let tensor = |shape: &[usize]| Tensor::zeros(shape, DType::F32, &Device::Cpu).unwrap();
+        let lora = [
+            ("lora_unet_down_blocks_0_attentions_0_transformer_blocks_0_attn2_to_k.lora_down.weight".to_string(), tensor(&[4, 1024])),
+            ("lora_unet_down_blocks_0_attentions_0_transformer_blocks_0_attn2_to_k.lora_up.weight".to_string(), tensor(&[320, 4])),
+        ];
+        candle::safetensors::save(&lora.into_iter().collect(), folder.join("loras/whitedog.safetensors")).unwrap();
+        candle::safetensors::save(&[("emb_params".to_string(), tensor(&[2, 768]))].into_iter().collect(), folder.join("cat.safetensors")).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            let name = std::ffi::OsStr::from_bytes(b"invalid-\xff.safetensors");
+            std::fs::copy(folder.join("cat.safetensors"), folder.join(name)).unwrap();
+        }
+        std::fs::write(folder.join("corrupt.safetensors"), u64::MAX.to_le_bytes()).unwrap();
+
+        let index = folder.join("index.json");
+        let mut library = ModelLibrary::new([&folder]).with_index(&index);
+        library.scan().unwrap();
+        let lora = library.find(ModelKind::Lora, "whitedog").unwrap();
+        assert_eq!(lora.base, Some(BaseModel::V2));
+        assert_eq!(lora.sha256.len(), 64);
+        let embedding = library.find(ModelKind::Embedding, "cat").unwrap();
+        assert_eq!(embedding.base, Some(BaseModel::V1));
+        assert_eq!(library.resolve("lora:whitedog").unwrap(), folder.join("loras/whitedog.safetensors"));
+        assert!(library.resolve("lora:cat").is_err());
+
+        let mut cached = ModelLibrary::new([&folder]).with_index(&index);
+        assert_eq!(cached.scan().unwrap().len(), 2);
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+}
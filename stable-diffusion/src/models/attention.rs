@@ -0,0 +1,343 @@
+//! Attention blocks of the UNet.
+
+use std::cell::Cell;
+
+use candle::{DType, IndexOp, Module, Result, Tensor, D};
+use candle_nn::{Conv2d, GroupNorm, LayerNorm};
+
+use super::nn::{self, Linear, VarBuilder};
+
+#[derive(Debug)]
+struct GeGlu {
+    proj: Linear,
+}
+
+impl GeGlu {
+    fn new(vs: VarBuilder, dim_in: usize, dim_out: usize) -> Result<Self> {
+        let proj = nn::linear(dim_in, dim_out * 2, vs.pp("proj"))?;
+        Ok(Self { proj })
+    }
+}
+
+impl Module for GeGlu {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let hidden_states_and_gate = self.proj.forward(xs)?.chunk(2, D::Minus1)?;
+        &hidden_states_and_gate[0] * hidden_states_and_gate[1].gelu()?
+    }
+}
+
+#[derive(Debug)]
+struct FeedForward {
+    project_in: GeGlu,
+    linear: Linear,
+}
+
+impl FeedForward {
+    fn new(vs: VarBuilder, dim: usize, dim_out: Option<usize>, mult: usize) -> Result<Self> {
+        let inner_dim = dim * mult;
+        let dim_out = dim_out.unwrap_or(dim);
+        let vs = vs.pp("net");
+        let project_in = GeGlu::new(vs.pp("0"), dim, inner_dim)?;
+        let linear = nn::linear(inner_dim, dim_out, vs.pp("2"))?;
+        Ok(Self { project_in, linear })
+    }
+}
+
+impl Module for FeedForward {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = self.project_in.forward(xs)?;
+        self.linear.forward(&xs)
+    }
+}
+
+/// The `ImagePromptProjection` struct is used to hold the IP-Adapter key and value projections of a cross-attention.
+#[derive(Debug)]
+pub struct ImagePromptProjection {
+    to_k_ip: Linear,
+    to_v_ip: Linear,
+}
+
+impl ImagePromptProjection {
+    /// Create a new `ImagePromptProjection` instance.
+    pub fn new(vs: VarBuilder, context_dim: usize, inner_dim: usize) -> Result<Self> {
+        let to_k_ip = nn::linear_no_bias(context_dim, inner_dim, vs.pp("to_k_ip"))?;
+        let to_v_ip = nn::linear_no_bias(context_dim, inner_dim, vs.pp("to_v_ip"))?;
+        Ok(Self { to_k_ip, to_v_ip })
+    }
+}
+
+/// The `ImagePromptAttention` struct is used to hold the image prompt tokens the cross-attentions attend to,
+/// with the projections of every cross-attention in the order they are applied.
+#[derive(Debug)]
+pub struct ImagePromptAttention<'a> {
+    projections: &'a [ImagePromptProjection],
+    tokens: Vec<(Tensor, f64)>,
+    cursor: Cell<usize>,
+}
+
+impl<'a> ImagePromptAttention<'a> {
+    /// Create a new `ImagePromptAttention` instance from the projections and the weighted tokens of every image.
+    pub fn new(projections: &'a [ImagePromptProjection], tokens: Vec<(Tensor, f64)>) -> Self {
+        let cursor = Cell::new(0);
+        Self { projections, tokens, cursor }
+    }
+
+    /// Restart from the first cross-attention, before a forward pass of the UNet.
+    pub fn reset(&self) {
+        self.cursor.set(0);
+    }
+
+    fn next_projection(&self) -> Result<&'a ImagePromptProjection> {
+        let index = self.cursor.get();
+        self.cursor.set(index + 1);
+        match self.projections.get(index) {
+            Some(projection) => Ok(projection),
+            None => candle::bail!("the image prompt has {} projections, but the UNet has more cross-attentions", self.projections.len()),
+        }
+    }
+}
+
+/// The `CrossAttention` struct is used to attend to the hidden states or to a context.
+#[derive(Debug)]
+pub struct CrossAttention {
+    to_q: Linear,
+    to_k: Linear,
+    to_v: Linear,
+    to_out: Linear,
+    heads: usize,
+    scale: f64,
+    slice_size: Option<usize>,
+}
+
+impl CrossAttention {
+    /// Create a new `CrossAttention` instance.
+    pub fn new(vs: VarBuilder, query_dim: usize, context_dim: Option<usize>, heads: usize, dim_head: usize, slice_size: Option<usize>) -> Result<Self> {
+        let inner_dim = dim_head * heads;
+        let context_dim = context_dim.unwrap_or(query_dim);
+        let scale = 1.0 / f64::sqrt(dim_head as f64);
+        let to_q = nn::linear_no_bias(query_dim, inner_dim, vs.pp("to_q"))?;
+        let to_k = nn::linear_no_bias(context_dim, inner_dim, vs.pp("to_k"))?;
+        let to_v = nn::linear_no_bias(context_dim, inner_dim, vs.pp("to_v"))?;
+        let to_out = nn::linear(inner_dim, query_dim, vs.pp("to_out.0"))?;
+        Ok(Self { to_q, to_k, to_v, to_out, heads, scale, slice_size })
+    }
+
+    fn reshape_heads_to_batch_dim(&self, xs: &Tensor) -> Result<Tensor> {
+        let (batch_size, seq_len, dim) = xs.dims3()?;
+        xs.reshape((batch_size, seq_len, self.heads, dim / self.heads))?
+            .transpose(1, 2)?
+            .reshape((batch_size * self.heads, seq_len, dim / self.heads))
+    }
+
+    fn reshape_batch_dim_to_heads(&self, xs: &Tensor) -> Result<Tensor> {
+        let (batch_size, seq_len, dim) = xs.dims3()?;
+        xs.reshape((batch_size / self.heads, self.heads, seq_len, dim))?
+            .transpose(1, 2)?
+            .reshape((batch_size / self.heads, seq_len, dim * self.heads))
+    }
+
+    fn sliced_attention(&self, query: &Tensor, key: &Tensor, value: &Tensor, slice_size: usize) -> Result<Tensor> {
+        let batch_size_attention = query.dim(0)?;
+        let mut hidden_states = Vec::with_capacity(batch_size_attention / slice_size);
+        let in_dtype = query.dtype();
+        let query = query.to_dtype(DType::F32)?;
+        let key = key.to_dtype(DType::F32)?;
+        let value = value.to_dtype(DType::F32)?;
+        for i in 0 .. batch_size_attention / slice_size {
+            let start_idx = i * slice_size;
+            let end_idx = (i + 1) * slice_size;
+            let xs = query
+                .i(start_idx..end_idx)?
+                .matmul(&(key.i(start_idx..end_idx)?.t()? * self.scale)?)?;
+            let xs = candle_nn::ops::softmax(&xs, D::Minus1)?.matmul(&value.i(start_idx..end_idx)?)?;
+            hidden_states.push(xs)
+        }
+        let hidden_states = Tensor::cat(&hidden_states, 0)?.to_dtype(in_dtype)?;
+        self.reshape_batch_dim_to_heads(&hidden_states)
+    }
+
+    #[cfg(feature = "flash-attn")]
+    fn flash_attention(&self, query: &Tensor, key: &Tensor, value: &Tensor) -> Result<Tensor> {
+        // The heads are in the batch dimension, flash attention gets them as heads of a single sequence.
+        let in_dtype = query.dtype();
+        let heads = |xs: &Tensor| xs.to_dtype(DType::F16)?.unsqueeze(0)?.transpose(1, 2);
+        let xs = candle_flash_attn::flash_attn(&heads(query)?, &heads(key)?, &heads(value)?, self.scale as f32, false)?
+            .transpose(1, 2)?
+            .squeeze(0)?
+            .to_dtype(in_dtype)?;
+        self.reshape_batch_dim_to_heads(&xs)
+    }
+
+    fn attention(&self, query: &Tensor, key: &Tensor, value: &Tensor) -> Result<Tensor> {
+        #[cfg(feature = "flash-attn")]
+        if query.device().is_cuda() {
+            return self.flash_attention(query, key, value);
+        }
+        let in_dtype = query.dtype();
+        let query = query.to_dtype(DType::F32)?;
+        let key = key.to_dtype(DType::F32)?;
+        let value = value.to_dtype(DType::F32)?;
+        let xs = query.matmul(&(key.t()? * self.scale)?)?;
+        let xs = candle_nn::ops::softmax_last_dim(&xs)?;
+        let xs = xs.matmul(&value)?.to_dtype(in_dtype)?;
+        self.reshape_batch_dim_to_heads(&xs)
+    }
+
+    fn attend(&self, query: &Tensor, key: &Tensor, value: &Tensor) -> Result<Tensor> {
+        let key = self.reshape_heads_to_batch_dim(key)?;
+        let value = self.reshape_heads_to_batch_dim(value)?;
+        let dim0 = query.dim(0)?;
+        match self.slice_size.filter(|slice_size| dim0 >= *slice_size) {
+            None => self.attention(query, &key, &value),
+            Some(slice_size) => self.sliced_attention(query, &key, &value, slice_size),
+        }
+    }
+
+    /// Attend to the context, or to the hidden states themselves if there's no context.
+    ///
+    /// With an image prompt, the queries also attend to the image tokens through the IP-Adapter projections,
+    /// and the weighted results are added to the attention over the context.
+    pub fn forward(&self, xs: &Tensor, context: Option<&Tensor>, image_prompt: Option<&ImagePromptAttention>) -> Result<Tensor> {
+        let query = self.reshape_heads_to_batch_dim(&self.to_q.forward(xs)?)?;
+        let context = context.unwrap_or(xs).contiguous()?;
+        let key = self.to_k.forward(&context)?;
+        let value = self.to_v.forward(&context)?;
+        let mut xs = self.attend(&query, &key, &value)?;
+        if let Some(image_prompt) = image_prompt {
+            let projection = image_prompt.next_projection()?;
+            for (tokens, weight) in image_prompt.tokens.iter() {
+                let key = projection.to_k_ip.forward(tokens)?;
+                let value = projection.to_v_ip.forward(tokens)?;
+                xs = (xs + (self.attend(&query, &key, &value)? * *weight)?)?;
+            }
+        }
+        self.to_out.forward(&xs)
+    }
+}
+
+#[derive(Debug)]
+struct BasicTransformerBlock {
+    attn1: CrossAttention,
+    ff: FeedForward,
+    attn2: CrossAttention,
+    norm1: LayerNorm,
+    norm2: LayerNorm,
+    norm3: LayerNorm,
+}
+
+impl BasicTransformerBlock {
+    fn new(vs: VarBuilder, dim: usize, n_heads: usize, d_head: usize, context_dim: Option<usize>, sliced_attention_size: Option<usize>) -> Result<Self> {
+        let attn1 = CrossAttention::new(vs.pp("attn1"), dim, None, n_heads, d_head, sliced_attention_size)?;
+        let ff = FeedForward::new(vs.pp("ff"), dim, None, 4)?;
+        let attn2 = CrossAttention::new(vs.pp("attn2"), dim, context_dim, n_heads, d_head, sliced_attention_size)?;
+        let norm1 = nn::layer_norm(dim, 1e-5, vs.pp("norm1"))?;
+        let norm2 = nn::layer_norm(dim, 1e-5, vs.pp("norm2"))?;
+        let norm3 = nn::layer_norm(dim, 1e-5, vs.pp("norm3"))?;
+        Ok(Self { attn1, ff, attn2, norm1, norm2, norm3 })
+    }
+
+    fn forward(&self, xs: &Tensor, context: Option<&Tensor>, image_prompt: Option<&ImagePromptAttention>) -> Result<Tensor> {
+        let xs = (self.attn1.forward(&self.norm1.forward(xs)?, None, None)? + xs)?;
+        let xs = (self.attn2.forward(&self.norm2.forward(&xs)?, context, image_prompt)? + xs)?;
+        self.ff.forward(&self.norm3.forward(&xs)?)? + xs
+    }
+}
+
+/// The `SpatialTransformerConfig` struct is used to configure a spatial transformer.
+#[derive(Debug, Clone, Copy)]
+pub struct SpatialTransformerConfig {
+    /// The number of transformer blocks.
+    pub depth: usize,
+    /// The number of groups of the group normalization.
+    pub num_groups: usize,
+    /// The dimension of the context the blocks attend to.
+    pub context_dim: Option<usize>,
+    /// The size of the attention slices, if the attention is sliced.
+    pub sliced_attention_size: Option<usize>,
+    /// Whether the projections are linear layers instead of convolutions.
+    pub use_linear_projection: bool,
+}
+
+impl Default for SpatialTransformerConfig {
+    fn default() -> Self {
+        Self {
+            depth: 1,
+            num_groups: 32,
+            context_dim: None,
+            sliced_attention_size: None,
+            use_linear_projection: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Proj {
+    Conv2d(Conv2d),
+    Linear(Linear),
+}
+
+/// The `SpatialTransformer` struct is used to hold the transformer applied to the feature maps, aka `Transformer2DModel`.
+#[derive(Debug)]
+pub struct SpatialTransformer {
+    norm: GroupNorm,
+    proj_in: Proj,
+    transformer_blocks: Vec<BasicTransformerBlock>,
+    proj_out: Proj,
+}
+
+impl SpatialTransformer {
+    /// Create a new `SpatialTransformer` instance.
+    pub fn new(vs: VarBuilder, in_channels: usize, n_heads: usize, d_head: usize, config: SpatialTransformerConfig) -> Result<Self> {
+        let inner_dim = n_heads * d_head;
+        let norm = nn::group_norm(config.num_groups, in_channels, 1e-6, vs.pp("norm"))?;
+        let proj_in = if config.use_linear_projection {
+            Proj::Linear(nn::linear(in_channels, inner_dim, vs.pp("proj_in"))?)
+        } else {
+            Proj::Conv2d(nn::conv2d(in_channels, inner_dim, 1, Default::default(), vs.pp("proj_in"))?)
+        };
+        let vs_tb = vs.pp("transformer_blocks");
+        let transformer_blocks = (0 .. config.depth)
+            .map(|index| BasicTransformerBlock::new(vs_tb.pp(index), inner_dim, n_heads, d_head, config.context_dim, config.sliced_attention_size))
+            .collect::<Result<Vec<_>>>()?;
+        let proj_out = if config.use_linear_projection {
+            Proj::Linear(nn::linear(inner_dim, in_channels, vs.pp("proj_out"))?)
+        } else {
+            Proj::Conv2d(nn::conv2d(inner_dim, in_channels, 1, Default::default(), vs.pp("proj_out"))?)
+        };
+        Ok(Self { norm, proj_in, transformer_blocks, proj_out })
+    }
+
+    /// Apply the transformer to the hidden states, attending to the context and the image prompt.
+    pub fn forward(&self, xs: &Tensor, context: Option<&Tensor>, image_prompt: Option<&ImagePromptAttention>) -> Result<Tensor> {
+        let (batch, _channel, height, width) = xs.dims4()?;
+        let residual = xs;
+        let xs = self.norm.forward(xs)?;
+        let (inner_dim, xs) = match &self.proj_in {
+            Proj::Conv2d(p) => {
+                let xs = p.forward(&xs)?;
+                let inner_dim = xs.dim(1)?;
+                let xs = xs.transpose(1, 2)?.t()?.reshape((batch, height * width, inner_dim))?;
+                (inner_dim, xs)
+            }
+            Proj::Linear(p) => {
+                let inner_dim = xs.dim(1)?;
+                let xs = xs.transpose(1, 2)?.t()?.reshape((batch, height * width, inner_dim))?;
+                let xs = p.forward(&xs)?;
+                (xs.dim(D::Minus1)?, xs)
+            }
+        };
+        let mut xs = xs;
+        for block in self.transformer_blocks.iter() {
+            xs = block.forward(&xs, context, image_prompt)?
+        }
+        let xs = match &self.proj_out {
+            Proj::Conv2d(p) => p.forward(&xs.reshape((batch, height, width, inner_dim))?.t()?.transpose(1, 2)?)?,
+            Proj::Linear(p) => {
+                let xs = p.forward(&xs)?;
+                let channels = xs.dim(D::Minus1)?;
+                xs.reshape((batch, height, width, channels))?.t()?.transpose(1, 2)?
+            }
+        };
+        xs + residual
+    }
+}
@@ -0,0 +1,370 @@
+//! CLIP text and vision transformers.
+
+use candle::{DType, Device, IndexOp, Module, Result, Tensor, D};
+use candle_nn::{Conv2d, Conv2dConfig, Embedding, LayerNorm};
+
+use super::nn::{self, Linear, VarBuilder};
+
+/// The `Activation` enum is used to specify the activation of the CLIP MLPs.
+#[derive(Debug, Clone, Copy)]
+pub enum Activation {
+    /// The sigmoid approximation of GELU.
+    QuickGelu,
+    /// The tanh approximation of GELU.
+    Gelu,
+}
+
+impl Module for Activation {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::QuickGelu => xs * candle_nn::ops::sigmoid(&(xs * 1.702f64)?)?,
+            Self::Gelu => xs.gelu(),
+        }
+    }
+}
+
+/// The `ClipTextConfig` struct is used to configure the CLIP text transformer.
+#[derive(Debug, Clone)]
+pub struct ClipTextConfig {
+    /// The size of the vocabulary.
+    pub vocab_size: usize,
+    /// The dimension of the embeddings.
+    pub embed_dim: usize,
+    /// The activation of the MLPs.
+    pub activation: Activation,
+    /// The dimension of the MLPs.
+    pub intermediate_size: usize,
+    /// The maximum number of tokens.
+    pub max_position_embeddings: usize,
+    /// The number of encoder layers.
+    pub num_hidden_layers: usize,
+    /// The number of attention heads.
+    pub num_attention_heads: usize,
+}
+
+impl ClipTextConfig {
+    /// The text encoder of Stable Diffusion 1.5, from `openai/clip-vit-large-patch14`.
+    pub fn v1_5() -> Self {
+        Self {
+            vocab_size: 49408,
+            embed_dim: 768,
+            intermediate_size: 3072,
+            max_position_embeddings: 77,
+            num_hidden_layers: 12,
+            num_attention_heads: 12,
+            activation: Activation::QuickGelu,
+        }
+    }
+
+    /// The text encoder of Stable Diffusion 2.1.
+    pub fn v2_1() -> Self {
+        Self {
+            vocab_size: 49408,
+            embed_dim: 1024,
+            intermediate_size: 4096,
+            max_position_embeddings: 77,
+            num_hidden_layers: 23,
+            num_attention_heads: 16,
+            activation: Activation::Gelu,
+        }
+    }
+
+    /// The first text encoder of Stable Diffusion XL.
+    pub fn sdxl() -> Self {
+        Self::v1_5()
+    }
+
+    /// The second text encoder of Stable Diffusion XL.
+    pub fn sdxl2() -> Self {
+        Self {
+            vocab_size: 49408,
+            embed_dim: 1280,
+            intermediate_size: 5120,
+            max_position_embeddings: 77,
+            num_hidden_layers: 32,
+            num_attention_heads: 20,
+            activation: Activation::Gelu,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ClipTextEmbeddings {
+    token_embedding: Embedding,
+    position_embedding: Embedding,
+    position_ids: Tensor,
+}
+
+impl ClipTextEmbeddings {
+    fn new(vs: VarBuilder, c: &ClipTextConfig) -> Result<Self> {
+        let token_embedding = nn::embedding(c.vocab_size, c.embed_dim, vs.pp("token_embedding"))?;
+        let position_embedding = nn::embedding(c.max_position_embeddings, c.embed_dim, vs.pp("position_embedding"))?;
+        let position_ids = Tensor::arange(0u32, c.max_position_embeddings as u32, vs.device())?.unsqueeze(0)?;
+        Ok(Self { token_embedding, position_embedding, position_ids })
+    }
+}
+
+impl Module for ClipTextEmbeddings {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let seq_len = xs.dim(1)?;
+        let token_embedding = self.token_embedding.forward(xs)?;
+        let position_embedding = self.position_embedding.forward(&self.position_ids.narrow(1, 0, seq_len)?)?;
+        token_embedding.broadcast_add(&position_embedding)
+    }
+}
+
+#[derive(Debug)]
+struct ClipAttention {
+    k_proj: Linear,
+    v_proj: Linear,
+    q_proj: Linear,
+    out_proj: Linear,
+    head_dim: usize,
+    scale: f64,
+    num_attention_heads: usize,
+}
+
+impl ClipAttention {
+    fn new(vs: VarBuilder, embed_dim: usize, num_attention_heads: usize) -> Result<Self> {
+        let k_proj = nn::linear(embed_dim, embed_dim, vs.pp("k_proj"))?;
+        let v_proj = nn::linear(embed_dim, embed_dim, vs.pp("v_proj"))?;
+        let q_proj = nn::linear(embed_dim, embed_dim, vs.pp("q_proj"))?;
+        let out_proj = nn::linear(embed_dim, embed_dim, vs.pp("out_proj"))?;
+        let head_dim = embed_dim / num_attention_heads;
+        let scale = (head_dim as f64).powf(-0.5);
+        Ok(Self { k_proj, v_proj, q_proj, out_proj, head_dim, scale, num_attention_heads })
+    }
+
+    fn shape(&self, xs: &Tensor, seq_len: usize, bsz: usize) -> Result<Tensor> {
+        xs.reshape((bsz, seq_len, self.num_attention_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()
+    }
+
+    fn forward(&self, xs: &Tensor, causal_attention_mask: Option<&Tensor>) -> Result<Tensor> {
+        let in_dtype = xs.dtype();
+        let (bsz, seq_len, embed_dim) = xs.dims3()?;
+        let query_states = (self.q_proj.forward(xs)? * self.scale)?;
+        let proj_shape = (bsz * self.num_attention_heads, seq_len, self.head_dim);
+        let query_states = self.shape(&query_states, seq_len, bsz)?.reshape(proj_shape)?.to_dtype(DType::F32)?;
+        let key_states = self.shape(&self.k_proj.forward(xs)?, seq_len, bsz)?.reshape(proj_shape)?.to_dtype(DType::F32)?;
+        let value_states = self.shape(&self.v_proj.forward(xs)?, seq_len, bsz)?.reshape(proj_shape)?.to_dtype(DType::F32)?;
+        let attn_weights = query_states.matmul(&key_states.transpose(1, 2)?)?;
+
+        let attn_weights = match causal_attention_mask {
+            Some(mask) => {
+                let src_len = key_states.dim(1)?;
+                attn_weights
+                    .reshape((bsz, self.num_attention_heads, seq_len, src_len))?
+                    .broadcast_add(mask)?
+                    .reshape((bsz * self.num_attention_heads, seq_len, src_len))?
+            }
+            None => attn_weights,
+        };
+        let attn_weights = candle_nn::ops::softmax(&attn_weights, D::Minus1)?;
+
+        let attn_output = attn_weights.matmul(&value_states)?.to_dtype(in_dtype)?;
+        let attn_output = attn_output
+            .reshape((bsz, self.num_attention_heads, seq_len, self.head_dim))?
+            .transpose(1, 2)?
+            .reshape((bsz, seq_len, embed_dim))?;
+        self.out_proj.forward(&attn_output)
+    }
+}
+
+#[derive(Debug)]
+struct ClipMlp {
+    fc1: Linear,
+    fc2: Linear,
+    activation: Activation,
+}
+
+impl ClipMlp {
+    fn new(vs: VarBuilder, embed_dim: usize, intermediate_size: usize, activation: Activation) -> Result<Self> {
+        let fc1 = nn::linear(embed_dim, intermediate_size, vs.pp("fc1"))?;
+        let fc2 = nn::linear(intermediate_size, embed_dim, vs.pp("fc2"))?;
+        Ok(Self { fc1, fc2, activation })
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = self.fc1.forward(xs)?;
+        self.fc2.forward(&self.activation.forward(&xs)?)
+    }
+}
+
+#[derive(Debug)]
+struct ClipEncoderLayer {
+    self_attn: ClipAttention,
+    layer_norm1: LayerNorm,
+    mlp: ClipMlp,
+    layer_norm2: LayerNorm,
+}
+
+impl ClipEncoderLayer {
+    fn new(vs: VarBuilder, embed_dim: usize, intermediate_size: usize, num_attention_heads: usize, activation: Activation) -> Result<Self> {
+        let self_attn = ClipAttention::new(vs.pp("self_attn"), embed_dim, num_attention_heads)?;
+        let layer_norm1 = nn::layer_norm(embed_dim, 1e-5, vs.pp("layer_norm1"))?;
+        let mlp = ClipMlp::new(vs.pp("mlp"), embed_dim, intermediate_size, activation)?;
+        let layer_norm2 = nn::layer_norm(embed_dim, 1e-5, vs.pp("layer_norm2"))?;
+        Ok(Self { self_attn, layer_norm1, mlp, layer_norm2 })
+    }
+
+    fn forward(&self, xs: &Tensor, causal_attention_mask: Option<&Tensor>) -> Result<Tensor> {
+        let residual = xs;
+        let xs = self.layer_norm1.forward(xs)?;
+        let xs = self.self_attn.forward(&xs, causal_attention_mask)?;
+        let xs = (xs + residual)?;
+
+        let residual = &xs;
+        let xs = self.layer_norm2.forward(&xs)?;
+        let xs = self.mlp.forward(&xs)?;
+        xs + residual
+    }
+}
+
+/// The `ClipTextTransformer` struct is used to hold the CLIP text transformer.
+#[derive(Debug)]
+pub struct ClipTextTransformer {
+    embeddings: ClipTextEmbeddings,
+    layers: Vec<ClipEncoderLayer>,
+    final_layer_norm: LayerNorm,
+}
+
+impl ClipTextTransformer {
+    /// Create a new `ClipTextTransformer` instance.
+    pub fn new(vs: VarBuilder, c: &ClipTextConfig) -> Result<Self> {
+        let vs = vs.pp("text_model");
+        let embeddings = ClipTextEmbeddings::new(vs.pp("embeddings"), c)?;
+        let vs_layers = vs.pp("encoder").pp("layers");
+        let layers = (0 .. c.num_hidden_layers)
+            .map(|index| ClipEncoderLayer::new(vs_layers.pp(index), c.embed_dim, c.intermediate_size, c.num_attention_heads, c.activation))
+            .collect::<Result<Vec<_>>>()?;
+        let final_layer_norm = nn::layer_norm(c.embed_dim, 1e-5, vs.pp("final_layer_norm"))?;
+        Ok(Self { embeddings, layers, final_layer_norm })
+    }
+
+    fn build_causal_attention_mask(bsz: usize, seq_len: usize, device: &Device) -> Result<Tensor> {
+        let mask: Vec<_> = (0 .. seq_len)
+            .flat_map(|i| (0 .. seq_len).map(move |j| if j > i { f32::MIN } else { 0. }))
+            .collect();
+        let mask = Tensor::from_slice(&mask, (seq_len, seq_len), device)?;
+        mask.broadcast_as((bsz, seq_len, seq_len))
+    }
+}
+
+impl Module for ClipTextTransformer {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let (bsz, seq_len) = xs.dims2()?;
+        let xs = self.embeddings.forward(xs)?;
+        let causal_attention_mask = Self::build_causal_attention_mask(bsz, seq_len, xs.device())?;
+        let mut xs = xs;
+        for layer in self.layers.iter() {
+            xs = layer.forward(&xs, Some(&causal_attention_mask))?;
+        }
+        self.final_layer_norm.forward(&xs)
+    }
+}
+
+/// The `ClipVisionConfig` struct is used to configure the CLIP vision transformer.
+#[derive(Debug, Clone)]
+pub struct ClipVisionConfig {
+    /// The dimension of the embeddings.
+    pub embed_dim: usize,
+    /// The activation of the MLPs.
+    pub activation: Activation,
+    /// The dimension of the MLPs.
+    pub intermediate_size: usize,
+    /// The number of encoder layers.
+    pub num_hidden_layers: usize,
+    /// The number of attention heads.
+    pub num_attention_heads: usize,
+    /// The dimension of the projected image embeddings.
+    pub projection_dim: usize,
+    /// The resolution of the input images.
+    pub image_size: usize,
+    /// The size of the patches.
+    pub patch_size: usize,
+}
+
+impl ClipVisionConfig {
+    /// The ViT-H/14 image encoder, used by the IP-Adapters of Stable Diffusion 1.5.
+    pub fn vit_h_14() -> Self {
+        Self {
+            embed_dim: 1280,
+            activation: Activation::Gelu,
+            intermediate_size: 5120,
+            num_hidden_layers: 32,
+            num_attention_heads: 16,
+            projection_dim: 1024,
+            image_size: 224,
+            patch_size: 14,
+        }
+    }
+
+    /// The ViT-bigG/14 image encoder, used by the IP-Adapters of Stable Diffusion XL.
+    pub fn vit_big_g_14() -> Self {
+        Self {
+            embed_dim: 1664,
+            activation: Activation::Gelu,
+            intermediate_size: 8192,
+            num_hidden_layers: 48,
+            num_attention_heads: 16,
+            projection_dim: 1280,
+            image_size: 224,
+            patch_size: 14,
+        }
+    }
+}
+
+/// The `ClipVisionTransformer` struct is used to hold the CLIP vision transformer with its projection.
+#[derive(Debug)]
+pub struct ClipVisionTransformer {
+    patch_embedding: Conv2d,
+    class_embedding: Tensor,
+    position_embedding: Embedding,
+    position_ids: Tensor,
+    pre_layrnorm: LayerNorm,
+    layers: Vec<ClipEncoderLayer>,
+    post_layernorm: LayerNorm,
+    visual_projection: Linear,
+}
+
+impl ClipVisionTransformer {
+    /// Create a new `ClipVisionTransformer` instance.
+    pub fn new(vs: VarBuilder, c: &ClipVisionConfig) -> Result<Self> {
+        let visual_projection = nn::linear_no_bias(c.embed_dim, c.projection_dim, vs.pp("visual_projection"))?;
+        let vs = vs.pp("vision_model");
+        let vs_embeddings = vs.pp("embeddings");
+        let patch_config = Conv2dConfig { stride: c.patch_size, ..Default::default() };
+        let patch_embedding = nn::conv2d_no_bias(3, c.embed_dim, c.patch_size, patch_config, vs_embeddings.pp("patch_embedding"))?;
+        let class_embedding = vs_embeddings.get(c.embed_dim, "class_embedding")?;
+        let num_positions = (c.image_size / c.patch_size).pow(2) + 1;
+        let position_embedding = nn::embedding(num_positions, c.embed_dim, vs_embeddings.pp("position_embedding"))?;
+        let position_ids = Tensor::arange(0u32, num_positions as u32, vs.device())?.unsqueeze(0)?;
+        let pre_layrnorm = nn::layer_norm(c.embed_dim, 1e-5, vs.pp("pre_layrnorm"))?;
+        let vs_layers = vs.pp("encoder").pp("layers");
+        let layers = (0 .. c.num_hidden_layers)
+            .map(|index| ClipEncoderLayer::new(vs_layers.pp(index), c.embed_dim, c.intermediate_size, c.num_attention_heads, c.activation))
+            .collect::<Result<Vec<_>>>()?;
+        let post_layernorm = nn::layer_norm(c.embed_dim, 1e-5, vs.pp("post_layernorm"))?;
+        Ok(Self { patch_embedding, class_embedding, position_embedding, position_ids, pre_layrnorm, layers, post_layernorm, visual_projection })
+    }
+}
+
+impl Module for ClipVisionTransformer {
+    /// Get the projected embeddings of normalized `(batch, 3, image_size, image_size)` images.
+    fn forward(&self, pixel_values: &Tensor) -> Result<Tensor> {
+        let batch_size = pixel_values.dim(0)?;
+        let patch_embeds = self.patch_embedding.forward(pixel_values)?.flatten_from(2)?.transpose(1, 2)?;
+        let embed_dim = patch_embeds.dim(2)?;
+        let class_embeds = self.class_embedding.expand((batch_size, 1, embed_dim))?.to_dtype(patch_embeds.dtype())?;
+        let embeddings = Tensor::cat(&[class_embeds, patch_embeds], 1)?;
+        let position_embedding = self.position_embedding.forward(&self.position_ids)?;
+        let mut xs = self.pre_layrnorm.forward(&embeddings.broadcast_add(&position_embedding)?)?;
+        for layer in self.layers.iter() {
+            xs = layer.forward(&xs, None)?;
+        }
+        let pooled = self.post_layernorm.forward(&xs.i((.., 0, ..))?)?;
+        self.visual_projection.forward(&pooled)
+    }
+}
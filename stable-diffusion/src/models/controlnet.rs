@@ -0,0 +1,121 @@
+//! ControlNet, a copy of the UNet encoder conditioned on a control image.
+
+use candle::{Module, Result, Tensor};
+use candle_nn::{Conv2d, Conv2dConfig};
+
+use super::embeddings::{TimestepEmbedding, Timesteps};
+use super::nn::{self, VarBuilder};
+use super::unet_2d::{down_blocks, mid_block, UNet2DConditionModelConfig, UNetDownBlock};
+use super::unet_2d_blocks::UNetMidBlock2DCrossAttn;
+
+/// The channels of the layers embedding the control image.
+const CONDITIONING_EMBEDDING_CHANNELS: [usize; 4] = [16, 32, 96, 256];
+
+#[derive(Debug)]
+struct ControlNetConditioningEmbedding {
+    conv_in: Conv2d,
+    blocks: Vec<Conv2d>,
+    conv_out: Conv2d,
+}
+
+impl ControlNetConditioningEmbedding {
+    fn new(vs: VarBuilder, conditioning_channels: usize, out_channels: usize) -> Result<Self> {
+        let channels = CONDITIONING_EMBEDDING_CHANNELS;
+        let padded = Conv2dConfig { padding: 1, ..Default::default() };
+        let strided = Conv2dConfig { padding: 1, stride: 2, ..Default::default() };
+        let conv_in = nn::conv2d(conditioning_channels, channels[0], 3, padded, vs.pp("conv_in"))?;
+        let vs_blocks = vs.pp("blocks");
+        let mut blocks = Vec::with_capacity(2 * (channels.len() - 1));
+        for (i, window) in channels.windows(2).enumerate() {
+            let (channel_in, channel_out) = (window[0], window[1]);
+            blocks.push(nn::conv2d(channel_in, channel_in, 3, padded, vs_blocks.pp(2 * i))?);
+            blocks.push(nn::conv2d(channel_in, channel_out, 3, strided, vs_blocks.pp(2 * i + 1))?);
+        }
+        let conv_out = nn::conv2d(channels[channels.len() - 1], out_channels, 3, padded, vs.pp("conv_out"))?;
+        Ok(Self { conv_in, blocks, conv_out })
+    }
+}
+
+impl Module for ControlNetConditioningEmbedding {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let mut xs = candle_nn::ops::silu(&self.conv_in.forward(xs)?)?;
+        for block in self.blocks.iter() {
+            xs = candle_nn::ops::silu(&block.forward(&xs)?)?;
+        }
+        self.conv_out.forward(&xs)
+    }
+}
+
+/// The `ControlNetModel` struct is used to hold a ControlNet, which predicts residuals for the down and mid blocks of the UNet.
+#[derive(Debug)]
+pub struct ControlNetModel {
+    conv_in: Conv2d,
+    time_proj: Timesteps,
+    time_embedding: TimestepEmbedding,
+    controlnet_cond_embedding: ControlNetConditioningEmbedding,
+    down_blocks: Vec<UNetDownBlock>,
+    controlnet_down_blocks: Vec<Conv2d>,
+    mid_block: UNetMidBlock2DCrossAttn,
+    controlnet_mid_block: Conv2d,
+}
+
+impl ControlNetModel {
+    /// Create a new `ControlNetModel` instance for a UNet with the same configuration.
+    pub fn new(vs: VarBuilder, in_channels: usize, conditioning_channels: usize, config: &UNet2DConditionModelConfig) -> Result<Self> {
+        let b_channels = config.blocks[0].out_channels;
+        let bl_channels = config.blocks[config.blocks.len() - 1].out_channels;
+        let time_embed_dim = b_channels * 4;
+        let conv_cfg = Conv2dConfig { padding: 1, ..Default::default() };
+        let conv_in = nn::conv2d(in_channels, b_channels, 3, conv_cfg, vs.pp("conv_in"))?;
+        let time_proj = Timesteps::new(b_channels, config.flip_sin_to_cos, config.freq_shift);
+        let time_embedding = TimestepEmbedding::new(vs.pp("time_embedding"), b_channels, time_embed_dim)?;
+        let controlnet_cond_embedding = ControlNetConditioningEmbedding::new(vs.pp("controlnet_cond_embedding"), conditioning_channels, b_channels)?;
+        let down_blocks = down_blocks(vs.pp("down_blocks"), config, time_embed_dim)?;
+        let mid_block = mid_block(vs.pp("mid_block"), config, time_embed_dim)?;
+
+        // There's a zero convolution for the output of `conv_in`, for every ResNet layer and for every downsampler.
+        let mut residual_channels = vec![b_channels];
+        for (i, block) in config.blocks.iter().enumerate() {
+            residual_channels.extend(std::iter::repeat_n(block.out_channels, config.layers_per_block));
+            if i < config.blocks.len() - 1 {
+                residual_channels.push(block.out_channels);
+            }
+        }
+        let vs_cdb = vs.pp("controlnet_down_blocks");
+        let controlnet_down_blocks = residual_channels
+            .into_iter()
+            .enumerate()
+            .map(|(i, channels)| nn::conv2d(channels, channels, 1, Default::default(), vs_cdb.pp(i)))
+            .collect::<Result<Vec<_>>>()?;
+        let controlnet_mid_block = nn::conv2d(bl_channels, bl_channels, 1, Default::default(), vs.pp("controlnet_mid_block"))?;
+        Ok(Self { conv_in, time_proj, time_embedding, controlnet_cond_embedding, down_blocks, controlnet_down_blocks, mid_block, controlnet_mid_block })
+    }
+
+    /// Predict the residuals of the down and mid blocks for the latents, the text embeddings and the control image,
+    /// scaled by the conditioning scale.
+    pub fn forward(&self, xs: &Tensor, timestep: f64, encoder_hidden_states: &Tensor, controlnet_cond: &Tensor, conditioning_scale: f64) -> Result<(Vec<Tensor>, Tensor)> {
+        let bsize = xs.dim(0)?;
+        let emb = (Tensor::ones(bsize, xs.dtype(), xs.device())? * timestep)?;
+        let emb = self.time_proj.forward(&emb)?;
+        let emb = self.time_embedding.forward(&emb)?;
+
+        let xs = self.conv_in.forward(xs)?;
+        let cond = self.controlnet_cond_embedding.forward(controlnet_cond)?;
+        let mut xs = xs.broadcast_add(&cond)?;
+        let mut down_block_res_xs = vec![xs.clone()];
+        for down_block in self.down_blocks.iter() {
+            let (down_xs, res_xs) = down_block.forward(&xs, &emb, encoder_hidden_states, None)?;
+            down_block_res_xs.extend(res_xs);
+            xs = down_xs;
+        }
+        let xs = self.mid_block.forward(&xs, Some(&emb), Some(encoder_hidden_states), None)?;
+
+        let down_block_res_xs = down_block_res_xs
+            .iter()
+            .zip(self.controlnet_down_blocks.iter())
+            .map(|(xs, block)| block.forward(xs)? * conditioning_scale)
+            .collect::<Result<Vec<_>>>()?;
+        let mid_block_res_xs = (self.controlnet_mid_block.forward(&xs)? * conditioning_scale)?;
+        Ok((down_block_res_xs, mid_block_res_xs))
+    }
+}
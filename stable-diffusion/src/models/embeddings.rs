@@ -0,0 +1,95 @@
+//! Timestep embeddings of the UNet.
+
+use candle::{Module, Result, Tensor, D};
+
+use super::nn::{self, Linear, VarBuilder};
+
+/// The `TimestepEmbedding` struct is used to project the sinusoidal timestep embeddings.
+#[derive(Debug)]
+pub struct TimestepEmbedding {
+    linear_1: Linear,
+    linear_2: Linear,
+    cond_proj: Option<Linear>,
+}
+
+impl TimestepEmbedding {
+    /// Create a new `TimestepEmbedding` instance.
+    pub fn new(vs: VarBuilder, channel: usize, time_embed_dim: usize) -> Result<Self> {
+        Self::with_condition(vs, channel, time_embed_dim, None)
+    }
+
+    /// Create a new `TimestepEmbedding` instance with a projection of a condition added to the timestep embeddings,
+    /// e.g. the guidance scale embeddings of the LCM models.
+    pub fn with_condition(vs: VarBuilder, channel: usize, time_embed_dim: usize, cond_proj_dim: Option<usize>) -> Result<Self> {
+        let linear_1 = nn::linear(channel, time_embed_dim, vs.pp("linear_1"))?;
+        let linear_2 = nn::linear(time_embed_dim, time_embed_dim, vs.pp("linear_2"))?;
+        let cond_proj = match cond_proj_dim {
+            Some(cond_proj_dim) => Some(nn::linear_no_bias(cond_proj_dim, channel, vs.pp("cond_proj"))?),
+            None => None,
+        };
+        Ok(Self { linear_1, linear_2, cond_proj })
+    }
+
+    /// Project the timestep embeddings, adding the projection of the condition if there is one.
+    pub fn forward_with_condition(&self, xs: &Tensor, condition: Option<&Tensor>) -> Result<Tensor> {
+        let xs = match (&self.cond_proj, condition) {
+            (Some(cond_proj), Some(condition)) => (xs + cond_proj.forward(condition)?)?,
+            _ => xs.clone(),
+        };
+        let xs = candle_nn::ops::silu(&self.linear_1.forward(&xs)?)?;
+        self.linear_2.forward(&xs)
+    }
+}
+
+impl Module for TimestepEmbedding {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.forward_with_condition(xs, None)
+    }
+}
+
+/// Get the `(batch, dim)` sinusoidal embeddings of a guidance scale, used as the timestep condition of the LCM models.
+pub fn guidance_scale_embedding(guidance_scale: f64, batch: usize, dim: usize, device: &candle::Device, dtype: candle::DType) -> Result<Tensor> {
+    let w = (guidance_scale - 1.) * 1000.;
+    let half_dim = dim / 2;
+    let exponent = (Tensor::arange(0, half_dim as u32, device)?.to_dtype(candle::DType::F32)? * (-f64::ln(10000.) / (half_dim as f64 - 1.)))?;
+    let emb = (exponent.exp()? * w)?;
+    let emb = Tensor::cat(&[emb.sin()?, emb.cos()?], 0)?;
+    let emb = if dim % 2 == 1 { emb.pad_with_zeros(0, 0, 1)? } else { emb };
+    emb.unsqueeze(0)?.repeat((batch, 1))?.to_dtype(dtype)
+}
+
+/// The `Timesteps` struct is used to compute the sinusoidal embeddings of the timesteps.
+#[derive(Debug)]
+pub struct Timesteps {
+    num_channels: usize,
+    flip_sin_to_cos: bool,
+    downscale_freq_shift: f64,
+}
+
+impl Timesteps {
+    /// Create a new `Timesteps` instance.
+    pub fn new(num_channels: usize, flip_sin_to_cos: bool, downscale_freq_shift: f64) -> Self {
+        Self { num_channels, flip_sin_to_cos, downscale_freq_shift }
+    }
+}
+
+impl Module for Timesteps {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let half_dim = (self.num_channels / 2) as u32;
+        let exponent = (Tensor::arange(0, half_dim, xs.device())?.to_dtype(candle::DType::F32)? * -f64::ln(10000.))?;
+        let exponent = (exponent / (half_dim as f64 - self.downscale_freq_shift))?;
+        let emb = exponent.exp()?.to_dtype(xs.dtype())?;
+        let emb = xs.unsqueeze(D::Minus1)?.broadcast_mul(&emb.unsqueeze(0)?)?;
+        let (cos, sin) = (emb.cos()?, emb.sin()?);
+        let emb = if self.flip_sin_to_cos {
+            Tensor::cat(&[&cos, &sin], D::Minus1)?
+        } else {
+            Tensor::cat(&[&sin, &cos], D::Minus1)?
+        };
+        if self.num_channels % 2 == 1 {
+            emb.pad_with_zeros(D::Minus2, 0, 1)
+        } else {
+            Ok(emb)
+        }
+    }
+}
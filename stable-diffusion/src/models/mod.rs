@@ -0,0 +1,11 @@
+//! Models that can be loaded either from floating point or from quantized weights.
+
+pub mod nn;
+pub mod embeddings;
+pub mod resnet;
+pub mod attention;
+pub mod unet_2d_blocks;
+pub mod unet_2d;
+pub mod clip;
+pub mod controlnet;
+pub mod rrdbnet;
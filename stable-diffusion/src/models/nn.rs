@@ -0,0 +1,177 @@
+//! Layers that can be loaded either from floating point or from quantized weights.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use candle::quantized::QMatMul;
+use candle::{DType, Device, Module, Result, Shape, Tensor};
+use candle_transformers::quantized_var_builder;
+
+/// The `VarBuilder` enum is used to load the weights of a model from safetensors or GGUF files.
+#[derive(Clone)]
+pub enum VarBuilder<'a> {
+    /// Floating point weights, usually loaded from safetensors files.
+    Float(candle_nn::VarBuilder<'a>),
+    /// Quantized weights loaded from a GGUF file. The tensors that aren't used by linear layers are dequantized.
+    Quantized(Arc<quantized_var_builder::VarBuilder>, DType),
+}
+
+impl VarBuilder<'_> {
+    /// Create a new `VarBuilder` from a weights file, using quantized weights if it's a GGUF file.
+    pub fn from_file(weights: impl AsRef<Path>, device: &Device, dtype: DType) -> Result<Self> {
+        let weights = weights.as_ref();
+        if is_gguf(weights) {
+            let builder = quantized_var_builder::VarBuilder::from_gguf(weights, device)?;
+            Ok(Self::Quantized(Arc::new(builder), dtype))
+        } else {
+            let builder = unsafe { candle_nn::VarBuilder::from_mmaped_safetensors(&[weights], dtype, device)? };
+            Ok(Self::Float(builder))
+        }
+    }
+
+    /// Push a prefix to the path of the tensors.
+    pub fn pp(&self, prefix: impl ToString) -> Self {
+        match self {
+            Self::Float(builder) => Self::Float(builder.pp(prefix)),
+            Self::Quantized(builder, dtype) => Self::Quantized(Arc::new(builder.pp(prefix)), *dtype),
+        }
+    }
+
+    /// Get the device of the weights.
+    pub fn device(&self) -> &Device {
+        match self {
+            Self::Float(builder) => builder.device(),
+            Self::Quantized(builder, _) => builder.device(),
+        }
+    }
+
+    /// Get a tensor, dequantizing it if needed.
+    pub fn get(&self, shape: impl Into<Shape>, name: &str) -> Result<Tensor> {
+        match self {
+            Self::Float(builder) => builder.get(shape, name),
+            Self::Quantized(builder, dtype) => builder.get(shape, name)?.dequantize(builder.device())?.to_dtype(*dtype),
+        }
+    }
+}
+
+/// The `HostWeights` enum is used to keep the weights of a model in the CPU memory, to build it on any device.
+#[derive(Clone)]
+pub enum HostWeights {
+    /// Floating point tensors on the CPU.
+    Float(Arc<HashMap<String, Tensor>>),
+    /// The contents of a GGUF file.
+    Quantized(Arc<Vec<u8>>),
+}
+
+impl HostWeights {
+    /// Load a safetensors or GGUF weights file into the CPU memory.
+    pub fn load(weights: impl AsRef<Path>) -> Result<Self> {
+        let weights = weights.as_ref();
+        if is_gguf(weights) {
+            Ok(Self::Quantized(Arc::new(std::fs::read(weights)?)))
+        } else {
+            Ok(Self::Float(Arc::new(candle::safetensors::load(weights, &Device::Cpu)?)))
+        }
+    }
+
+    /// Create a `VarBuilder` moving the weights to a device.
+    pub fn var_builder(&self, device: &Device, dtype: DType) -> Result<VarBuilder<'static>> {
+        match self {
+            Self::Float(tensors) => Ok(VarBuilder::Float(candle_nn::VarBuilder::from_tensors(tensors.as_ref().clone(), dtype, device))),
+            Self::Quantized(buffer) => Ok(VarBuilder::Quantized(Arc::new(quantized_var_builder::VarBuilder::from_gguf_buffer(buffer, device)?), dtype)),
+        }
+    }
+}
+
+/// Check if a weights file is a GGUF file.
+pub fn is_gguf(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "gguf")
+}
+
+/// The `Linear` enum is used to hold a linear layer with floating point or quantized weights.
+#[derive(Debug, Clone)]
+pub enum Linear {
+    /// A linear layer with floating point weights.
+    Float(candle_nn::Linear),
+    /// A linear layer with quantized weights.
+    Quantized(QMatMul, Option<Tensor>),
+}
+
+impl Module for Linear {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Float(linear) => linear.forward(xs),
+            Self::Quantized(weight, bias) => {
+                // The quantized matmul only supports F32 inputs.
+                let dtype = xs.dtype();
+                let xs = weight.forward(&xs.to_dtype(DType::F32)?)?.to_dtype(dtype)?;
+                match bias {
+                    Some(bias) => xs.broadcast_add(bias),
+                    None => Ok(xs),
+                }
+            }
+        }
+    }
+}
+
+fn linear_b(in_dim: usize, out_dim: usize, bias: bool, vs: VarBuilder) -> Result<Linear> {
+    match &vs {
+        VarBuilder::Float(builder) => {
+            let linear = if bias {
+                candle_nn::linear(in_dim, out_dim, builder.clone())?
+            } else {
+                candle_nn::linear_no_bias(in_dim, out_dim, builder.clone())?
+            };
+            Ok(Linear::Float(linear))
+        }
+        VarBuilder::Quantized(builder, _) => {
+            let weight = QMatMul::from_arc(builder.get((out_dim, in_dim), "weight")?)?;
+            let bias = if bias { Some(vs.get(out_dim, "bias")?) } else { None };
+            Ok(Linear::Quantized(weight, bias))
+        }
+    }
+}
+
+/// Create a linear layer with a bias.
+pub fn linear(in_dim: usize, out_dim: usize, vs: VarBuilder) -> Result<Linear> {
+    linear_b(in_dim, out_dim, true, vs)
+}
+
+/// Create a linear layer without a bias.
+pub fn linear_no_bias(in_dim: usize, out_dim: usize, vs: VarBuilder) -> Result<Linear> {
+    linear_b(in_dim, out_dim, false, vs)
+}
+
+/// Create a 2D convolution. Convolutions are always computed with dequantized weights.
+pub fn conv2d(in_channels: usize, out_channels: usize, kernel_size: usize, config: candle_nn::Conv2dConfig, vs: VarBuilder) -> Result<candle_nn::Conv2d> {
+    let weight = vs.get((out_channels, in_channels / config.groups, kernel_size, kernel_size), "weight")?;
+    let bias = vs.get(out_channels, "bias")?;
+    Ok(candle_nn::Conv2d::new(weight, Some(bias), config))
+}
+
+/// Create a 2D convolution without a bias.
+pub fn conv2d_no_bias(in_channels: usize, out_channels: usize, kernel_size: usize, config: candle_nn::Conv2dConfig, vs: VarBuilder) -> Result<candle_nn::Conv2d> {
+    let weight = vs.get((out_channels, in_channels / config.groups, kernel_size, kernel_size), "weight")?;
+    Ok(candle_nn::Conv2d::new(weight, None, config))
+}
+
+/// Create a group normalization layer.
+pub fn group_norm(num_groups: usize, num_channels: usize, eps: f64, vs: VarBuilder) -> Result<candle_nn::GroupNorm> {
+    let weight = vs.get(num_channels, "weight")?;
+    let bias = vs.get(num_channels, "bias")?;
+    candle_nn::GroupNorm::new(weight, bias, num_channels, num_groups, eps)
+}
+
+/// Create a layer normalization layer.
+pub fn layer_norm(size: usize, eps: f64, vs: VarBuilder) -> Result<candle_nn::LayerNorm> {
+    let weight = vs.get(size, "weight")?;
+    let bias = vs.get(size, "bias")?;
+    Ok(candle_nn::LayerNorm::new(weight, bias, eps))
+}
+
+/// Create an embedding layer.
+pub fn embedding(in_size: usize, out_size: usize, vs: VarBuilder) -> Result<candle_nn::Embedding> {
+    let embeddings = vs.get((in_size, out_size), "weight")?;
+    Ok(candle_nn::Embedding::new(embeddings, out_size))
+}
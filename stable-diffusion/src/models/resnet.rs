@@ -0,0 +1,95 @@
+//! ResNet blocks of the UNet.
+
+use candle::{Module, Result, Tensor, D};
+use candle_nn::{Conv2d, Conv2dConfig, GroupNorm};
+
+use super::nn::{self, Linear, VarBuilder};
+
+/// The `ResnetBlock2DConfig` struct is used to configure a ResNet block.
+#[derive(Debug, Clone, Copy)]
+pub struct ResnetBlock2DConfig {
+    /// The number of output channels, defaults to the number of input channels.
+    pub out_channels: Option<usize>,
+    /// The number of channels of the timestep embeddings.
+    pub temb_channels: Option<usize>,
+    /// The number of groups of the group normalizations.
+    pub groups: usize,
+    /// The number of groups of the output group normalization, defaults to `groups`.
+    pub groups_out: Option<usize>,
+    /// The epsilon of the group normalizations.
+    pub eps: f64,
+    /// Whether to use a convolution in the skip connection, defaults to whether the number of channels changes.
+    pub use_in_shortcut: Option<bool>,
+    /// The output is divided by this factor.
+    pub output_scale_factor: f64,
+}
+
+impl Default for ResnetBlock2DConfig {
+    fn default() -> Self {
+        Self {
+            out_channels: None,
+            temb_channels: Some(512),
+            groups: 32,
+            groups_out: None,
+            eps: 1e-6,
+            use_in_shortcut: None,
+            output_scale_factor: 1.,
+        }
+    }
+}
+
+/// The `ResnetBlock2D` struct is used to hold a ResNet block.
+#[derive(Debug)]
+pub struct ResnetBlock2D {
+    norm1: GroupNorm,
+    conv1: Conv2d,
+    norm2: GroupNorm,
+    conv2: Conv2d,
+    time_emb_proj: Option<Linear>,
+    conv_shortcut: Option<Conv2d>,
+    config: ResnetBlock2DConfig,
+}
+
+impl ResnetBlock2D {
+    /// Create a new `ResnetBlock2D` instance.
+    pub fn new(vs: VarBuilder, in_channels: usize, config: ResnetBlock2DConfig) -> Result<Self> {
+        let out_channels = config.out_channels.unwrap_or(in_channels);
+        let conv_cfg = Conv2dConfig { padding: 1, ..Default::default() };
+        let norm1 = nn::group_norm(config.groups, in_channels, config.eps, vs.pp("norm1"))?;
+        let conv1 = nn::conv2d(in_channels, out_channels, 3, conv_cfg, vs.pp("conv1"))?;
+        let groups_out = config.groups_out.unwrap_or(config.groups);
+        let norm2 = nn::group_norm(groups_out, out_channels, config.eps, vs.pp("norm2"))?;
+        let conv2 = nn::conv2d(out_channels, out_channels, 3, conv_cfg, vs.pp("conv2"))?;
+        let use_in_shortcut = config.use_in_shortcut.unwrap_or(in_channels != out_channels);
+        let conv_shortcut = if use_in_shortcut {
+            Some(nn::conv2d(in_channels, out_channels, 1, Default::default(), vs.pp("conv_shortcut"))?)
+        } else {
+            None
+        };
+        let time_emb_proj = match config.temb_channels {
+            Some(temb_channels) => Some(nn::linear(temb_channels, out_channels, vs.pp("time_emb_proj"))?),
+            None => None,
+        };
+        Ok(Self { norm1, conv1, norm2, conv2, time_emb_proj, conv_shortcut, config })
+    }
+
+    /// Apply the block to the hidden states with the timestep embeddings.
+    pub fn forward(&self, xs: &Tensor, temb: Option<&Tensor>) -> Result<Tensor> {
+        let shortcut_xs = match &self.conv_shortcut {
+            Some(conv_shortcut) => conv_shortcut.forward(xs)?,
+            None => xs.clone(),
+        };
+        let xs = self.norm1.forward(xs)?;
+        let xs = self.conv1.forward(&candle_nn::ops::silu(&xs)?)?;
+        let xs = match (temb, &self.time_emb_proj) {
+            (Some(temb), Some(time_emb_proj)) => time_emb_proj
+                .forward(&candle_nn::ops::silu(temb)?)?
+                .unsqueeze(D::Minus1)?
+                .unsqueeze(D::Minus1)?
+                .broadcast_add(&xs)?,
+            _ => xs,
+        };
+        let xs = self.conv2.forward(&candle_nn::ops::silu(&self.norm2.forward(&xs)?)?)?;
+        (shortcut_xs + xs)? / self.config.output_scale_factor
+    }
+}
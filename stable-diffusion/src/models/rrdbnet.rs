@@ -0,0 +1,137 @@
+//! Residual-in-residual dense block network (RRDBNet) of the ESRGAN upscalers.
+
+use candle::{Module, Result, Tensor};
+use candle_nn::{Conv2d, Conv2dConfig};
+
+use super::nn::{self, VarBuilder};
+
+/// The slope of the leaky ReLU activations.
+const NEGATIVE_SLOPE: f64 = 0.2;
+/// The factor the residuals of the dense blocks are scaled by.
+const RESIDUAL_SCALE: f64 = 0.2;
+
+fn lrelu(xs: &Tensor) -> Result<Tensor> {
+    candle_nn::ops::leaky_relu(xs, NEGATIVE_SLOPE)
+}
+
+fn conv3x3(in_channels: usize, out_channels: usize, vs: VarBuilder) -> Result<Conv2d> {
+    let config = Conv2dConfig { padding: 1, ..Default::default() };
+    nn::conv2d(in_channels, out_channels, 3, config, vs)
+}
+
+/// The `ResidualDenseBlock` struct is used to hold five densely connected convolutions.
+#[derive(Debug)]
+struct ResidualDenseBlock {
+    convs: Vec<Conv2d>,
+}
+
+impl ResidualDenseBlock {
+    fn new(vs: VarBuilder, num_feat: usize, num_grow_ch: usize) -> Result<Self> {
+        let convs = (0 .. 5)
+            .map(|index| {
+                let out_channels = if index == 4 { num_feat } else { num_grow_ch };
+                conv3x3(num_feat + index * num_grow_ch, out_channels, vs.pp(format!("conv{}", index + 1)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { convs })
+    }
+}
+
+impl Module for ResidualDenseBlock {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let mut features = vec![xs.clone()];
+        for conv in &self.convs[.. 4] {
+            let ys = lrelu(&conv.forward(&Tensor::cat(&features, 1)?)?)?;
+            features.push(ys);
+        }
+        let ys = self.convs[4].forward(&Tensor::cat(&features, 1)?)?;
+        (ys * RESIDUAL_SCALE)? + xs
+    }
+}
+
+/// The `ResidualInResidualDenseBlock` struct is used to hold three residual dense blocks with a residual connection.
+#[derive(Debug)]
+struct ResidualInResidualDenseBlock {
+    blocks: [ResidualDenseBlock; 3],
+}
+
+impl ResidualInResidualDenseBlock {
+    fn new(vs: VarBuilder, num_feat: usize, num_grow_ch: usize) -> Result<Self> {
+        let blocks = [
+            ResidualDenseBlock::new(vs.pp("rdb1"), num_feat, num_grow_ch)?,
+            ResidualDenseBlock::new(vs.pp("rdb2"), num_feat, num_grow_ch)?,
+            ResidualDenseBlock::new(vs.pp("rdb3"), num_feat, num_grow_ch)?,
+        ];
+        Ok(Self { blocks })
+    }
+}
+
+impl Module for ResidualInResidualDenseBlock {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let mut ys = xs.clone();
+        for block in &self.blocks {
+            ys = block.forward(&ys)?;
+        }
+        (ys * RESIDUAL_SCALE)? + xs
+    }
+}
+
+/// The `RRDBNet` struct is used to hold the network of the Real-ESRGAN upscalers.
+///
+/// The network upscales 4x, the 2x and 1x variants first fold the pixels into channels.
+#[derive(Debug)]
+pub struct RRDBNet {
+    conv_first: Conv2d,
+    body: Vec<ResidualInResidualDenseBlock>,
+    conv_body: Conv2d,
+    conv_up1: Conv2d,
+    conv_up2: Conv2d,
+    conv_hr: Conv2d,
+    conv_last: Conv2d,
+    scale: usize,
+}
+
+impl RRDBNet {
+    /// Create a new `RRDBNet` instance.
+    pub fn new(vs: VarBuilder, in_channels: usize, out_channels: usize, scale: usize, num_feat: usize, num_block: usize, num_grow_ch: usize) -> Result<Self> {
+        let unshuffle = match scale {
+            4 => 1,
+            2 => 2,
+            1 => 4,
+            _ => candle::bail!("RRDBNet supports the scales 1, 2 and 4, got {scale}"),
+        };
+        let conv_first = conv3x3(in_channels * unshuffle * unshuffle, num_feat, vs.pp("conv_first"))?;
+        let body = (0 .. num_block)
+            .map(|index| ResidualInResidualDenseBlock::new(vs.pp(format!("body.{index}")), num_feat, num_grow_ch))
+            .collect::<Result<Vec<_>>>()?;
+        let conv_body = conv3x3(num_feat, num_feat, vs.pp("conv_body"))?;
+        let conv_up1 = conv3x3(num_feat, num_feat, vs.pp("conv_up1"))?;
+        let conv_up2 = conv3x3(num_feat, num_feat, vs.pp("conv_up2"))?;
+        let conv_hr = conv3x3(num_feat, num_feat, vs.pp("conv_hr"))?;
+        let conv_last = conv3x3(num_feat, out_channels, vs.pp("conv_last"))?;
+        Ok(Self { conv_first, body, conv_body, conv_up1, conv_up2, conv_hr, conv_last, scale })
+    }
+}
+
+impl Module for RRDBNet {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = match self.scale {
+            2 => candle_nn::ops::pixel_unshuffle(xs, 2)?,
+            1 => candle_nn::ops::pixel_unshuffle(xs, 4)?,
+            _ => xs.clone(),
+        };
+        let features = self.conv_first.forward(&xs)?;
+        let mut body = features.clone();
+        for block in &self.body {
+            body = block.forward(&body)?;
+        }
+        let features = (features + self.conv_body.forward(&body)?)?;
+        let upsample = |xs: &Tensor| {
+            let (_, _, height, width) = xs.dims4()?;
+            xs.upsample_nearest2d(height * 2, width * 2)
+        };
+        let features = lrelu(&self.conv_up1.forward(&upsample(&features)?)?)?;
+        let features = lrelu(&self.conv_up2.forward(&upsample(&features)?)?)?;
+        self.conv_last.forward(&lrelu(&self.conv_hr.forward(&features)?)?)
+    }
+}
@@ -0,0 +1,313 @@
+//! The UNet that predicts the noise of the latents.
+
+use candle::{Module, Result, Tensor};
+use candle_nn::{Conv2d, Conv2dConfig, GroupNorm};
+
+use super::attention::{ImagePromptAttention, ImagePromptProjection};
+use super::embeddings::{TimestepEmbedding, Timesteps};
+use super::nn::{self, VarBuilder};
+use super::unet_2d_blocks::*;
+
+/// The `BlockConfig` struct is used to configure a down block and its matching up block.
+#[derive(Debug, Clone, Copy)]
+pub struct BlockConfig {
+    /// The number of output channels.
+    pub out_channels: usize,
+    /// The number of transformer blocks of the attention layers, or `None` if the block has no attention.
+    pub use_cross_attn: Option<usize>,
+    /// The number of attention heads.
+    pub attention_head_dim: usize,
+}
+
+/// The `UNet2DConditionModelConfig` struct is used to configure the UNet.
+#[derive(Debug, Clone)]
+pub struct UNet2DConditionModelConfig {
+    /// Whether the input is mapped from `[0, 1]` to `[-1, 1]`.
+    pub center_input_sample: bool,
+    /// Whether the cosine comes before the sine in the timestep embeddings.
+    pub flip_sin_to_cos: bool,
+    /// The frequency shift of the timestep embeddings.
+    pub freq_shift: f64,
+    /// The configuration of the blocks, from the highest to the lowest resolution.
+    pub blocks: Vec<BlockConfig>,
+    /// The number of ResNet layers of each down block.
+    pub layers_per_block: usize,
+    /// The padding of the downsamplers.
+    pub downsample_padding: usize,
+    /// The output scale factor of the mid block.
+    pub mid_block_scale_factor: f64,
+    /// The number of groups of the group normalizations.
+    pub norm_num_groups: usize,
+    /// The epsilon of the group normalizations.
+    pub norm_eps: f64,
+    /// The dimension of the text embeddings.
+    pub cross_attention_dim: usize,
+    /// The size of the attention slices, `Some(0)` to pick it automatically, or `None` to disable slicing.
+    pub sliced_attention_size: Option<usize>,
+    /// Whether the transformer projections are linear layers.
+    pub use_linear_projection: bool,
+    /// The dimension of the condition added to the timestep embeddings, e.g. the guidance scale embeddings of the LCM models.
+    pub time_cond_proj_dim: Option<usize>,
+}
+
+/// The `UNetConditioning` struct is used to hold the optional inputs of the UNet besides the text embeddings.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UNetConditioning<'a> {
+    /// The residuals added to the outputs of the down blocks, e.g. from ControlNets.
+    pub down_block_additional_residuals: Option<&'a [Tensor]>,
+    /// The residual added to the output of the mid block, e.g. from ControlNets.
+    pub mid_block_additional_residual: Option<&'a Tensor>,
+    /// The image prompt attended to by the cross-attentions, e.g. from an IP-Adapter.
+    pub image_prompt: Option<&'a ImagePromptAttention<'a>>,
+    /// The condition added to the timestep embeddings.
+    pub timestep_cond: Option<&'a Tensor>,
+}
+
+/// The `UNetDownBlock` enum is used to hold a down block with or without attention.
+#[derive(Debug)]
+pub enum UNetDownBlock {
+    /// A down block without attention.
+    Basic(DownBlock2D),
+    /// A down block with attention.
+    CrossAttn(CrossAttnDownBlock2D),
+}
+
+impl UNetDownBlock {
+    /// Apply the block to the hidden states, returning them with the residuals of every layer.
+    pub fn forward(&self, xs: &Tensor, temb: &Tensor, encoder_hidden_states: &Tensor, image_prompt: Option<&ImagePromptAttention>) -> Result<(Tensor, Vec<Tensor>)> {
+        match self {
+            Self::Basic(b) => b.forward(xs, Some(temb)),
+            Self::CrossAttn(b) => b.forward(xs, Some(temb), Some(encoder_hidden_states), image_prompt),
+        }
+    }
+}
+
+impl UNet2DConditionModelConfig {
+    /// Get the size of the attention slices of a block, picking it automatically if the sliced attention size is 0.
+    fn sliced_attention_size(&self, attention_head_dim: usize) -> Option<usize> {
+        match self.sliced_attention_size {
+            Some(0) => Some(attention_head_dim / 2),
+            size => size,
+        }
+    }
+}
+
+/// Create the down blocks of a UNet, which are shared with the ControlNets.
+pub fn down_blocks(vs_db: VarBuilder, config: &UNet2DConditionModelConfig, time_embed_dim: usize) -> Result<Vec<UNetDownBlock>> {
+    let n_blocks = config.blocks.len();
+    let b_channels = config.blocks[0].out_channels;
+    (0 .. n_blocks)
+        .map(|i| {
+            let BlockConfig { out_channels, use_cross_attn, attention_head_dim } = config.blocks[i];
+            let in_channels = if i > 0 { config.blocks[i - 1].out_channels } else { b_channels };
+            let db_cfg = DownBlock2DConfig {
+                num_layers: config.layers_per_block,
+                resnet_eps: config.norm_eps,
+                resnet_groups: config.norm_num_groups,
+                add_downsample: i < n_blocks - 1,
+                downsample_padding: config.downsample_padding,
+                ..Default::default()
+            };
+            if let Some(transformer_layers_per_block) = use_cross_attn {
+                let block_config = CrossAttnDownBlock2DConfig {
+                    downblock: db_cfg,
+                    attn_num_head_channels: attention_head_dim,
+                    cross_attention_dim: config.cross_attention_dim,
+                    sliced_attention_size: config.sliced_attention_size(attention_head_dim),
+                    use_linear_projection: config.use_linear_projection,
+                    transformer_layers_per_block,
+                };
+                let block = CrossAttnDownBlock2D::new(vs_db.pp(i), in_channels, out_channels, Some(time_embed_dim), block_config)?;
+                Ok(UNetDownBlock::CrossAttn(block))
+            } else {
+                let block = DownBlock2D::new(vs_db.pp(i), in_channels, out_channels, Some(time_embed_dim), db_cfg)?;
+                Ok(UNetDownBlock::Basic(block))
+            }
+        })
+        .collect()
+}
+
+/// Create the mid block of a UNet, which is shared with the ControlNets.
+pub fn mid_block(vs: VarBuilder, config: &UNet2DConditionModelConfig, time_embed_dim: usize) -> Result<UNetMidBlock2DCrossAttn> {
+    let last = config.blocks[config.blocks.len() - 1];
+    let mid_cfg = UNetMidBlock2DCrossAttnConfig {
+        resnet_eps: config.norm_eps,
+        output_scale_factor: config.mid_block_scale_factor,
+        cross_attn_dim: config.cross_attention_dim,
+        attn_num_head_channels: last.attention_head_dim,
+        resnet_groups: Some(config.norm_num_groups),
+        sliced_attention_size: config.sliced_attention_size(last.attention_head_dim),
+        use_linear_projection: config.use_linear_projection,
+        transformer_layers_per_block: last.use_cross_attn.unwrap_or(1),
+        ..Default::default()
+    };
+    UNetMidBlock2DCrossAttn::new(vs, last.out_channels, Some(time_embed_dim), mid_cfg)
+}
+
+/// Create the IP-Adapter projections of every cross-attention of a UNet, in the order they are applied.
+///
+/// The IP-Adapter weights number the attention processors of the down, up and mid blocks in that order, self-attentions
+/// included, so the cross-attentions have odd indices. The UNet applies the mid block before the up blocks.
+pub fn image_prompt_projections(vs: VarBuilder, config: &UNet2DConditionModelConfig) -> Result<Vec<ImagePromptProjection>> {
+    let n_blocks = config.blocks.len();
+    let channels = |layers: usize, block: &BlockConfig| vec![block.out_channels; layers * block.use_cross_attn.unwrap_or(0)];
+    let down = config.blocks.iter().flat_map(|block| channels(config.layers_per_block, block)).collect::<Vec<_>>();
+    let up = config.blocks.iter().rev().flat_map(|block| channels(config.layers_per_block + 1, block)).collect::<Vec<_>>();
+    let last = &config.blocks[n_blocks - 1];
+    let mid = vec![last.out_channels; last.use_cross_attn.unwrap_or(1)];
+    let numbered = down.iter().chain(up.iter()).chain(mid.iter()).enumerate().map(|(index, channels)| (2 * index + 1, *channels)).collect::<Vec<_>>();
+    let (down, rest) = numbered.split_at(down.len());
+    let (up, mid) = rest.split_at(up.len());
+    down.iter()
+        .chain(mid.iter())
+        .chain(up.iter())
+        .map(|(index, channels)| ImagePromptProjection::new(vs.pp(index), config.cross_attention_dim, *channels))
+        .collect()
+}
+
+#[derive(Debug)]
+enum UNetUpBlock {
+    Basic(UpBlock2D),
+    CrossAttn(CrossAttnUpBlock2D),
+}
+
+/// The `UNet2DConditionModel` struct is used to hold the UNet conditioned on text embeddings.
+#[derive(Debug)]
+pub struct UNet2DConditionModel {
+    conv_in: Conv2d,
+    time_proj: Timesteps,
+    time_embedding: TimestepEmbedding,
+    down_blocks: Vec<UNetDownBlock>,
+    mid_block: UNetMidBlock2DCrossAttn,
+    up_blocks: Vec<UNetUpBlock>,
+    conv_norm_out: GroupNorm,
+    conv_out: Conv2d,
+    config: UNet2DConditionModelConfig,
+}
+
+impl UNet2DConditionModel {
+    /// Create a new `UNet2DConditionModel` instance.
+    pub fn new(vs: VarBuilder, in_channels: usize, out_channels: usize, config: UNet2DConditionModelConfig) -> Result<Self> {
+        let n_blocks = config.blocks.len();
+        let b_channels = config.blocks[0].out_channels;
+        let bl_channels = config.blocks[n_blocks - 1].out_channels;
+        let time_embed_dim = b_channels * 4;
+        let conv_cfg = Conv2dConfig { padding: 1, ..Default::default() };
+        let conv_in = nn::conv2d(in_channels, b_channels, 3, conv_cfg, vs.pp("conv_in"))?;
+
+        let time_proj = Timesteps::new(b_channels, config.flip_sin_to_cos, config.freq_shift);
+        let time_embedding = TimestepEmbedding::with_condition(vs.pp("time_embedding"), b_channels, time_embed_dim, config.time_cond_proj_dim)?;
+
+        let down_blocks = down_blocks(vs.pp("down_blocks"), &config, time_embed_dim)?;
+        let mid_block = mid_block(vs.pp("mid_block"), &config, time_embed_dim)?;
+
+        let vs_ub = vs.pp("up_blocks");
+        let up_blocks = (0 .. n_blocks)
+            .map(|i| {
+                let BlockConfig { out_channels, use_cross_attn, attention_head_dim } = config.blocks[n_blocks - 1 - i];
+                let prev_out_channels = if i > 0 { config.blocks[n_blocks - i].out_channels } else { bl_channels };
+                let in_channels = {
+                    let index = if i == n_blocks - 1 { 0 } else { n_blocks - i - 2 };
+                    config.blocks[index].out_channels
+                };
+                let ub_cfg = UpBlock2DConfig {
+                    num_layers: config.layers_per_block + 1,
+                    resnet_eps: config.norm_eps,
+                    resnet_groups: config.norm_num_groups,
+                    add_upsample: i < n_blocks - 1,
+                    ..Default::default()
+                };
+                if let Some(transformer_layers_per_block) = use_cross_attn {
+                    let block_config = CrossAttnUpBlock2DConfig {
+                        upblock: ub_cfg,
+                        attn_num_head_channels: attention_head_dim,
+                        cross_attention_dim: config.cross_attention_dim,
+                        sliced_attention_size: config.sliced_attention_size(attention_head_dim),
+                        use_linear_projection: config.use_linear_projection,
+                        transformer_layers_per_block,
+                    };
+                    let block = CrossAttnUpBlock2D::new(vs_ub.pp(i), in_channels, prev_out_channels, out_channels, Some(time_embed_dim), block_config)?;
+                    Ok(UNetUpBlock::CrossAttn(block))
+                } else {
+                    let block = UpBlock2D::new(vs_ub.pp(i), in_channels, prev_out_channels, out_channels, Some(time_embed_dim), ub_cfg)?;
+                    Ok(UNetUpBlock::Basic(block))
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let conv_norm_out = nn::group_norm(config.norm_num_groups, b_channels, config.norm_eps, vs.pp("conv_norm_out"))?;
+        let conv_out = nn::conv2d(b_channels, out_channels, 3, conv_cfg, vs.pp("conv_out"))?;
+        Ok(Self { conv_in, time_proj, time_embedding, down_blocks, mid_block, up_blocks, conv_norm_out, conv_out, config })
+    }
+
+    /// Predict the noise of the latents at a timestep, conditioned on the text embeddings.
+    pub fn forward(&self, xs: &Tensor, timestep: f64, encoder_hidden_states: &Tensor) -> Result<Tensor> {
+        self.forward_with_conditioning(xs, timestep, encoder_hidden_states, &Default::default())
+    }
+
+    /// Predict the noise of the latents with additional conditioning, e.g. residuals from ControlNets added to the
+    /// outputs of the down and mid blocks or an image prompt from an IP-Adapter.
+    pub fn forward_with_conditioning(&self, xs: &Tensor, timestep: f64, encoder_hidden_states: &Tensor, conditioning: &UNetConditioning) -> Result<Tensor> {
+        let UNetConditioning { down_block_additional_residuals, mid_block_additional_residual, image_prompt, timestep_cond } = *conditioning;
+        if let Some(image_prompt) = image_prompt {
+            image_prompt.reset();
+        }
+        let (bsize, _channels, height, width) = xs.dims4()?;
+        let device = xs.device();
+        let n_blocks = self.config.blocks.len();
+        let default_overall_up_factor = 2usize.pow(n_blocks as u32 - 1);
+        let forward_upsample_size = height % default_overall_up_factor != 0 || width % default_overall_up_factor != 0;
+        let xs = if self.config.center_input_sample { ((xs * 2.0)? - 1.0)? } else { xs.clone() };
+
+        let emb = (Tensor::ones(bsize, xs.dtype(), device)? * timestep)?;
+        let emb = self.time_proj.forward(&emb)?;
+        let emb = self.time_embedding.forward_with_condition(&emb, timestep_cond)?;
+
+        let xs = self.conv_in.forward(&xs)?;
+        let mut down_block_res_xs = vec![xs.clone()];
+        let mut xs = xs;
+        for down_block in self.down_blocks.iter() {
+            let (down_xs, res_xs) = down_block.forward(&xs, &emb, encoder_hidden_states, image_prompt)?;
+            down_block_res_xs.extend(res_xs);
+            xs = down_xs;
+        }
+        if let Some(residuals) = down_block_additional_residuals {
+            if residuals.len() != down_block_res_xs.len() {
+                candle::bail!("expected {} down block residuals, got {}", down_block_res_xs.len(), residuals.len())
+            }
+            down_block_res_xs = down_block_res_xs
+                .iter()
+                .zip(residuals)
+                .map(|(xs, residual)| xs + residual)
+                .collect::<Result<Vec<_>>>()?;
+        }
+
+        let mut xs = self.mid_block.forward(&xs, Some(&emb), Some(encoder_hidden_states), image_prompt)?;
+        if let Some(residual) = mid_block_additional_residual {
+            xs = (xs + residual)?;
+        }
+
+        let mut upsample_size = None;
+        for (i, up_block) in self.up_blocks.iter().enumerate() {
+            let n_resnets = match up_block {
+                UNetUpBlock::Basic(b) => b.resnets.len(),
+                UNetUpBlock::CrossAttn(b) => b.upblock.resnets.len(),
+            };
+            let res_xs = down_block_res_xs.split_off(down_block_res_xs.len() - n_resnets);
+            if i < n_blocks - 1 && forward_upsample_size {
+                if let Some(last) = down_block_res_xs.last() {
+                    let (_, _, h, w) = last.dims4()?;
+                    upsample_size = Some((h, w))
+                }
+            }
+            xs = match up_block {
+                UNetUpBlock::Basic(b) => b.forward(&xs, &res_xs, Some(&emb), upsample_size)?,
+                UNetUpBlock::CrossAttn(b) => b.forward(&xs, &res_xs, Some(&emb), upsample_size, Some(encoder_hidden_states), image_prompt)?,
+            };
+        }
+
+        let xs = self.conv_norm_out.forward(&xs)?;
+        let xs = candle_nn::ops::silu(&xs)?;
+        self.conv_out.forward(&xs)
+    }
+}
@@ -0,0 +1,418 @@
+//! Down, mid and up blocks of the UNet.
+
+use candle::{Module, Result, Tensor, D};
+use candle_nn::{Conv2d, Conv2dConfig};
+
+use super::attention::{ImagePromptAttention, SpatialTransformer, SpatialTransformerConfig};
+use super::nn::{self, VarBuilder};
+use super::resnet::{ResnetBlock2D, ResnetBlock2DConfig};
+
+#[derive(Debug)]
+struct Downsample2D {
+    conv: Conv2d,
+    padding: usize,
+}
+
+impl Downsample2D {
+    fn new(vs: VarBuilder, in_channels: usize, out_channels: usize, padding: usize) -> Result<Self> {
+        let config = Conv2dConfig { stride: 2, padding, ..Default::default() };
+        let conv = nn::conv2d(in_channels, out_channels, 3, config, vs.pp("conv"))?;
+        Ok(Self { conv, padding })
+    }
+}
+
+impl Module for Downsample2D {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        if self.padding == 0 {
+            let xs = xs.pad_with_zeros(D::Minus1, 0, 1)?.pad_with_zeros(D::Minus2, 0, 1)?;
+            self.conv.forward(&xs)
+        } else {
+            self.conv.forward(xs)
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Upsample2D {
+    conv: Conv2d,
+}
+
+impl Upsample2D {
+    fn new(vs: VarBuilder, in_channels: usize, out_channels: usize) -> Result<Self> {
+        let config = Conv2dConfig { padding: 1, ..Default::default() };
+        let conv = nn::conv2d(in_channels, out_channels, 3, config, vs.pp("conv"))?;
+        Ok(Self { conv })
+    }
+
+    fn forward(&self, xs: &Tensor, size: Option<(usize, usize)>) -> Result<Tensor> {
+        let xs = match size {
+            None => {
+                let (_bsize, _channels, h, w) = xs.dims4()?;
+                xs.upsample_nearest2d(2 * h, 2 * w)?
+            }
+            Some((h, w)) => xs.upsample_nearest2d(h, w)?,
+        };
+        self.conv.forward(&xs)
+    }
+}
+
+/// The `UNetMidBlock2DCrossAttnConfig` struct is used to configure the mid block of the UNet.
+#[derive(Debug, Clone, Copy)]
+pub struct UNetMidBlock2DCrossAttnConfig {
+    /// The number of attention and ResNet layers after the first ResNet.
+    pub num_layers: usize,
+    /// The epsilon of the ResNet group normalizations.
+    pub resnet_eps: f64,
+    /// The number of groups of the ResNet group normalizations.
+    pub resnet_groups: Option<usize>,
+    /// The number of attention heads.
+    pub attn_num_head_channels: usize,
+    /// The output of the ResNets is divided by this factor.
+    pub output_scale_factor: f64,
+    /// The dimension of the text embeddings.
+    pub cross_attn_dim: usize,
+    /// The size of the attention slices, if the attention is sliced.
+    pub sliced_attention_size: Option<usize>,
+    /// Whether the transformer projections are linear layers.
+    pub use_linear_projection: bool,
+    /// The number of transformer blocks of each attention layer.
+    pub transformer_layers_per_block: usize,
+}
+
+impl Default for UNetMidBlock2DCrossAttnConfig {
+    fn default() -> Self {
+        Self {
+            num_layers: 1,
+            resnet_eps: 1e-6,
+            resnet_groups: Some(32),
+            attn_num_head_channels: 1,
+            output_scale_factor: 1.,
+            cross_attn_dim: 1280,
+            sliced_attention_size: None,
+            use_linear_projection: false,
+            transformer_layers_per_block: 1,
+        }
+    }
+}
+
+/// The `UNetMidBlock2DCrossAttn` struct is used to hold the mid block of the UNet.
+#[derive(Debug)]
+pub struct UNetMidBlock2DCrossAttn {
+    resnet: ResnetBlock2D,
+    attn_resnets: Vec<(SpatialTransformer, ResnetBlock2D)>,
+}
+
+impl UNetMidBlock2DCrossAttn {
+    /// Create a new `UNetMidBlock2DCrossAttn` instance.
+    pub fn new(vs: VarBuilder, in_channels: usize, temb_channels: Option<usize>, config: UNetMidBlock2DCrossAttnConfig) -> Result<Self> {
+        let vs_resnets = vs.pp("resnets");
+        let vs_attns = vs.pp("attentions");
+        let resnet_groups = config.resnet_groups.unwrap_or_else(|| usize::min(in_channels / 4, 32));
+        let resnet_cfg = ResnetBlock2DConfig {
+            eps: config.resnet_eps,
+            groups: resnet_groups,
+            output_scale_factor: config.output_scale_factor,
+            temb_channels,
+            ..Default::default()
+        };
+        let resnet = ResnetBlock2D::new(vs_resnets.pp("0"), in_channels, resnet_cfg)?;
+        let n_heads = config.attn_num_head_channels;
+        let attn_cfg = SpatialTransformerConfig {
+            depth: config.transformer_layers_per_block,
+            num_groups: resnet_groups,
+            context_dim: Some(config.cross_attn_dim),
+            sliced_attention_size: config.sliced_attention_size,
+            use_linear_projection: config.use_linear_projection,
+        };
+        let mut attn_resnets = vec![];
+        for index in 0 .. config.num_layers {
+            let attn = SpatialTransformer::new(vs_attns.pp(index), in_channels, n_heads, in_channels / n_heads, attn_cfg)?;
+            let resnet = ResnetBlock2D::new(vs_resnets.pp(index + 1), in_channels, resnet_cfg)?;
+            attn_resnets.push((attn, resnet))
+        }
+        Ok(Self { resnet, attn_resnets })
+    }
+
+    /// Apply the block to the hidden states.
+    pub fn forward(&self, xs: &Tensor, temb: Option<&Tensor>, encoder_hidden_states: Option<&Tensor>, image_prompt: Option<&ImagePromptAttention>) -> Result<Tensor> {
+        let mut xs = self.resnet.forward(xs, temb)?;
+        for (attn, resnet) in self.attn_resnets.iter() {
+            xs = resnet.forward(&attn.forward(&xs, encoder_hidden_states, image_prompt)?, temb)?
+        }
+        Ok(xs)
+    }
+}
+
+/// The `DownBlock2DConfig` struct is used to configure a down block of the UNet.
+#[derive(Debug, Clone, Copy)]
+pub struct DownBlock2DConfig {
+    /// The number of ResNet layers.
+    pub num_layers: usize,
+    /// The epsilon of the ResNet group normalizations.
+    pub resnet_eps: f64,
+    /// The number of groups of the ResNet group normalizations.
+    pub resnet_groups: usize,
+    /// The output of the ResNets is divided by this factor.
+    pub output_scale_factor: f64,
+    /// Whether the block ends with a downsampler.
+    pub add_downsample: bool,
+    /// The padding of the downsampler.
+    pub downsample_padding: usize,
+}
+
+impl Default for DownBlock2DConfig {
+    fn default() -> Self {
+        Self {
+            num_layers: 1,
+            resnet_eps: 1e-6,
+            resnet_groups: 32,
+            output_scale_factor: 1.,
+            add_downsample: true,
+            downsample_padding: 1,
+        }
+    }
+}
+
+/// The `DownBlock2D` struct is used to hold a down block without attention.
+#[derive(Debug)]
+pub struct DownBlock2D {
+    resnets: Vec<ResnetBlock2D>,
+    downsampler: Option<Downsample2D>,
+}
+
+impl DownBlock2D {
+    /// Create a new `DownBlock2D` instance.
+    pub fn new(vs: VarBuilder, in_channels: usize, out_channels: usize, temb_channels: Option<usize>, config: DownBlock2DConfig) -> Result<Self> {
+        let vs_resnets = vs.pp("resnets");
+        let resnet_cfg = ResnetBlock2DConfig {
+            out_channels: Some(out_channels),
+            eps: config.resnet_eps,
+            groups: config.resnet_groups,
+            output_scale_factor: config.output_scale_factor,
+            temb_channels,
+            ..Default::default()
+        };
+        let resnets = (0 .. config.num_layers)
+            .map(|i| {
+                let in_channels = if i == 0 { in_channels } else { out_channels };
+                ResnetBlock2D::new(vs_resnets.pp(i), in_channels, resnet_cfg)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let downsampler = if config.add_downsample {
+            Some(Downsample2D::new(vs.pp("downsamplers").pp("0"), out_channels, out_channels, config.downsample_padding)?)
+        } else {
+            None
+        };
+        Ok(Self { resnets, downsampler })
+    }
+
+    /// Apply the block to the hidden states, returning them with the residuals of every layer.
+    pub fn forward(&self, xs: &Tensor, temb: Option<&Tensor>) -> Result<(Tensor, Vec<Tensor>)> {
+        let mut xs = xs.clone();
+        let mut output_states = vec![];
+        for resnet in self.resnets.iter() {
+            xs = resnet.forward(&xs, temb)?;
+            output_states.push(xs.clone());
+        }
+        if let Some(downsampler) = &self.downsampler {
+            xs = downsampler.forward(&xs)?;
+            output_states.push(xs.clone());
+        }
+        Ok((xs, output_states))
+    }
+}
+
+/// The `CrossAttnDownBlock2DConfig` struct is used to configure a down block with attention.
+#[derive(Debug, Clone, Copy)]
+pub struct CrossAttnDownBlock2DConfig {
+    /// The configuration of the ResNets and downsampler.
+    pub downblock: DownBlock2DConfig,
+    /// The number of attention heads.
+    pub attn_num_head_channels: usize,
+    /// The dimension of the text embeddings.
+    pub cross_attention_dim: usize,
+    /// The size of the attention slices, if the attention is sliced.
+    pub sliced_attention_size: Option<usize>,
+    /// Whether the transformer projections are linear layers.
+    pub use_linear_projection: bool,
+    /// The number of transformer blocks of each attention layer.
+    pub transformer_layers_per_block: usize,
+}
+
+/// The `CrossAttnDownBlock2D` struct is used to hold a down block with attention.
+#[derive(Debug)]
+pub struct CrossAttnDownBlock2D {
+    downblock: DownBlock2D,
+    attentions: Vec<SpatialTransformer>,
+}
+
+impl CrossAttnDownBlock2D {
+    /// Create a new `CrossAttnDownBlock2D` instance.
+    pub fn new(vs: VarBuilder, in_channels: usize, out_channels: usize, temb_channels: Option<usize>, config: CrossAttnDownBlock2DConfig) -> Result<Self> {
+        let downblock = DownBlock2D::new(vs.clone(), in_channels, out_channels, temb_channels, config.downblock)?;
+        let n_heads = config.attn_num_head_channels;
+        let cfg = SpatialTransformerConfig {
+            depth: config.transformer_layers_per_block,
+            context_dim: Some(config.cross_attention_dim),
+            num_groups: config.downblock.resnet_groups,
+            sliced_attention_size: config.sliced_attention_size,
+            use_linear_projection: config.use_linear_projection,
+        };
+        let vs_attn = vs.pp("attentions");
+        let attentions = (0 .. config.downblock.num_layers)
+            .map(|i| SpatialTransformer::new(vs_attn.pp(i), out_channels, n_heads, out_channels / n_heads, cfg))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { downblock, attentions })
+    }
+
+    /// Apply the block to the hidden states, returning them with the residuals of every layer.
+    pub fn forward(&self, xs: &Tensor, temb: Option<&Tensor>, encoder_hidden_states: Option<&Tensor>, image_prompt: Option<&ImagePromptAttention>) -> Result<(Tensor, Vec<Tensor>)> {
+        let mut output_states = vec![];
+        let mut xs = xs.clone();
+        for (resnet, attn) in self.downblock.resnets.iter().zip(self.attentions.iter()) {
+            xs = resnet.forward(&xs, temb)?;
+            xs = attn.forward(&xs, encoder_hidden_states, image_prompt)?;
+            output_states.push(xs.clone());
+        }
+        if let Some(downsampler) = &self.downblock.downsampler {
+            xs = downsampler.forward(&xs)?;
+            output_states.push(xs.clone());
+        }
+        Ok((xs, output_states))
+    }
+}
+
+/// The `UpBlock2DConfig` struct is used to configure an up block of the UNet.
+#[derive(Debug, Clone, Copy)]
+pub struct UpBlock2DConfig {
+    /// The number of ResNet layers.
+    pub num_layers: usize,
+    /// The epsilon of the ResNet group normalizations.
+    pub resnet_eps: f64,
+    /// The number of groups of the ResNet group normalizations.
+    pub resnet_groups: usize,
+    /// The output of the ResNets is divided by this factor.
+    pub output_scale_factor: f64,
+    /// Whether the block ends with an upsampler.
+    pub add_upsample: bool,
+}
+
+impl Default for UpBlock2DConfig {
+    fn default() -> Self {
+        Self {
+            num_layers: 1,
+            resnet_eps: 1e-6,
+            resnet_groups: 32,
+            output_scale_factor: 1.,
+            add_upsample: true,
+        }
+    }
+}
+
+/// The `UpBlock2D` struct is used to hold an up block without attention.
+#[derive(Debug)]
+pub struct UpBlock2D {
+    /// The ResNet layers, each consuming one residual.
+    pub resnets: Vec<ResnetBlock2D>,
+    upsampler: Option<Upsample2D>,
+}
+
+impl UpBlock2D {
+    /// Create a new `UpBlock2D` instance.
+    pub fn new(vs: VarBuilder, in_channels: usize, prev_output_channels: usize, out_channels: usize, temb_channels: Option<usize>, config: UpBlock2DConfig) -> Result<Self> {
+        let vs_resnets = vs.pp("resnets");
+        let resnet_cfg = ResnetBlock2DConfig {
+            out_channels: Some(out_channels),
+            temb_channels,
+            eps: config.resnet_eps,
+            groups: config.resnet_groups,
+            output_scale_factor: config.output_scale_factor,
+            ..Default::default()
+        };
+        let resnets = (0 .. config.num_layers)
+            .map(|i| {
+                let res_skip_channels = if i == config.num_layers - 1 { in_channels } else { out_channels };
+                let resnet_in_channels = if i == 0 { prev_output_channels } else { out_channels };
+                ResnetBlock2D::new(vs_resnets.pp(i), resnet_in_channels + res_skip_channels, resnet_cfg)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let upsampler = if config.add_upsample {
+            Some(Upsample2D::new(vs.pp("upsamplers").pp("0"), out_channels, out_channels)?)
+        } else {
+            None
+        };
+        Ok(Self { resnets, upsampler })
+    }
+
+    /// Apply the block to the hidden states, consuming the residuals of the matching down block.
+    pub fn forward(&self, xs: &Tensor, res_xs: &[Tensor], temb: Option<&Tensor>, upsample_size: Option<(usize, usize)>) -> Result<Tensor> {
+        let mut xs = xs.clone();
+        for (index, resnet) in self.resnets.iter().enumerate() {
+            xs = Tensor::cat(&[&xs, &res_xs[res_xs.len() - index - 1]], 1)?.contiguous()?;
+            xs = resnet.forward(&xs, temb)?;
+        }
+        match &self.upsampler {
+            Some(upsampler) => upsampler.forward(&xs, upsample_size),
+            None => Ok(xs),
+        }
+    }
+}
+
+/// The `CrossAttnUpBlock2DConfig` struct is used to configure an up block with attention.
+#[derive(Debug, Clone, Copy)]
+pub struct CrossAttnUpBlock2DConfig {
+    /// The configuration of the ResNets and upsampler.
+    pub upblock: UpBlock2DConfig,
+    /// The number of attention heads.
+    pub attn_num_head_channels: usize,
+    /// The dimension of the text embeddings.
+    pub cross_attention_dim: usize,
+    /// The size of the attention slices, if the attention is sliced.
+    pub sliced_attention_size: Option<usize>,
+    /// Whether the transformer projections are linear layers.
+    pub use_linear_projection: bool,
+    /// The number of transformer blocks of each attention layer.
+    pub transformer_layers_per_block: usize,
+}
+
+/// The `CrossAttnUpBlock2D` struct is used to hold an up block with attention.
+#[derive(Debug)]
+pub struct CrossAttnUpBlock2D {
+    /// The ResNets and upsampler of the block.
+    pub upblock: UpBlock2D,
+    attentions: Vec<SpatialTransformer>,
+}
+
+impl CrossAttnUpBlock2D {
+    /// Create a new `CrossAttnUpBlock2D` instance.
+    pub fn new(vs: VarBuilder, in_channels: usize, prev_output_channels: usize, out_channels: usize, temb_channels: Option<usize>, config: CrossAttnUpBlock2DConfig) -> Result<Self> {
+        let upblock = UpBlock2D::new(vs.clone(), in_channels, prev_output_channels, out_channels, temb_channels, config.upblock)?;
+        let n_heads = config.attn_num_head_channels;
+        let cfg = SpatialTransformerConfig {
+            depth: config.transformer_layers_per_block,
+            context_dim: Some(config.cross_attention_dim),
+            num_groups: config.upblock.resnet_groups,
+            sliced_attention_size: config.sliced_attention_size,
+            use_linear_projection: config.use_linear_projection,
+        };
+        let vs_attn = vs.pp("attentions");
+        let attentions = (0 .. config.upblock.num_layers)
+            .map(|i| SpatialTransformer::new(vs_attn.pp(i), out_channels, n_heads, out_channels / n_heads, cfg))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { upblock, attentions })
+    }
+
+    /// Apply the block to the hidden states, consuming the residuals of the matching down block.
+    pub fn forward(&self, xs: &Tensor, res_xs: &[Tensor], temb: Option<&Tensor>, upsample_size: Option<(usize, usize)>, encoder_hidden_states: Option<&Tensor>, image_prompt: Option<&ImagePromptAttention>) -> Result<Tensor> {
+        let mut xs = xs.clone();
+        for (index, resnet) in self.upblock.resnets.iter().enumerate() {
+            xs = Tensor::cat(&[&xs, &res_xs[res_xs.len() - index - 1]], 1)?.contiguous()?;
+            xs = resnet.forward(&xs, temb)?;
+            xs = self.attentions[index].forward(&xs, encoder_hidden_states, image_prompt)?;
+        }
+        match &self.upblock.upsampler {
+            Some(upsampler) => upsampler.forward(&xs, upsample_size),
+            None => Ok(xs),
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! Seeded initial noise for the diffusion process.
+
+use candle::{DType, Device, Tensor};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// The `Noise` struct is used to generate the initial latent noise from seeds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Noise {
+    /// The main seed.
+    pub seed: u64,
+    /// The variation seed and its strength.
+    pub variation: Option<(u64, f64)>,
+    /// The `(width, height)` resolution the seed noise is generated at before being resized to the target resolution.
+    pub resize_from: Option<(usize, usize)>,
+}
+
+impl Noise {
+    /// Create a new `Noise` instance from a seed.
+    pub fn new(seed: u64) -> Self {
+        let variation = None;
+        let resize_from = None;
+        Self { seed, variation, resize_from }
+    }
+
+    /// Sets the variation seed and its strength.
+    pub fn with_variation(self, variation: Option<(u64, f64)>) -> Self {
+        Self { variation, ..self }
+    }
+
+    /// Sets the resolution the seed noise is generated at.
+    pub fn with_resize_from(self, resize_from: Option<(usize, usize)>) -> Self {
+        Self { resize_from, ..self }
+    }
+
+    /// Generate the noise for a latent of shape `(batch, channels, height, width)`.
+    pub fn generate(&self, shape: (usize, usize, usize, usize), device: &Device) -> candle::Result<Tensor> {
+        let (batch, channels, height, width) = shape;
+        let noise_shape = match self.resize_from {
+            Some((width, height)) => (batch, channels, height / 8, width / 8),
+            None => shape,
+        };
+        let mut noise = Self::randn(self.seed, noise_shape)?;
+        if let Some((variation_seed, strength)) = self.variation {
+            let variation = Self::randn(variation_seed, noise_shape)?;
+            noise = slerp(strength, &noise, &variation)?;
+        }
+        if noise_shape != shape {
+            // Keep the composition by pasting the centered overlap of the seed noise
+            // into noise generated at the target resolution.
+            let (_, _, noise_height, noise_width) = noise_shape;
+            let target = Self::randn(self.seed, shape)?;
+            let (target_y, source_y, h) = Self::overlap(height, noise_height);
+            let (target_x, source_x, w) = Self::overlap(width, noise_width);
+            let source = noise.narrow(2, source_y, h)?.narrow(3, source_x, w)?;
+            noise = target.slice_assign(&[0..batch, 0..channels, target_y..target_y + h, target_x..target_x + w], &source)?;
+        }
+        noise.to_device(device)
+    }
+
+    /// Returns the target offset, the source offset and the length of the centered overlap of two sizes.
+    fn overlap(target: usize, source: usize) -> (usize, usize, usize) {
+        if target >= source {
+            ((target - source) / 2, 0, source)
+        } else {
+            (0, (source - target) / 2, target)
+        }
+    }
+
+    /// Generate standard normal noise on the CPU so that seeds are reproducible across devices.
+    fn randn(seed: u64, shape: (usize, usize, usize, usize)) -> candle::Result<Tensor> {
+        let (batch, channels, height, width) = shape;
+        let len = batch * channels * height * width;
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut values = Vec::with_capacity(len + 1);
+        while values.len() < len {
+            // Box-Muller transform.
+            let u1: f64 = 1.0 - rng.gen::<f64>();
+            let u2: f64 = rng.gen::<f64>();
+            let radius = (-2.0 * u1.ln()).sqrt();
+            let angle = 2.0 * std::f64::consts::PI * u2;
+            values.push((radius * angle.cos()) as f32);
+            values.push((radius * angle.sin()) as f32);
+        }
+        values.truncate(len);
+        Tensor::from_vec(values, shape, &Device::Cpu)
+    }
+}
+
+/// Spherical linear interpolation between two tensors, where `t = 0` returns `low` and `t = 1` returns `high`.
+pub fn slerp(t: f64, low: &Tensor, high: &Tensor) -> candle::Result<Tensor> {
+    let dtype = low.dtype();
+    let low = low.to_dtype(DType::F32)?;
+    let high = high.to_dtype(DType::F32)?;
+    let low_norm = low.sqr()?.sum_all()?.sqrt()?.to_scalar::<f32>()? as f64;
+    let high_norm = high.sqr()?.sum_all()?.sqrt()?.to_scalar::<f32>()? as f64;
+    let dot = ((&low / low_norm)? * (&high / high_norm)?)?.sum_all()?.to_scalar::<f32>()? as f64;
+    let result = if dot.abs() > 0.9995 {
+        // The tensors are almost collinear, fall back to a linear interpolation.
+        ((&low * (1.0 - t))? + (&high * t)?)?
+    } else {
+        let omega = dot.acos();
+        let sin_omega = omega.sin();
+        ((&low * (((1.0 - t) * omega).sin() / sin_omega))? + (&high * ((t * omega).sin() / sin_omega))?)?
+    };
+    result.to_dtype(dtype)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn slerp_endpoints() {
+        let low = Noise::randn(0, (1, 4, 8, 8)).unwrap();
+        let high = Noise::randn(1, (1, 4, 8, 8)).unwrap();
+        let start = slerp(0.0, &low, &high).unwrap();
+        let end = slerp(1.0, &low, &high).unwrap();
+        let diff = |a: &Tensor, b: &Tensor| (a - b).unwrap().abs().unwrap().flatten_all().unwrap().max(0).unwrap().to_scalar::<f32>().unwrap();
+        assert!(diff(&start, &low) < 1e-4);
+        assert!(diff(&end, &high) < 1e-4);
+    }
+
+    #[test]
+    fn resized_noise_keeps_center() {
+        let noise = Noise::new(42).with_resize_from(Some((64, 64)));
+        let small = Noise::new(42).generate((1, 4, 8, 8), &Device::Cpu).unwrap();
+        let large = noise.generate((1, 4, 16, 16), &Device::Cpu).unwrap();
+        let center = large.narrow(2, 4, 8).unwrap().narrow(3, 4, 8).unwrap();
+        let diff = (center - small).unwrap().abs().unwrap().sum_all().unwrap().to_scalar::<f32>().unwrap();
+        assert_eq!(diff, 0.0);
+    }
+}
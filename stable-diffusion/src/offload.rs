@@ -0,0 +1,134 @@
+//! Offloading and device placement of the Stable Diffusion components.
+
+use std::sync::Arc;
+
+use crate::{Device, Result};
+
+/// The `OffloadPolicy` enum is used to specify where the weights of the components are kept between their uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OffloadPolicy {
+    /// Every component stays loaded on its device for the whole life of the model.
+    #[default]
+    None,
+    /// The weights of the UNet and the VAE stay in the CPU memory, and are moved to their devices only while sampling
+    /// and while encoding or decoding images. The text encoders run on the CPU.
+    Model,
+}
+
+/// The `ComponentDevices` struct is used to place components on devices other than the main device.
+#[derive(Debug, Clone, Default)]
+pub struct ComponentDevices {
+    /// The device of the UNet.
+    pub unet: Option<Device>,
+    /// The device of the VAE.
+    pub vae: Option<Device>,
+    /// The device of the text encoders.
+    pub text_encoder: Option<Device>,
+}
+
+impl ComponentDevices {
+    /// Create a new `ComponentDevices` instance placing every component on the main device.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Sets the device of the UNet.
+    pub fn with_unet(self, unet: Option<Device>) -> Self {
+        Self { unet, ..self }
+    }
+
+    /// Sets the device of the VAE.
+    pub fn with_vae(self, vae: Option<Device>) -> Self {
+        Self { vae, ..self }
+    }
+
+    /// Sets the device of the text encoders.
+    pub fn with_text_encoder(self, text_encoder: Option<Device>) -> Self {
+        Self { text_encoder, ..self }
+    }
+}
+
+/// The `Component` enum is used to hold a model that is either loaded on its device or offloaded.
+pub enum Component<T> {
+    /// A model that stays loaded on its device.
+    Resident(Arc<T>),
+    /// A model whose weights are kept in the CPU memory. It's built on its device every time it's used, and released
+    /// afterwards.
+    Offloaded(Arc<dyn Fn() -> Result<T> + Send + Sync>),
+}
+
+impl<T> Component<T> {
+    /// Create a new `Component::Offloaded` instance from a function building the model on its device.
+    pub fn offloaded(build: impl Fn() -> Result<T> + Send + Sync + 'static) -> Self {
+        Self::Offloaded(Arc::new(build))
+    }
+
+    /// Get the model, moving it to its device if it's offloaded.
+    pub fn get(&self) -> Result<Arc<T>> {
+        match self {
+            Self::Resident(model) => Ok(model.clone()),
+            Self::Offloaded(build) => build().map(Arc::new),
+        }
+    }
+
+    /// Get an identifier of the component, which is different for every loaded or offloaded model.
+    pub fn id(&self) -> usize {
+        match self {
+            Self::Resident(model) => Arc::as_ptr(model) as *const () as usize,
+            Self::Offloaded(build) => Arc::as_ptr(build) as *const () as usize,
+        }
+    }
+
+    /// Check if the model stays loaded on its device.
+    pub fn is_resident(&self) -> bool {
+        matches!(self, Self::Resident(_))
+    }
+}
+
+impl<T> Clone for Component<T> {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Resident(model) => Self::Resident(model.clone()),
+            Self::Offloaded(build) => Self::Offloaded(build.clone()),
+        }
+    }
+}
+
+impl<T> From<Arc<T>> for Component<T> {
+    fn from(model: Arc<T>) -> Self {
+        Self::Resident(model)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use candle::{DType, Module, Tensor};
+
+    use super::*;
+    use crate::models::nn::{linear, HostWeights, Linear};
+
+    #[test]
+    fn offloaded_models_are_built_from_the_memory() {
+        let path = std::env::temp_dir().join("offloaded-linear.safetensors");
+        let tensors = HashMap::from([
+            ("weight".to_string(), Tensor::new(&[[1f32, 0.0], [0.0, 2.0]], &Device::Cpu).unwrap()),
+            ("bias".to_string(), Tensor::new(&[0f32, 1.0], &Device::Cpu).unwrap()),
+        ]);
+        candle::safetensors::save(&tensors, &path).unwrap();
+        let weights = HostWeights::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // The file is gone, so the model can only be built from the weights kept in the memory.
+        let component: Component<Linear> = Component::offloaded(move || Ok(linear(2, 2, weights.var_builder(&Device::Cpu, DType::F32)?)?));
+        assert!(!component.is_resident());
+        let (first, second) = (component.get().unwrap(), component.get().unwrap());
+        assert!(!Arc::ptr_eq(&first, &second));
+        let output = second.forward(&Tensor::new(&[[1f32, 1.0]], &Device::Cpu).unwrap()).unwrap();
+        assert_eq!(output.to_vec2::<f32>().unwrap(), [[1.0, 3.0]]);
+
+        let resident = Component::from(first.clone());
+        assert!(Arc::ptr_eq(&resident.get().unwrap(), &first));
+    }
+}
@@ -0,0 +1,15 @@
+//! A common interface for the image generation pipelines.
+
+use crate::{GenerationParameters, Result, StableDiffusion};
+
+/// The `Pipeline` trait is used to generate images with any of the pipelines from the same parameters.
+pub trait Pipeline {
+    /// Generate an image from generation parameters.
+    fn generate(&self, args: GenerationParameters) -> Result<image::ImageBuffer<image::Rgb<u8>, Vec<u8>>>;
+}
+
+impl Pipeline for StableDiffusion {
+    fn generate(&self, args: GenerationParameters) -> Result<image::ImageBuffer<image::Rgb<u8>, Vec<u8>>> {
+        StableDiffusion::generate(self, args)
+    }
+}
@@ -0,0 +1,155 @@
+//! Deterministic annotators turning images into ControlNet control images.
+//!
+//! Every annotator resizes the image to the target resolution and returns an RGB control image.
+
+use image::{imageops::FilterType, DynamicImage, GrayImage, ImageBuffer, Luma, Rgb};
+use serde::{Deserialize, Serialize};
+
+use crate::{Result, StableDiffusionError};
+
+/// The control image type returned by the annotators.
+pub type ControlImageBuffer = ImageBuffer<Rgb<u8>, Vec<u8>>;
+
+/// The `Preprocessor` enum is used to select an annotator and its parameters.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Preprocessor {
+    /// Canny edges with low and high hysteresis thresholds, e.g. `(100.0, 200.0)`.
+    Canny {
+        /// The low threshold.
+        low: f32,
+        /// The high threshold.
+        high: f32,
+    },
+    /// Soft edges from the magnitude of the Sobel gradients.
+    SoftEdge,
+    /// Dark strokes on a light background turned into white strokes on black.
+    Scribble {
+        /// The luminance under which a pixel is a stroke.
+        threshold: u8,
+    },
+    /// The image downsampled by a factor and upsampled back, for the tile ControlNet.
+    Tile {
+        /// The downsampling factor.
+        factor: u32,
+    },
+    /// The colors quantized into flat regions, for the segmentation ControlNet.
+    Segmentation {
+        /// The number of levels of each channel.
+        levels: u8,
+    },
+}
+
+impl Preprocessor {
+    /// Annotate an image at the target resolution.
+    pub fn apply(&self, image: &DynamicImage, width: u32, height: u32) -> Result<ControlImageBuffer> {
+        match *self {
+            Self::Canny { low, high } => canny(image, width, height, low, high),
+            Self::SoftEdge => Ok(soft_edge(image, width, height)),
+            Self::Scribble { threshold } => Ok(scribble(image, width, height, threshold)),
+            Self::Tile { factor } => Ok(tile(image, width, height, factor)),
+            Self::Segmentation { levels } => Ok(segmentation(image, width, height, levels)),
+        }
+    }
+
+    /// Check if the parameters of the annotator are valid.
+    pub fn validate(&self) -> Result<()> {
+        match *self {
+            Self::Canny { low, high } => validate_canny(low, high),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn resized_luma(image: &DynamicImage, width: u32, height: u32) -> GrayImage {
+    image.resize_exact(width, height, FilterType::Triangle).to_luma8()
+}
+
+fn luma_to_rgb(image: &GrayImage) -> ControlImageBuffer {
+    DynamicImage::ImageLuma8(image.clone()).to_rgb8()
+}
+
+fn validate_canny(low: f32, high: f32) -> Result<()> {
+    if !(low.is_finite() && high.is_finite() && 0.0 <= low && low <= high) {
+        return Err(StableDiffusionError::invalid_parameters(format!("the Canny thresholds must satisfy 0 <= low <= high, got {low} and {high}")));
+    }
+    Ok(())
+}
+
+/// Detect the Canny edges of an image, as white edges on black.
+pub fn canny(image: &DynamicImage, width: u32, height: u32, low: f32, high: f32) -> Result<ControlImageBuffer> {
+    validate_canny(low, high)?;
+    let edges = imageproc::edges::canny(&resized_luma(image, width, height), low, high);
+    Ok(luma_to_rgb(&edges))
+}
+
+/// Detect soft edges from the Sobel gradients of an image, normalized to the strongest gradient.
+pub fn soft_edge(image: &DynamicImage, width: u32, height: u32) -> ControlImageBuffer {
+    let luma = imageproc::filter::gaussian_blur_f32(&resized_luma(image, width, height), 1.0);
+    let gradients = imageproc::gradients::sobel_gradients(&luma);
+    let max = gradients.pixels().map(|pixel| pixel[0]).max().unwrap_or(0).max(1) as f32;
+    let edges = GrayImage::from_fn(width, height, |x, y| {
+        Luma([(gradients.get_pixel(x, y)[0] as f32 / max * 255.0).round() as u8])
+    });
+    luma_to_rgb(&edges)
+}
+
+/// Turn the dark strokes of a scribble into white strokes on black.
+pub fn scribble(image: &DynamicImage, width: u32, height: u32, threshold: u8) -> ControlImageBuffer {
+    let mut strokes = resized_luma(image, width, height);
+    for pixel in strokes.pixels_mut() {
+        pixel[0] = if pixel[0] < threshold { 255 } else { 0 };
+    }
+    luma_to_rgb(&strokes)
+}
+
+/// Downsample an image by a factor and upsample it back to the target resolution.
+pub fn tile(image: &DynamicImage, width: u32, height: u32, factor: u32) -> ControlImageBuffer {
+    let factor = factor.max(1);
+    let small = image.resize_exact((width / factor).max(1), (height / factor).max(1), FilterType::Triangle);
+    small.resize_exact(width, height, FilterType::Triangle).to_rgb8()
+}
+
+/// Quantize the colors of an image into flat regions with a number of levels per channel.
+pub fn segmentation(image: &DynamicImage, width: u32, height: u32, levels: u8) -> ControlImageBuffer {
+    let levels = levels.max(2) as u32;
+    let step = 256 / levels;
+    let mut regions = imageproc::filter::median_filter(&image.resize_exact(width, height, FilterType::Triangle).to_rgb8(), 2, 2);
+    for pixel in regions.pixels_mut() {
+        for channel in pixel.0.iter_mut() {
+            let level = (*channel as u32 / step).min(levels - 1);
+            *channel = (level * 255 / (levels - 1)) as u8;
+        }
+    }
+    regions
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn split_image() -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_fn(64, 64, |x, _| Luma([if x < 32 { 0 } else { 255 }])))
+    }
+
+    #[test]
+    fn canny_finds_the_boundary() {
+        let edges = canny(&split_image(), 32, 32, 50.0, 100.0).unwrap();
+        assert_eq!(edges.dimensions(), (32, 32));
+        assert!(edges.enumerate_pixels().any(|(x, _, pixel)| (15 ..= 16).contains(&x) && pixel[0] == 255));
+        assert!(edges.enumerate_pixels().all(|(x, _, pixel)| (14 ..= 17).contains(&x) || pixel[0] == 0));
+    }
+
+    #[test]
+    fn inverted_canny_thresholds_are_rejected() {
+        let preprocessor = Preprocessor::Canny { low: 200.0, high: 100.0 };
+        assert!(matches!(preprocessor.validate(), Err(StableDiffusionError::InvalidParameters(_))));
+        assert!(preprocessor.apply(&split_image(), 32, 32).is_err());
+    }
+
+    #[test]
+    fn scribble_inverts_strokes() {
+        let strokes = scribble(&split_image(), 64, 64, 128);
+        assert_eq!(strokes.get_pixel(0, 0)[0], 255);
+        assert_eq!(strokes.get_pixel(63, 0)[0], 0);
+    }
+}
@@ -0,0 +1,222 @@
+//! A module for the composite prompt syntaxes: composable `AND` prompts and prompt scheduling.
+//!
+//! `a cat :1.2 AND a dog :0.8` combines the noise predictions of each term with its weight, while `[from:to:when]`
+//! switches from a text to another at a step, `[to:when]` adds a text and `[from::when]` removes it. `when` is a step
+//! number, or a fraction of the steps if it's lower than 1. `[a|b]` alternates between texts at every step.
+
+use candle::Tensor;
+
+use crate::{Result, StableDiffusionError};
+
+/// The `PromptTerm` struct is used to specify a weighted term of a composable prompt.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PromptTerm {
+    pub text: String,
+    pub weight: f64,
+}
+
+impl PromptTerm {
+    /// Split a prompt on the `AND` keywords into its terms, each optionally ending with a `:weight`.
+    pub fn parse(prompt: &str) -> Vec<Self> {
+        split_and(prompt)
+            .into_iter()
+            .map(|term| {
+                let weighted = term.rsplit_once(':').and_then(|(text, weight)| Some((text, weight.trim().parse::<f64>().ok()?)));
+                match weighted {
+                    Some((text, weight)) => Self { text: text.trim().to_string(), weight },
+                    None => Self { text: term.trim().to_string(), weight: 1.0 },
+                }
+            })
+            .collect()
+    }
+}
+
+/// Split a prompt on the `AND` words.
+fn split_and(prompt: &str) -> Vec<&str> {
+    let is_word = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '_');
+    let mut terms = Vec::new();
+    let mut start = 0;
+    for (index, _) in prompt.match_indices("AND") {
+        if is_word(prompt[.. index].chars().next_back()) || is_word(prompt[index + 3 ..].chars().next()) {
+            continue;
+        }
+        terms.push(&prompt[start .. index]);
+        start = index + 3;
+    }
+    terms.push(&prompt[start ..]);
+    terms
+}
+
+/// A node of a scheduled prompt.
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Text(String),
+    Edit { from: Vec<Node>, to: Vec<Node>, when: f64 },
+    Alternate(Vec<Vec<Node>>),
+}
+
+/// The `PromptSchedule` struct is used to get the text of a scheduled prompt at each step.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PromptSchedule {
+    /// The texts with the step they're used until, exclusive.
+    pub texts: Vec<(usize, String)>,
+}
+
+impl PromptSchedule {
+    /// Parse a prompt with the `[from:to:when]` and `[a|b]` syntaxes for a number of steps.
+    pub fn parse(prompt: &str, n_steps: usize) -> Self {
+        let mut chars = prompt.chars().peekable();
+        let nodes = parse_sequence(&mut chars, false);
+        let mut texts: Vec<(usize, String)> = Vec::new();
+        for step in 0 .. n_steps.max(1) {
+            let text = render(&nodes, step, n_steps);
+            match texts.last_mut() {
+                Some((end, last)) if *last == text => *end = step + 1,
+                _ => texts.push((step + 1, text)),
+            }
+        }
+        Self { texts }
+    }
+
+    /// Get the text at a step, the last one after the scheduled steps.
+    pub fn at(&self, step: usize) -> &str {
+        self.texts
+            .iter()
+            .find(|(end, _)| step < *end)
+            .or(self.texts.last())
+            .map_or("", |(_, text)| text.as_str())
+    }
+}
+
+/// Parse the nodes until the end of the prompt, or until a separator or the end of the group if in a group.
+fn parse_sequence(chars: &mut std::iter::Peekable<std::str::Chars>, in_group: bool) -> Vec<Node> {
+    let mut nodes = Vec::new();
+    let mut text = String::new();
+    while let Some(&c) = chars.peek() {
+        match c {
+            ':' | '|' | ']' if in_group => break,
+            '[' => {
+                chars.next();
+                if !text.is_empty() {
+                    nodes.push(Node::Text(std::mem::take(&mut text)));
+                }
+                nodes.extend(parse_group(chars));
+            }
+            _ => {
+                text.push(c);
+                chars.next();
+            }
+        }
+    }
+    if !text.is_empty() {
+        nodes.push(Node::Text(text));
+    }
+    nodes
+}
+
+/// Parse a group after its `[`, keeping it as text if it isn't an edit or an alternation.
+fn parse_group(chars: &mut std::iter::Peekable<std::str::Chars>) -> Vec<Node> {
+    let mut parts = vec![parse_sequence(chars, true)];
+    let mut separators = Vec::new();
+    let closed = loop {
+        match chars.next() {
+            Some(']') => break true,
+            Some(separator) => {
+                separators.push(separator);
+                parts.push(parse_sequence(chars, true));
+            }
+            None => break false,
+        }
+    };
+    let when = || match parts.last().map(Vec::as_slice) {
+        Some([Node::Text(when)]) => when.trim().parse::<f64>().ok(),
+        _ => None,
+    };
+    if closed {
+        match (separators.as_slice(), when()) {
+            ([':'], Some(when)) => return vec![Node::Edit { from: Vec::new(), to: parts[0].clone(), when }],
+            ([':', ':'], Some(when)) => return vec![Node::Edit { from: parts[0].clone(), to: parts[1].clone(), when }],
+            (separators, _) if !separators.is_empty() && separators.iter().all(|separator| *separator == '|') => return vec![Node::Alternate(parts)],
+            _ => {}
+        }
+    }
+    // Not a scheduling group, e.g. an attention bracket, which is kept as it is.
+    let mut nodes = vec![Node::Text("[".into())];
+    for (index, part) in parts.into_iter().enumerate() {
+        if index > 0 {
+            nodes.push(Node::Text(separators[index - 1].to_string()));
+        }
+        nodes.extend(part);
+    }
+    if closed {
+        nodes.push(Node::Text("]".into()));
+    }
+    nodes
+}
+
+/// Render the text of the nodes at a step.
+fn render(nodes: &[Node], step: usize, n_steps: usize) -> String {
+    nodes
+        .iter()
+        .map(|node| match node {
+            Node::Text(text) => text.clone(),
+            Node::Edit { from, to, when } => {
+                let when = if *when < 1.0 { when * n_steps as f64 } else { *when };
+                if (step as f64) < when.floor() { render(from, step, n_steps) } else { render(to, step, n_steps) }
+            }
+            Node::Alternate(options) => render(&options[step % options.len()], step, n_steps),
+        })
+        .collect()
+}
+
+/// The `Conditioning` struct is used to hold the weighted text embeddings conditioning the UNet at each step.
+pub(crate) struct Conditioning {
+    /// The weighted embeddings with the step they're used until, exclusive.
+    terms: Vec<(usize, Vec<(Tensor, f64)>)>,
+}
+
+impl From<Tensor> for Conditioning {
+    fn from(text_embeddings: Tensor) -> Self {
+        Self { terms: vec![(usize::MAX, vec![(text_embeddings, 1.0)])] }
+    }
+}
+
+impl Conditioning {
+    /// Create a new `Conditioning` instance from the weighted embeddings of step ranges, with their end step.
+    pub fn new(terms: Vec<(usize, Vec<(Tensor, f64)>)>) -> Result<Self> {
+        if terms.iter().any(|(_, terms)| terms.is_empty()) {
+            return Err(StableDiffusionError::invalid_parameters("the prompt has no terms"));
+        }
+        Ok(Self { terms })
+    }
+
+    /// Get the weighted embeddings of a step.
+    pub fn at(&self, step: usize) -> &[(Tensor, f64)] {
+        self.terms
+            .iter()
+            .find(|(end, _)| step < *end)
+            .or(self.terms.last())
+            .map_or(&[][..], |(_, terms)| terms.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn composite_prompts() {
+        let terms = PromptTerm::parse("a cat :1.2 AND a dog AND ANDROID :0.5");
+        let terms = terms.iter().map(|term| (term.text.as_str(), term.weight)).collect::<Vec<_>>();
+        assert_eq!(terms, [("a cat", 1.2), ("a dog", 1.0), ("ANDROID", 0.5)]);
+
+        let schedule = PromptSchedule::parse("a [dog:cat:0.5] in [the snow|the rain], [high quality]", 4);
+        assert_eq!(schedule.at(0), "a dog in the snow, [high quality]");
+        assert_eq!(schedule.at(1), "a dog in the rain, [high quality]");
+        assert_eq!(schedule.at(2), "a cat in the snow, [high quality]");
+        assert_eq!(schedule.at(9), "a cat in the rain, [high quality]");
+
+        let schedule = PromptSchedule::parse("a house[, at night:2][ with a tree::3]", 4);
+        assert_eq!(schedule.texts, [(2, "a house with a tree".to_string()), (3, "a house, at night with a tree".to_string()), (4, "a house, at night".to_string())]);
+    }
+}
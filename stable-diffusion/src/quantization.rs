@@ -0,0 +1,128 @@
+//! Conversion of safetensors weights to quantized GGUF weights.
+
+use std::path::Path;
+
+use candle::quantized::{gguf_file, GgmlDType, QTensor};
+use candle::{DType, Device};
+use sha2::{Digest, Sha256};
+
+use crate::Result;
+
+/// The `Quantization` enum is used to specify the format of the quantized weights.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Quantization {
+    /// 4-bit quantization with a scale per block of 32 weights.
+    Q4_0,
+    /// 5-bit quantization with a scale per block of 32 weights.
+    Q5_0,
+    /// 8-bit quantization with a scale per block of 32 weights.
+    #[default]
+    Q8_0,
+    /// 4-bit k-quantization with super-blocks of 256 weights.
+    Q4K,
+    /// 5-bit k-quantization with super-blocks of 256 weights.
+    Q5K,
+    /// 6-bit k-quantization with super-blocks of 256 weights.
+    Q6K,
+}
+
+impl Quantization {
+    fn ggml_dtype(&self) -> GgmlDType {
+        match self {
+            Self::Q4_0 => GgmlDType::Q4_0,
+            Self::Q5_0 => GgmlDType::Q5_0,
+            Self::Q8_0 => GgmlDType::Q8_0,
+            Self::Q4K => GgmlDType::Q4K,
+            Self::Q5K => GgmlDType::Q5K,
+            Self::Q6K => GgmlDType::Q6K,
+        }
+    }
+}
+
+/// Get the file name of the quantized weights of a source file, e.g. `unet.0123456789abcdef.q8_0.gguf`.
+///
+/// The name depends on the path, size, and modification time of the source, so quantized weights are never reused
+/// for another source. Files fetched from a repository have the revision in their path.
+pub fn quantized_file_name(input: impl AsRef<Path>, name: &str, quantization: Quantization) -> Result<String> {
+    let input = input.as_ref();
+    let metadata = std::fs::metadata(input)?;
+    let modified = metadata.modified()?.duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+    let mut hasher = Sha256::new();
+    hasher.update(std::fs::canonicalize(input)?.to_string_lossy().as_bytes());
+    hasher.update(metadata.len().to_le_bytes());
+    hasher.update(modified.as_nanos().to_le_bytes());
+    let key = hasher.finalize().iter().take(8).map(|byte| format!("{byte:02x}")).collect::<String>();
+    Ok(format!("{name}.{key}.{quantization:?}.gguf").to_lowercase())
+}
+
+/// Convert safetensors weights to GGUF weights.
+///
+/// The weights of the linear layers are quantized. Their rows must be a multiple of the block size of the quantization,
+/// otherwise they are stored in F16, like the convolutions and embeddings. Biases and normalizations are stored in F32.
+pub fn quantize_weights(input: impl AsRef<Path>, output: impl AsRef<Path>, quantization: Quantization) -> Result<()> {
+    let input = input.as_ref();
+    let output = output.as_ref();
+    let _span = tracing::info_span!("quantize", input = %input.display(), ?quantization).entered();
+    let dtype = quantization.ggml_dtype();
+    let tensors = candle::safetensors::load(input, &Device::Cpu)?;
+    let mut names = tensors.keys().collect::<Vec<_>>();
+    names.sort();
+    let mut quantized = Vec::with_capacity(names.len());
+    for name in names {
+        let tensor = tensors[name].to_dtype(DType::F32)?;
+        let dims = tensor.dims();
+        let is_linear = dims.len() == 2 && name.ends_with(".weight") && !name.contains("embedding");
+        let tensor_dtype = if is_linear && dims[1] % dtype.block_size() == 0 {
+            dtype
+        } else if dims.len() > 1 {
+            GgmlDType::F16
+        } else {
+            GgmlDType::F32
+        };
+        tracing::debug!(name = %name, ?dims, dtype = ?tensor_dtype, "quantizing tensor");
+        quantized.push((name.as_str(), QTensor::quantize(&tensor, tensor_dtype)?));
+    }
+    if let Some(directory) = output.parent() {
+        std::fs::create_dir_all(directory)?;
+    }
+    let mut file = std::fs::File::create(output)?;
+    let tensors = quantized.iter().map(|(name, tensor)| (*name, tensor)).collect::<Vec<_>>();
+    gguf_file::write(&mut file, &[], &tensors)?;
+    tracing::info!(output = %output.display(), "weights quantized");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use candle::{Module, Tensor};
+    use crate::models::nn::{self, VarBuilder};
+
+    #[test]
+    fn quantized_weights_round_trip() {
+        let directory = std::env::temp_dir().join("stable-diffusion-quantization-test");
+        std::fs::create_dir_all(&directory).unwrap();
+        let input = directory.join("linear.safetensors");
+        let weight = Tensor::randn(0f32, 1., (8, 64), &Device::Cpu).unwrap();
+        let bias = Tensor::randn(0f32, 1., 8, &Device::Cpu).unwrap();
+        candle::safetensors::save(&[("linear.weight", weight.clone()), ("linear.bias", bias.clone())].into_iter().collect(), &input).unwrap();
+        let name = quantized_file_name(&input, "linear", Quantization::Q8_0).unwrap();
+        assert!(name.starts_with("linear.") && name.ends_with(".q8_0.gguf"));
+        let output = directory.join(name);
+        quantize_weights(&input, &output, Quantization::Q8_0).unwrap();
+
+        let vs = VarBuilder::from_file(&output, &Device::Cpu, DType::F32).unwrap();
+        let linear = nn::linear(64, 8, vs.pp("linear")).unwrap();
+        assert!(matches!(linear, nn::Linear::Quantized(..)));
+        let xs = Tensor::randn(0f32, 1., (2, 64), &Device::Cpu).unwrap();
+        let expected = xs.matmul(&weight.t().unwrap()).unwrap().broadcast_add(&bias).unwrap();
+        let max = |xs: Tensor| xs.abs().unwrap().flatten_all().unwrap().max(0).unwrap().to_scalar::<f32>().unwrap();
+        // The inputs of quantized matmuls are also quantized to 8 bits.
+        let error = max((linear.forward(&xs).unwrap() - &expected).unwrap()) / max(expected);
+        assert!(error < 0.05, "{error}");
+
+        candle::safetensors::save(&[("linear.weight", weight.clone())].into_iter().collect(), &input).unwrap();
+        assert_ne!(quantized_file_name(&input, "linear", Quantization::Q8_0).unwrap(), output.file_name().unwrap().to_string_lossy());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,104 @@
+//! A module for prompting regions of the canvas separately.
+
+use candle::{DType, Device, Tensor};
+use serde::{Deserialize, Serialize};
+
+use crate::{Result, StableDiffusionError};
+
+/// The `RegionArea` enum is used to specify the part of the canvas a regional prompt applies to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RegionArea {
+    /// A rectangle, in fractions of the width and height of the canvas.
+    Rect {
+        x: f64,
+        y: f64,
+        width: f64,
+        height: f64,
+    },
+    /// A mask, where the white pixels are in the region. It's resized to the resolution of the generation.
+    Mask(#[serde(with = "crate::serialization::luma_image")] image::ImageBuffer<image::Luma<u8>, Vec<u8>>),
+}
+
+/// The `PromptRegion` struct is used to condition a region of the canvas on its own prompt.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PromptRegion {
+    pub prompt: String,
+    /// The unconditional prompt of the region, defaults to the one of the generation.
+    pub uncond_prompt: Option<String>,
+    pub area: RegionArea,
+    /// The weight of the region prediction against the base prediction and the overlapping regions.
+    pub weight: f64,
+}
+
+impl PromptRegion {
+    /// Create a new `PromptRegion` instance with a weight of 1.
+    pub fn new(prompt: impl Into<String>, area: RegionArea) -> Self {
+        let prompt = prompt.into();
+        let uncond_prompt = None;
+        let weight = 1.0;
+        Self { prompt, uncond_prompt, area, weight }
+    }
+
+    /// Create a new `PromptRegion` instance for a rectangle, in fractions of the canvas.
+    pub fn rect(prompt: impl Into<String>, x: f64, y: f64, width: f64, height: f64) -> Self {
+        Self::new(prompt, RegionArea::Rect { x, y, width, height })
+    }
+
+    /// Sets the unconditional prompt.
+    pub fn with_uncond_prompt(self, uncond_prompt: Option<String>) -> Self {
+        Self { uncond_prompt, ..self }
+    }
+
+    /// Sets the weight.
+    pub fn with_weight(self, weight: f64) -> Self {
+        Self { weight, ..self }
+    }
+
+    /// Check if the region is valid.
+    pub fn validate(&self) -> Result<()> {
+        if !self.weight.is_finite() || self.weight < 0.0 {
+            return Err(StableDiffusionError::invalid_parameters(format!("the weight of the region {} must be non-negative, got {}", self.prompt, self.weight)));
+        }
+        if let RegionArea::Rect { x, y, width, height } = self.area {
+            let valid = [x, y, width, height].iter().all(|value| (0.0 ..= 1.0).contains(value)) && x + width <= 1.0 && y + height <= 1.0;
+            if !valid || width == 0.0 || height == 0.0 {
+                return Err(StableDiffusionError::invalid_parameters(format!("the rectangle of the region {} must be a non-empty part of the canvas, got {x}, {y}, {width}x{height}", self.prompt)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the `(1, 1, height, width)` mask of the region in `[0, 1]`, multiplied by its weight.
+    pub(crate) fn to_tensor(&self, width: usize, height: usize, device: &Device, dtype: DType) -> Result<Tensor> {
+        let mask = match &self.area {
+            RegionArea::Rect { x, y, width: rect_width, height: rect_height } => {
+                let (left, right) = ((x * width as f64).round() as usize, ((x + rect_width) * width as f64).round() as usize);
+                let (top, bottom) = ((y * height as f64).round() as usize, ((y + rect_height) * height as f64).round() as usize);
+                (0 .. height)
+                    .flat_map(|row| (0 .. width).map(move |column| (row, column)))
+                    .map(|(row, column)| if (top .. bottom).contains(&row) && (left .. right).contains(&column) { 1f32 } else { 0f32 })
+                    .collect::<Vec<_>>()
+            }
+            RegionArea::Mask(mask) => image::imageops::resize(mask, width as u32, height as u32, image::imageops::FilterType::Triangle)
+                .pixels()
+                .map(|pixel| pixel[0] as f32 / 255.0)
+                .collect(),
+        };
+        let mask = (Tensor::from_vec(mask, (1, 1, height, width), device)? * self.weight)?;
+        Ok(mask.to_dtype(dtype)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rectangle_masks() {
+        let region = PromptRegion::rect("a cat", 0.5, 0.0, 0.5, 1.0).with_weight(0.5);
+        let mask = region.to_tensor(4, 2, &Device::Cpu, DType::F32).unwrap();
+        assert_eq!(mask.flatten_all().unwrap().to_vec1::<f32>().unwrap(), [0.0, 0.0, 0.5, 0.5, 0.0, 0.0, 0.5, 0.5]);
+        assert!(PromptRegion::rect("a dog", 0.5, 0.0, 0.6, 1.0).validate().is_err());
+    }
+}
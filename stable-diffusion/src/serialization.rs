@@ -0,0 +1,154 @@
+//! Serde helpers for the fields without a serde implementation, e.g. images and data types.
+//!
+//! Images are serialized as `{"base64": "<PNG>"}` and deserialized either from `{"base64": "..."}`,
+//! from `{"path": "..."}` or from a plain path string.
+
+use std::io::Cursor;
+use std::path::PathBuf;
+
+use base64::Engine;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// The `ImageSource` enum is used to specify where a serialized image comes from.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ImageSource {
+    Path(PathBuf),
+    Base64(String),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ImageField {
+    Path(PathBuf),
+    Source(ImageSource),
+}
+
+fn encode<S: Serializer, P: image::PixelWithColorType<Subpixel = u8>>(image: &image::ImageBuffer<P, Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+    let mut bytes = Cursor::new(Vec::new());
+    image.write_to(&mut bytes, image::ImageOutputFormat::Png).map_err(serde::ser::Error::custom)?;
+    ImageSource::Base64(base64::engine::general_purpose::STANDARD.encode(bytes.into_inner())).serialize(serializer)
+}
+
+fn decode<'de, D: Deserializer<'de>>(deserializer: D) -> Result<image::DynamicImage, D::Error> {
+    let source = match ImageField::deserialize(deserializer)? {
+        ImageField::Path(path) | ImageField::Source(ImageSource::Path(path)) => ImageSource::Path(path),
+        ImageField::Source(source) => source,
+    };
+    match source {
+        ImageSource::Path(path) => image::open(&path).map_err(|error| de::Error::custom(format!("failed to open {}: {error}", path.display()))),
+        ImageSource::Base64(data) => {
+            // Accept data URLs, e.g. `data:image/png;base64,...`.
+            let data = data.split_once(";base64,").map_or(data.as_str(), |(_, data)| data);
+            let bytes = base64::engine::general_purpose::STANDARD.decode(data.trim()).map_err(de::Error::custom)?;
+            image::load_from_memory(&bytes).map_err(de::Error::custom)
+        }
+    }
+}
+
+/// Serde functions of RGB images.
+pub(crate) mod rgb_image {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(image: &image::ImageBuffer<image::Rgb<u8>, Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+        encode(image, serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<image::ImageBuffer<image::Rgb<u8>, Vec<u8>>, D::Error> {
+        Ok(decode(deserializer)?.to_rgb8())
+    }
+}
+
+/// Serde functions of grayscale images.
+pub(crate) mod luma_image {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(image: &image::ImageBuffer<image::Luma<u8>, Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+        encode(image, serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<image::ImageBuffer<image::Luma<u8>, Vec<u8>>, D::Error> {
+        Ok(decode(deserializer)?.to_luma8())
+    }
+}
+
+/// Serde functions of optional RGB images.
+pub(crate) mod optional_rgb_image {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(image: &Option<image::RgbImage>, serializer: S) -> Result<S::Ok, S::Error> {
+        match image {
+            Some(image) => encode(image, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<image::RgbImage>, D::Error> {
+        #[derive(Deserialize)]
+        struct Wrapper(#[serde(deserialize_with = "decode")] image::DynamicImage);
+        Ok(Option::<Wrapper>::deserialize(deserializer)?.map(|Wrapper(image)| image.to_rgb8()))
+    }
+}
+
+/// Serde functions of optional grayscale images.
+pub(crate) mod optional_luma_image {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(image: &Option<image::GrayImage>, serializer: S) -> Result<S::Ok, S::Error> {
+        match image {
+            Some(image) => encode(image, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<image::GrayImage>, D::Error> {
+        #[derive(Deserialize)]
+        struct Wrapper(#[serde(deserialize_with = "decode")] image::DynamicImage);
+        Ok(Option::<Wrapper>::deserialize(deserializer)?.map(|Wrapper(image)| image.to_luma8()))
+    }
+}
+
+/// Serde functions of data types, named `f16`, `bf16`, `f32`...
+pub(crate) mod dtype {
+    use super::*;
+    use candle::DType;
+
+    pub fn serialize<S: Serializer>(dtype: &DType, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(dtype.as_str())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DType, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        match name.as_str() {
+            "u8" => Ok(DType::U8),
+            "u32" => Ok(DType::U32),
+            "i64" => Ok(DType::I64),
+            "bf16" => Ok(DType::BF16),
+            "f16" => Ok(DType::F16),
+            "f32" => Ok(DType::F32),
+            "f64" => Ok(DType::F64),
+            _ => Err(de::Error::unknown_variant(&name, &["u8", "u32", "i64", "bf16", "f16", "f32", "f64"])),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{File, GenerationParameters};
+
+    #[test]
+    fn generation_parameters_round_trip() {
+        let image = image::ImageBuffer::from_fn(16, 8, |x, y| image::Rgb([x as u8, y as u8, 7]));
+        let parameters = GenerationParameters::new("A green apple").with_img2img(Some(image.clone())).with_seed(Some(3));
+        let json = serde_json::to_string(&parameters).unwrap();
+        let parameters: GenerationParameters = serde_json::from_str(&json).unwrap();
+        assert_eq!(parameters.img2img, Some(image));
+        assert_eq!(parameters.seed, Some(3));
+
+        let parameters: GenerationParameters = serde_json::from_str(r#"{"prompt": "A red apple"}"#).unwrap();
+        assert_eq!(parameters.img2img_strength, GenerationParameters::new("").img2img_strength);
+
+        let file: File = serde_json::from_str(r#""unet.safetensors""#).unwrap();
+        assert!(matches!(file, File::Path(_)));
+    }
+}
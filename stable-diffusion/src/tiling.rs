@@ -0,0 +1,222 @@
+//! A module for generating canvases larger than the native size of the models, in overlapping tiles.
+
+use candle::{DType, Device, Tensor};
+use serde::{Deserialize, Serialize};
+
+use crate::{Result, StableDiffusionError};
+
+/// The `TileWeighting` enum is used to specify how the overlapping tiles are averaged.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TileWeighting {
+    /// Every pixel of a tile has the same weight.
+    Uniform,
+    /// The weights decrease from the center of a tile, with a standard deviation in fractions of the tile size.
+    Gaussian {
+        sigma: f64,
+    },
+}
+
+impl Default for TileWeighting {
+    fn default() -> Self {
+        Self::Gaussian { sigma: 0.3 }
+    }
+}
+
+impl TileWeighting {
+    /// Get the `(1, 1, height, width)` weights of a tile.
+    fn weights(&self, height: usize, width: usize, device: &Device) -> Result<Tensor> {
+        let weights = match *self {
+            Self::Uniform => vec![1f32; height * width],
+            Self::Gaussian { sigma } => {
+                let gaussian = |size: usize| -> Vec<f64> {
+                    let center = (size as f64 - 1.0) / 2.0;
+                    let sigma = sigma * size as f64;
+                    (0 .. size).map(|x| (-(x as f64 - center).powi(2) / (2.0 * sigma * sigma)).exp()).collect()
+                };
+                let (rows, columns) = (gaussian(height), gaussian(width));
+                rows.iter().flat_map(|row| columns.iter().map(move |column| (row * column) as f32)).collect()
+            }
+        };
+        Ok(Tensor::from_vec(weights, (1, 1, height, width), device)?)
+    }
+}
+
+/// A part of the latents, in latent pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Window {
+    pub top: usize,
+    pub left: usize,
+    pub height: usize,
+    pub width: usize,
+}
+
+impl Window {
+    /// Crop the window of a `(batch, channels, height, width)` tensor whose resolution is `scale` times the latent one.
+    pub fn crop(&self, tensor: &Tensor, scale: usize) -> Result<Tensor> {
+        Ok(tensor.narrow(2, self.top * scale, self.height * scale)?.narrow(3, self.left * scale, self.width * scale)?)
+    }
+
+    /// Get the window at a resolution `scale` times higher.
+    pub fn scale(&self, scale: usize) -> Self {
+        Self { top: self.top * scale, left: self.left * scale, height: self.height * scale, width: self.width * scale }
+    }
+}
+
+/// Get the evenly spread starts of the tiles covering a size with at least an overlap.
+fn tile_starts(size: usize, tile: usize, overlap: usize) -> Vec<usize> {
+    if size <= tile {
+        return vec![0];
+    }
+    let count = (size - overlap).div_ceil(tile - overlap);
+    (0 .. count).map(|index| ((index * (size - tile)) as f64 / (count - 1) as f64).round() as usize).collect()
+}
+
+/// Blend overlapping tiles of `(batch, channels, tile_height, tile_width)` tensors into a `(height, width)` tensor.
+fn blend(windows: &[Window], height: usize, width: usize, weighting: TileWeighting, device: &Device, mut tile: impl FnMut(&Window) -> Result<Tensor>) -> Result<Tensor> {
+    let mut sum: Option<Tensor> = None;
+    let mut weight_sum: Option<Tensor> = None;
+    for window in windows {
+        let weights = weighting.weights(window.height, window.width, device)?;
+        let pad = |tensor: Tensor| -> Result<Tensor> {
+            Ok(tensor
+                .pad_with_zeros(2, window.top, height - window.top - window.height)?
+                .pad_with_zeros(3, window.left, width - window.left - window.width)?)
+        };
+        let value = tile(window)?;
+        let value = pad(value.to_dtype(DType::F32)?.broadcast_mul(&weights)?)?;
+        let weights = pad(weights)?;
+        sum = Some(match sum {
+            None => value,
+            Some(sum) => (sum + value)?,
+        });
+        weight_sum = Some(match weight_sum {
+            None => weights,
+            Some(weight_sum) => (weight_sum + weights)?,
+        });
+    }
+    match (sum, weight_sum) {
+        (Some(sum), Some(weight_sum)) => Ok(sum.broadcast_div(&weight_sum)?),
+        _ => Err(StableDiffusionError::invalid_parameters("the canvas has no tiles")),
+    }
+}
+
+/// Get the windows of the tiles covering a canvas, in the unit of the sizes.
+fn windows(height: usize, width: usize, tile: usize, overlap: usize) -> Vec<Window> {
+    let (tile_height, tile_width) = (tile.min(height), tile.min(width));
+    tile_starts(height, tile, overlap)
+        .into_iter()
+        .flat_map(|top| tile_starts(width, tile, overlap).into_iter().map(move |left| Window { top, left, height: tile_height, width: tile_width }))
+        .collect()
+}
+
+/// The `TiledDiffusion` struct is used to denoise a large canvas in overlapping tiles, as in MultiDiffusion.
+///
+/// The UNet predicts the noise of every tile at each step, and the overlapping predictions are averaged with the tile
+/// weights. The latents can also be decoded in tiles, which bounds the memory of the VAE.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TiledDiffusion {
+    /// The size of the tiles, in pixels.
+    pub tile_size: usize,
+    /// The minimum overlap of the tiles, in pixels.
+    pub overlap: usize,
+    pub weighting: TileWeighting,
+    /// The `(tile_size, overlap)` of the VAE decoding tiles in pixels, or `None` to decode the latents at once.
+    pub vae_tiling: Option<(usize, usize)>,
+}
+
+impl TiledDiffusion {
+    /// Create a new `TiledDiffusion` instance with Gaussian weights, decoding the latents in tiles of 512 pixels.
+    pub fn new(tile_size: usize, overlap: usize) -> Self {
+        let weighting = Default::default();
+        let vae_tiling = Some((512, 64));
+        Self { tile_size, overlap, weighting, vae_tiling }
+    }
+
+    /// Sets the weighting of the tiles.
+    pub fn with_weighting(self, weighting: TileWeighting) -> Self {
+        Self { weighting, ..self }
+    }
+
+    /// Sets the `(tile_size, overlap)` of the VAE decoding tiles in pixels.
+    pub fn with_vae_tiling(self, vae_tiling: Option<(usize, usize)>) -> Self {
+        Self { vae_tiling, ..self }
+    }
+
+    /// Check if the tiling is valid.
+    pub fn validate(&self) -> Result<()> {
+        let Self { tile_size, overlap, weighting, vae_tiling } = *self;
+        // The UNet downsamples the latents three times.
+        if tile_size == 0 || tile_size % 64 != 0 {
+            return Err(StableDiffusionError::invalid_parameters(format!("the tile size must be a positive multiple of 64, got {tile_size}")));
+        }
+        if overlap % 8 != 0 || overlap >= tile_size {
+            return Err(StableDiffusionError::invalid_parameters(format!("the tile overlap must be a multiple of 8 smaller than the tile size, got {overlap}")));
+        }
+        if let TileWeighting::Gaussian { sigma } = weighting {
+            if !(sigma.is_finite() && sigma > 0.0) {
+                return Err(StableDiffusionError::invalid_parameters(format!("the sigma of the tile weights must be positive, got {sigma}")));
+            }
+        }
+        if let Some((tile_size, overlap)) = vae_tiling {
+            if tile_size == 0 || tile_size % 8 != 0 || overlap % 8 != 0 || overlap >= tile_size {
+                return Err(StableDiffusionError::invalid_parameters(format!("the VAE tiles must be multiples of 8 with a smaller overlap, got {tile_size} and {overlap}")));
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the windows of the tiles covering latents.
+    pub(crate) fn windows(&self, latent_height: usize, latent_width: usize) -> Vec<Window> {
+        windows(latent_height, latent_width, self.tile_size / 8, self.overlap / 8)
+    }
+
+    /// Blend the noise predictions of the tiles covering latents.
+    pub(crate) fn blend(&self, latent_height: usize, latent_width: usize, device: &Device, tile: impl FnMut(&Window) -> Result<Tensor>) -> Result<Tensor> {
+        blend(&self.windows(latent_height, latent_width), latent_height, latent_width, self.weighting, device, tile)
+    }
+}
+
+/// Decode `(1, 4, height, width)` latents in overlapping tiles into a `(1, 3, height * 8, width * 8)` tensor in `[-1, 1]`.
+///
+/// The tiles are normalized separately by the decoder, so a large overlap hides their seams better.
+pub(crate) fn decode_tiled(latents: &Tensor, tile_size: usize, overlap: usize, mut decode: impl FnMut(&Tensor) -> Result<Tensor>) -> Result<Tensor> {
+    let (_, _, latent_height, latent_width) = latents.dims4()?;
+    // The tiles are laid out in latent pixels, so that every decoded tile is blended exactly where it was decoded.
+    let windows = windows(latent_height, latent_width, tile_size / 8, overlap / 8).iter().map(|window| window.scale(8)).collect::<Vec<_>>();
+    blend(&windows, latent_height * 8, latent_width * 8, TileWeighting::default(), latents.device(), |window| {
+        let window = Window { top: window.top / 8, left: window.left / 8, height: window.height / 8, width: window.width / 8 };
+        decode(&window.crop(latents, 1)?)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tiles_cover_the_canvas() {
+        let tiling = TiledDiffusion::new(512, 128);
+        let windows = tiling.windows(64, 200);
+        assert_eq!(windows.len(), 4);
+        assert_eq!(windows.last().map(|window| window.left + window.width), Some(200));
+
+        // A constant prediction stays constant once the overlapping tiles are averaged.
+        let blended = tiling.blend(64, 200, &Device::Cpu, |window| Ok(Tensor::ones((1, 4, window.height, window.width), DType::F32, &Device::Cpu)?)).unwrap();
+        let values = blended.flatten_all().unwrap().to_vec1::<f32>().unwrap();
+        assert!(values.iter().all(|value| (value - 1.0).abs() < 1e-5));
+    }
+
+    #[test]
+    fn tiled_decoding_keeps_the_tiles_in_place() {
+        // 200 latent pixels, i.e. 1600 pixels, don't split evenly into 512 pixel tiles.
+        let latents = Tensor::arange(0f32, 3.0 * 8.0 * 200.0, &Device::Cpu).unwrap().reshape((1, 3, 8, 200)).unwrap();
+        let upsample = |latents: &Tensor| -> Result<Tensor> {
+            let (_, _, height, width) = latents.dims4()?;
+            Ok(latents.upsample_nearest2d(height * 8, width * 8)?)
+        };
+        let tiled = decode_tiled(&latents, 512, 64, upsample).unwrap();
+        let difference = (tiled - upsample(&latents).unwrap()).unwrap().abs().unwrap().flatten_all().unwrap().max(0).unwrap().to_scalar::<f32>().unwrap();
+        assert!(difference < 1e-2, "{difference}");
+    }
+}
@@ -2,9 +2,14 @@
 
 use candle_transformers::models::stable_diffusion::StableDiffusionConfig;
 
-use crate::{File, StableDiffusionVersion};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{File, Repository, Result, StableDiffusionError, StableDiffusionVersion};
 
 /// The `TokenizerWeights` struct is used to specify the weights of the Tokenizer model.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TokenizerWeights {
     /// The weights of the first Tokenizer model.
     pub tokenizer: File,
@@ -13,39 +18,39 @@ pub struct TokenizerWeights {
 }
 
 impl TokenizerWeights {
-    fn tokenizer1(version: StableDiffusionVersion) -> File {
-        let tokenizer_repo = match version {
-            StableDiffusionVersion::V1_5 | StableDiffusionVersion::V2_1 => {
-                "openai/clip-vit-base-patch32"
-            }
-            StableDiffusionVersion::XL | StableDiffusionVersion::Turbo => {
-                // This seems similar to the patch32 version except some very small
-                // difference in the split regex.
-                "openai/clip-vit-large-patch14"
-            }
-        };
-        File::Repository(crate::Repository::new(tokenizer_repo, "tokenizer.json"))
-    }
-
-    fn tokenizer2() -> File {
-        File::Repository(crate::Repository::new("laion/CLIP-ViT-bigG-14-laion2B-39B-b160k", "tokenizer.json"))
-    }
-
     /// Create a new `TokenizerWeights` instance from a file.
+    ///
+    /// The files can be `tokenizer.json` files, or `vocab.json` files with a `merges.txt` file in the same folder.
     pub fn from_file(tokenizer: File, tokenizer2: Option<File>) -> Self {
         Self { tokenizer, tokenizer2 }
     }
 
-    /// Create a new `TokenizerWeights` instance from a repository.
-    pub fn from_repository(version: StableDiffusionVersion) -> Self {
-        let tokenizer = Self::tokenizer1(version);
-        let tokenizer2 = if matches!(version, StableDiffusionVersion::XL | StableDiffusionVersion::Turbo) {
-            Some(Self::tokenizer2())
+    /// Create a new `TokenizerWeights` instance from the `tokenizer` and `tokenizer_2` folders of a diffusers repository.
+    pub fn from_repository(repository: &str, version: StableDiffusionVersion) -> Self {
+        let tokenizer = File::Repository(Repository::new(repository, "tokenizer/vocab.json"));
+        let tokenizer2 = if version.is_xl() {
+            Some(File::Repository(Repository::new(repository, "tokenizer_2/vocab.json")))
         } else {
             None
         };
         Self::from_file(tokenizer, tokenizer2)
     }
+
+    /// Fetch a tokenizer file, with the `merges.txt` file of a `vocab.json` file.
+    pub(crate) fn fetch(file: &File) -> Result<PathBuf> {
+        let path = file.fetch()?;
+        if is_vocab(&path) {
+            file.sibling(MERGES).fetch()?;
+        }
+        Ok(path)
+    }
+}
+
+const VOCAB: &str = "vocab.json";
+const MERGES: &str = "merges.txt";
+
+fn is_vocab(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| name == VOCAB)
 }
 
 /// The `Tokenizer` struct is used to specify the Tokenizer model.
@@ -57,23 +62,95 @@ pub struct Tokenizer {
 
 impl Tokenizer {
     /// Create a new `Tokenizer` instance from a configuration and weights.
-    pub fn new(config: &StableDiffusionConfig, file: impl AsRef<std::path::Path>) -> anyhow::Result<Tokenizer> {
-        let tokenizer = tokenizers::Tokenizer::from_file(file).map_err(anyhow::Error::msg)?;
-        let pad_id = match &config.clip.pad_with {
-            Some(padding) => *tokenizer.get_vocab(true).get(padding.as_str()).unwrap(),
-            None => *tokenizer.get_vocab(true).get("<|endoftext|>").unwrap(),
+    ///
+    /// The file can be a `tokenizer.json` file, or a `vocab.json` file with a `merges.txt` file in the same folder.
+    pub fn new(config: &StableDiffusionConfig, file: impl AsRef<std::path::Path>) -> Result<Tokenizer> {
+        Self::from_clip_config(&config.clip, file)
+    }
+
+    /// Create a new `Tokenizer` instance from the configuration of the CLIP model it feeds.
+    pub(crate) fn from_clip_config(config: &candle_transformers::models::stable_diffusion::clip::Config, file: impl AsRef<std::path::Path>) -> Result<Tokenizer> {
+        let file = file.as_ref();
+        let tokenizer = if is_vocab(file) {
+            Self::from_vocab_and_merges(file, &file.with_file_name(MERGES))?
+        } else {
+            tokenizers::Tokenizer::from_file(file)?
         };
-        let max_position_embeddings = config.clip.max_position_embeddings;
+        let padding = config.pad_with.as_deref().unwrap_or("<|endoftext|>");
+        let pad_id = tokenizer
+            .token_to_id(padding)
+            .ok_or_else(|| StableDiffusionError::Tokenizer(format!("the padding token {padding} isn't in the vocabulary")))?;
+        let max_position_embeddings = config.max_position_embeddings;
         Ok(Tokenizer { pad_id, tokenizer, max_position_embeddings })
     }
 
+    /// Build the byte-level BPE tokenizer of CLIP from its vocabulary and merges, as the `tokenizer.json` files describe it.
+    fn from_vocab_and_merges(vocab: &Path, merges: &Path) -> Result<tokenizers::Tokenizer> {
+        use tokenizers::normalizers::{Lowercase, NormalizerWrapper, Replace, NFC};
+        use tokenizers::pre_tokenizers::byte_level::ByteLevel;
+        use tokenizers::pre_tokenizers::split::{Split, SplitPattern};
+        use tokenizers::pre_tokenizers::PreTokenizerWrapper;
+        use tokenizers::processors::roberta::RobertaProcessing;
+        use tokenizers::models::bpe::BPE;
+        use tokenizers::{DecoderWrapper, Model, PostProcessorWrapper, SplitDelimiterBehavior};
+
+        const START: &str = "<|startoftext|>";
+        const END: &str = "<|endoftext|>";
+        let path = |path: &Path| path.to_str().map(str::to_string).ok_or_else(|| StableDiffusionError::Tokenizer(format!("invalid path {}", path.display())));
+        let bpe = BPE::from_file(&path(vocab)?, &path(merges)?)
+            .unk_token(END.into())
+            .end_of_word_suffix("</w>".into())
+            .fuse_unk(false)
+            .build()?;
+        let token_id = |token: &str| bpe
+            .token_to_id(token)
+            .ok_or_else(|| StableDiffusionError::Tokenizer(format!("the token {token} isn't in the vocabulary")));
+        let (start, end) = (token_id(START)?, token_id(END)?);
+        let normalizer = tokenizers::normalizers::Sequence::new(vec![
+            NormalizerWrapper::from(NFC),
+            NormalizerWrapper::from(Replace::new(tokenizers::normalizers::replace::ReplacePattern::Regex(r"\s+".into()), " ")?),
+            NormalizerWrapper::from(Lowercase),
+        ]);
+        let split = Split::new(
+            SplitPattern::Regex(r"'s|'t|'re|'ve|'m|'ll|'d|[\p{L}]+|[\p{N}]|[^\s\p{L}\p{N}]+".into()),
+            SplitDelimiterBehavior::Removed,
+            true,
+        )?;
+        let pre_tokenizer = tokenizers::pre_tokenizers::sequence::Sequence::new(vec![
+            PreTokenizerWrapper::from(split),
+            PreTokenizerWrapper::from(ByteLevel::new(false, true, false)),
+        ]);
+        let post_processor = RobertaProcessing::new((END.into(), end), (START.into(), start))
+            .trim_offsets(false)
+            .add_prefix_space(false);
+        let mut tokenizer = tokenizers::Tokenizer::new(bpe);
+        tokenizer
+            .with_normalizer(NormalizerWrapper::from(normalizer))
+            .with_pre_tokenizer(PreTokenizerWrapper::from(pre_tokenizer))
+            .with_post_processor(PostProcessorWrapper::from(post_processor))
+            .with_decoder(DecoderWrapper::from(ByteLevel::default()));
+        Ok(tokenizer)
+    }
+
+    /// Get the id of the padding token.
+    pub(crate) fn pad_id(&self) -> u32 {
+        self.pad_id
+    }
+
     /// Tokenize a text into a vector of tokens.
-    pub fn tokenize(&self, text: &str) -> anyhow::Result<Vec<u32>> {
+    ///
+    /// Texts longer than the CLIP context are truncated, keeping their end token, with a warning.
+    pub fn tokenize(&self, text: &str) -> Result<Vec<u32>> {
         let mut tokens = self.tokenizer
-            .encode(text, true)
-            .map_err(anyhow::Error::msg)?
+            .encode(text, true)?
             .get_ids()
             .to_vec();
+        if tokens.len() > self.max_position_embeddings {
+            tracing::warn!(tokens = tokens.len(), max_tokens = self.max_position_embeddings, "the prompt is truncated");
+            let end = tokens[tokens.len() - 1];
+            tokens.truncate(self.max_position_embeddings - 1);
+            tokens.push(end);
+        }
         while tokens.len() < self.max_position_embeddings {
             tokens.push(self.pad_id)
         }
@@ -81,7 +158,7 @@ impl Tokenizer {
     }
 
     /// Tokenize a pair of texts into a vector of tokens.
-    pub fn tokenize_pair(&self, prompt: &str, cond_prompt: Option<&str>) -> anyhow::Result<(Vec<u32>, Option<Vec<u32>>)> {
+    pub fn tokenize_pair(&self, prompt: &str, cond_prompt: Option<&str>) -> Result<(Vec<u32>, Option<Vec<u32>>)> {
         let prompt = self.tokenize(prompt)?;
         let cond_prompt = match cond_prompt {
             Some(cond_prompt) => Some(self.tokenize(cond_prompt)?),
@@ -90,3 +167,25 @@ impl Tokenizer {
         Ok((prompt, cond_prompt))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tokenizer_from_vocab_and_merges() {
+        let folder = std::env::temp_dir().join("clip-vocab-test");
+        std::fs::create_dir_all(&folder).unwrap();
+        std::fs::write(folder.join(VOCAB), r#"{"<|startoftext|>": 0, "<|endoftext|>": 1, "a": 2, "p": 3, "p</w>": 4, "ap</w>": 5}"#).unwrap();
+        std::fs::write(folder.join(MERGES), "#version: 0.2\na p</w>\n").unwrap();
+        let config = StableDiffusionConfig::v1_5(None, None, None);
+        let tokenizer = Tokenizer::new(&config, folder.join(VOCAB)).unwrap();
+        let tokens = tokenizer.tokenize("AP").unwrap();
+        assert_eq!(&tokens[.. 3], &[0, 5, 1]);
+        assert_eq!(tokens.len(), config.clip.max_position_embeddings);
+        let tokens = tokenizer.tokenize(&"ap ".repeat(100)).unwrap();
+        assert_eq!(tokens.len(), config.clip.max_position_embeddings);
+        assert_eq!(tokens[.. 3], [0, 5, 5]);
+        assert_eq!(tokens[tokens.len() - 2 ..], [5, 1]);
+    }
+}
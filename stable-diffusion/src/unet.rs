@@ -3,11 +3,15 @@
 use std::path::Path;
 
 use candle::{DType, Device, Tensor};
-use candle_transformers::models::stable_diffusion::{unet_2d::UNet2DConditionModel, StableDiffusionConfig};
 
-use crate::File;
+use crate::models::nn::VarBuilder;
+use crate::models::unet_2d::{BlockConfig, UNet2DConditionModel, UNet2DConditionModelConfig, UNetConditioning};
+use serde::{Deserialize, Serialize};
+
+use crate::{Architecture, File, Result, StableDiffusionVersion};
 
 /// The `UNetWeights` struct is used to specify the weights of the UNet model.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UNetWeights {
     /// The weights of the UNet model.
     pub file: File,
@@ -36,19 +40,79 @@ impl UNetWeights {
     }
 }
 
-
+/// The `UNet` struct is used to specify the UNet model.
 pub struct UNet {
     unet: UNet2DConditionModel
 }
 
 impl UNet {
-    pub fn new(weights: impl AsRef<Path>, config: &StableDiffusionConfig, device: &Device, dtype: DType) -> candle::Result<Self> {
-        let use_flash_attention = false;
-        let unet = config.build_unet(weights, &device, 4, use_flash_attention, dtype)?;
+    /// Create a new `UNet` instance from weights, a version, device, and data type.
+    ///
+    /// GGUF weights, e.g. written by `quantize_weights`, are loaded with quantized linear layers.
+    pub fn new(weights: impl AsRef<Path>, version: StableDiffusionVersion, device: &Device, dtype: DType) -> Result<Self> {
+        Self::with_sliced_attention(weights, version, None, device, dtype)
+    }
+
+    /// Create a new `UNet` instance computing the attention in slices of a size, `Some(0)` picking it automatically.
+    pub fn with_sliced_attention(weights: impl AsRef<Path>, version: StableDiffusionVersion, sliced_attention_size: Option<usize>, device: &Device, dtype: DType) -> Result<Self> {
+        Self::from_var_builder(VarBuilder::from_file(weights, device, dtype)?, version, sliced_attention_size)
+    }
+
+    /// Create a new `UNet` instance from a `VarBuilder`.
+    pub(crate) fn from_var_builder(vs: VarBuilder, version: StableDiffusionVersion, sliced_attention_size: Option<usize>) -> Result<Self> {
+        let config = UNet2DConditionModelConfig { sliced_attention_size, ..version.unet_config() };
+        let unet = UNet2DConditionModel::new(vs, version.unet_in_channels(), 4, config)?;
         Ok(Self { unet })
     }
 
-    pub fn forward(&self, latent: &Tensor, timestep: f64, text_embeddings: &Tensor) -> candle::Result<Tensor> {
-        self.unet.forward(latent, timestep, &text_embeddings)
+    /// Predict the noise of the latents at a timestep.
+    pub fn forward(&self, latent: &Tensor, timestep: f64, text_embeddings: &Tensor) -> Result<Tensor> {
+        Ok(self.unet.forward(latent, timestep, text_embeddings)?)
+    }
+
+    /// Predict the noise of the latents at a timestep, adding the residuals of ControlNets to the down and mid blocks.
+    pub fn forward_with_residuals(&self, latent: &Tensor, timestep: f64, text_embeddings: &Tensor, down_residuals: &[Tensor], mid_residual: &Tensor) -> Result<Tensor> {
+        let conditioning = UNetConditioning {
+            down_block_additional_residuals: Some(down_residuals),
+            mid_block_additional_residual: Some(mid_residual),
+            ..Default::default()
+        };
+        self.forward_with_conditioning(latent, timestep, text_embeddings, &conditioning)
+    }
+
+    /// Predict the noise of the latents at a timestep with additional conditioning.
+    pub(crate) fn forward_with_conditioning(&self, latent: &Tensor, timestep: f64, text_embeddings: &Tensor, conditioning: &UNetConditioning) -> Result<Tensor> {
+        Ok(self.unet.forward_with_conditioning(latent, timestep, text_embeddings, conditioning)?)
+    }
+}
+
+impl StableDiffusionVersion {
+    pub(crate) fn unet_config(&self) -> UNet2DConditionModelConfig {
+        let bc = |out_channels, use_cross_attn, attention_head_dim| BlockConfig { out_channels, use_cross_attn, attention_head_dim };
+        let (blocks, cross_attention_dim, use_linear_projection) = match self.architecture() {
+            Architecture::V1 => (vec![bc(320, Some(1), 8), bc(640, Some(1), 8), bc(1280, Some(1), 8), bc(1280, None, 8)], 768, false),
+            Architecture::V2 => (vec![bc(320, Some(1), 5), bc(640, Some(1), 10), bc(1280, Some(1), 20), bc(1280, None, 20)], 1024, true),
+            Architecture::XL => (vec![bc(320, None, 5), bc(640, Some(2), 10), bc(1280, Some(10), 20)], 2048, true),
+        };
+        UNet2DConditionModelConfig {
+            center_input_sample: false,
+            flip_sin_to_cos: true,
+            freq_shift: 0.,
+            blocks,
+            layers_per_block: 2,
+            downsample_padding: 1,
+            mid_block_scale_factor: 1.,
+            norm_num_groups: 32,
+            norm_eps: 1e-5,
+            cross_attention_dim,
+            sliced_attention_size: None,
+            use_linear_projection,
+            time_cond_proj_dim: self.guidance_embedding_dim(),
+        }
+    }
+
+    /// Get the number of input channels of the UNet, which also gets the mask and the masked image latents when inpainting.
+    pub(crate) fn unet_in_channels(&self) -> usize {
+        if self.is_inpainting() { 9 } else { 4 }
     }
 }
\ No newline at end of file
@@ -0,0 +1,186 @@
+//! Real-ESRGAN upscalers, enlarging the generated images in overlapping tiles.
+
+use std::path::Path;
+
+use candle::{DType, Device, Module, Tensor};
+
+use crate::models::nn::VarBuilder;
+use crate::models::rrdbnet::RRDBNet;
+use crate::vae::tensor_to_image;
+use crate::{File, Result, StableDiffusionError};
+
+/// The `UpscalerWeights` struct is used to specify the weights of an upscaler.
+pub struct UpscalerWeights {
+    /// The weights of the upscaler.
+    pub file: File,
+}
+
+impl UpscalerWeights {
+    /// Create a new `UpscalerWeights` instance from a file.
+    pub fn from_file(file: impl Into<File>) -> Self {
+        let file = file.into();
+        Self { file }
+    }
+
+    /// Create a new `UpscalerWeights` instance from a safetensors file in a repository.
+    pub fn from_repository(repository: impl Into<String>, path: impl AsRef<Path>) -> Self {
+        let file = File::Repository(crate::Repository::new(repository.into(), path));
+        Self::from_file(file)
+    }
+}
+
+/// The `UpscalerConfig` struct is used to configure the RRDB network of an upscaler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpscalerConfig {
+    /// The upscaling factor, 2 or 4.
+    pub scale: usize,
+    /// The number of features of the convolutions.
+    pub num_feat: usize,
+    /// The number of residual-in-residual dense blocks.
+    pub num_block: usize,
+    /// The number of channels each convolution of a dense block adds.
+    pub num_grow_ch: usize,
+}
+
+impl UpscalerConfig {
+    /// The configuration of `RealESRGAN_x4plus`.
+    pub fn real_esrgan_x4plus() -> Self {
+        Self { scale: 4, num_feat: 64, num_block: 23, num_grow_ch: 32 }
+    }
+
+    /// The configuration of `RealESRGAN_x2plus`.
+    pub fn real_esrgan_x2plus() -> Self {
+        Self { scale: 2, ..Self::real_esrgan_x4plus() }
+    }
+
+    /// The configuration of `RealESRGAN_x4plus_anime_6B`.
+    pub fn real_esrgan_x4plus_anime_6b() -> Self {
+        Self { num_block: 6, ..Self::real_esrgan_x4plus() }
+    }
+}
+
+/// The `Upscaler` struct is used to specify an ESRGAN-family upscaler.
+///
+/// The image is processed in tiles, each one padded with its neighborhood so the seams don't show.
+pub struct Upscaler {
+    net: RRDBNet,
+    scale: usize,
+    tile_size: usize,
+    tile_padding: usize,
+    device: Device,
+    dtype: DType,
+}
+
+impl Upscaler {
+    /// Create a new `Upscaler` instance from weights, a configuration, device, and data type.
+    pub fn new(weights: impl AsRef<Path>, config: UpscalerConfig, device: &Device, dtype: DType) -> Result<Self> {
+        if config.scale != 2 && config.scale != 4 {
+            return Err(StableDiffusionError::invalid_parameters(format!("the upscaling factor must be 2 or 4, got {}", config.scale)));
+        }
+        let vs = VarBuilder::from_file(weights, device, dtype)?;
+        let net = RRDBNet::new(vs, 3, 3, config.scale, config.num_feat, config.num_block, config.num_grow_ch)?;
+        let scale = config.scale;
+        let tile_size = 256;
+        let tile_padding = 16;
+        let device = device.clone();
+        Ok(Self { net, scale, tile_size, tile_padding, device, dtype })
+    }
+
+    /// Sets the size of the tiles and the padding they are processed with, in input pixels.
+    pub fn with_tiling(self, tile_size: usize, tile_padding: usize) -> Result<Self> {
+        if tile_size < 8 {
+            return Err(StableDiffusionError::invalid_parameters(format!("the tile size must be at least 8, got {tile_size}")));
+        }
+        Ok(Self { tile_size, tile_padding, ..self })
+    }
+
+    /// Get the upscaling factor.
+    pub fn scale(&self) -> usize {
+        self.scale
+    }
+
+    /// Upscale an image.
+    pub fn upscale(&self, image: &image::ImageBuffer<image::Rgb<u8>, Vec<u8>>) -> Result<image::ImageBuffer<image::Rgb<u8>, Vec<u8>>> {
+        let _span = tracing::debug_span!("upscale", scale = self.scale).entered();
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let input = Tensor::from_vec(image.as_raw().clone(), (height, width, 3), &self.device)?
+            .permute((2, 0, 1))?
+            .to_dtype(self.dtype)?
+            .affine(1. / 255., 0.)?
+            .unsqueeze(0)?;
+        // The 2x network folds the pixels into channels, so the tiles must be aligned on even pixels.
+        let align = 4 / self.scale;
+        let padded_width = width.div_ceil(align) * align;
+        let padded_height = height.div_ceil(align) * align;
+        let input = input
+            .pad_with_same(2, 0, padded_height - height)?
+            .pad_with_same(3, 0, padded_width - width)?;
+
+        let scale = self.scale as u32;
+        let mut output = image::ImageBuffer::new(padded_width as u32 * scale, padded_height as u32 * scale);
+        for y in (0 .. padded_height).step_by(self.tile_size) {
+            for x in (0 .. padded_width).step_by(self.tile_size) {
+                let tile_width = self.tile_size.min(padded_width - x);
+                let tile_height = self.tile_size.min(padded_height - y);
+                let padded_x = x.saturating_sub(self.tile_padding) / align * align;
+                let padded_y = y.saturating_sub(self.tile_padding) / align * align;
+                let padded_right = ((x + tile_width + self.tile_padding).div_ceil(align) * align).min(padded_width);
+                let padded_bottom = ((y + tile_height + self.tile_padding).div_ceil(align) * align).min(padded_height);
+                let tile = input
+                    .narrow(2, padded_y, padded_bottom - padded_y)?
+                    .narrow(3, padded_x, padded_right - padded_x)?;
+                let tile = tensor_to_image(&self.net.forward(&tile)?)?;
+                let tile = image::imageops::crop_imm(
+                    &tile,
+                    (x - padded_x) as u32 * scale,
+                    (y - padded_y) as u32 * scale,
+                    tile_width as u32 * scale,
+                    tile_height as u32 * scale,
+                ).to_image();
+                image::imageops::replace(&mut output, &tile, x as i64 * scale as i64, y as i64 * scale as i64);
+            }
+        }
+        Ok(image::imageops::crop_imm(&output, 0, 0, width as u32 * scale, height as u32 * scale).to_image())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    /// Save zeroed weights of a tiny 2x network.
+    fn tiny_weights(path: &Path, config: UpscalerConfig) -> candle::Result<()> {
+        let (feat, grow) = (config.num_feat, config.num_grow_ch);
+        let mut convs = vec![("conv_first".to_string(), 3 * 4, feat)];
+        for block in 0 .. config.num_block {
+            for rdb in 1 ..= 3 {
+                for conv in 0 .. 5 {
+                    let out = if conv == 4 { feat } else { grow };
+                    convs.push((format!("body.{block}.rdb{rdb}.conv{}", conv + 1), feat + conv * grow, out));
+                }
+            }
+        }
+        for name in ["conv_body", "conv_up1", "conv_up2", "conv_hr"] {
+            convs.push((name.to_string(), feat, feat));
+        }
+        convs.push(("conv_last".to_string(), feat, 3));
+        let mut tensors = HashMap::new();
+        for (name, in_channels, out_channels) in convs {
+            tensors.insert(format!("{name}.weight"), Tensor::zeros((out_channels, in_channels, 3, 3), DType::F32, &Device::Cpu)?);
+            tensors.insert(format!("{name}.bias"), Tensor::zeros(out_channels, DType::F32, &Device::Cpu)?);
+        }
+        candle::safetensors::save(&tensors, path)
+    }
+
+    #[test]
+    fn tiled_upscaling_keeps_the_aspect() {
+        let config = UpscalerConfig { scale: 2, num_feat: 4, num_block: 1, num_grow_ch: 2 };
+        let path = std::env::temp_dir().join("tiny-rrdbnet.safetensors");
+        tiny_weights(&path, config).unwrap();
+        let upscaler = Upscaler::new(&path, config, &Device::Cpu, DType::F32).unwrap().with_tiling(8, 2).unwrap();
+        let image = upscaler.upscale(&image::ImageBuffer::new(13, 9)).unwrap();
+        assert_eq!(image.dimensions(), (26, 18));
+    }
+}
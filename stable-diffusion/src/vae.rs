@@ -1,13 +1,17 @@
 //! Variational Autoencoder (VAE) for Stable Diffusion models.
 
 use candle_transformers::models::stable_diffusion::{
-    vae::{AutoEncoderKL, DiagonalGaussianDistribution}, StableDiffusionConfig
+    vae::{AutoEncoderKL, AutoEncoderKLConfig, DiagonalGaussianDistribution}, StableDiffusionConfig
 };
 use candle::{DType, Device, Tensor, IndexOp};
 
-use crate::{File, StableDiffusionVersion};
+use serde::{Deserialize, Serialize};
+
+use crate::models::nn::{HostWeights, VarBuilder};
+use crate::{decode_tiled, File, Result, StableDiffusionError, StableDiffusionVersion};
 
 /// The `VAEWeights` struct is used to specify the weights of the Variational Autoencoder (VAE) model.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VAEWeights {
     pub file: File,
 }
@@ -23,7 +27,7 @@ impl VAEWeights {
         let use_f16 = dtype == DType::F16;
         let repository = repository.into();
         let (repo, filename) = if use_f16 {
-            if matches!(version, StableDiffusionVersion::XL | StableDiffusionVersion::Turbo) {
+            if version.is_xl() {
                 let repo = "madebyollin/sdxl-vae-fp16-fix";
                 let filename = "diffusion_pytorch_model.safetensors";
                 (repo, filename)
@@ -50,14 +54,26 @@ pub struct VAE {
 
 impl VAE {
     /// Create a new `VAE` instance from a configuration, weights, device, and data type.
-    pub fn new(config: &StableDiffusionConfig, vae_weights: impl AsRef<std::path::Path>, device: &Device, dtype: DType) -> anyhow::Result<Self> {
+    pub fn new(config: &StableDiffusionConfig, vae_weights: impl AsRef<std::path::Path>, device: &Device, dtype: DType) -> Result<Self> {
         
-        let vae = config.build_vae(vae_weights, &device, dtype)?;
+        let vae = config.build_vae(vae_weights, device, dtype)?;
+        Ok(Self { vae })
+    }
+
+    /// Create a new `VAE` instance from weights kept in the CPU memory, moving them to a device.
+    pub(crate) fn from_host_weights(weights: &HostWeights, device: &Device, dtype: DType) -> Result<Self> {
+        let vs = match weights.var_builder(device, dtype)? {
+            VarBuilder::Float(vs) => vs,
+            VarBuilder::Quantized(..) => return Err(StableDiffusionError::version_mismatch("the VAE can't be loaded from quantized weights")),
+        };
+        // The autoencoder is the same for every version.
+        let config = AutoEncoderKLConfig { block_out_channels: vec![128, 256, 512, 512], layers_per_block: 2, latent_channels: 4, norm_num_groups: 32 };
+        let vae = AutoEncoderKL::new(vs, 3, 3, config)?;
         Ok(Self { vae })
     }
 
     /// Encode an image into a latent distribution.
-    pub fn image_to_latent(&self, image: image::ImageBuffer<image::Rgb<u8>, Vec<u8>>, device: &Device, dtype: DType) -> candle::Result<DiagonalGaussianDistribution> {
+    pub fn image_to_latent(&self, image: image::ImageBuffer<image::Rgb<u8>, Vec<u8>>, device: &Device, dtype: DType) -> Result<DiagonalGaussianDistribution> {
         let (height, width) = (image.height() as usize, image.width() as usize);
         let image = image.into_raw();
         let tensor = Tensor::from_vec(image, (height, width, 3), device)?
@@ -65,35 +81,43 @@ impl VAE {
             .to_dtype(dtype)?
             .affine(2. / 255., -1.)?
             .unsqueeze(0)?;
-        Ok(self.encode(&tensor)?)
+        self.encode(&tensor)
     }
 
     /// Decode a latent distribution into an image.
-    pub fn latent_to_image(&self, latents: &Tensor, vae_scale: f64) -> candle::Result<image::ImageBuffer<image::Rgb<u8>, Vec<u8>>> {
+    pub fn latent_to_image(&self, latents: &Tensor, vae_scale: f64) -> Result<image::ImageBuffer<image::Rgb<u8>, Vec<u8>>> {
         let image = self.vae.decode(&(latents / vae_scale)?)?;
-        let image = ((image / 2.)? + 0.5)?.to_device(&Device::Cpu)?;
-        let image = (image.clamp(0f32, 1.)? * 255.)?.to_dtype(DType::U8)?.i(0)?;
-        let (channel, height, width) = image.dims3()?;
-        if channel != 3 {
-            candle::bail!("save_image expects an input of shape (3, height, width)")
-        }
-        let image = image.permute((1, 2, 0))?.flatten_all()?;
-        let pixels = image.to_vec1::<u8>()?;
-        let image: image::ImageBuffer<image::Rgb<u8>, Vec<u8>> =
-            match image::ImageBuffer::from_raw(width as u32, height as u32, pixels) {
-                Some(image) => image,
-                None => candle::bail!("error saving image"),
-            };
-        Ok(image)
+        tensor_to_image(&((image / 2.)? + 0.5)?)
+    }
+
+    /// Decode a latent distribution into an image in overlapping tiles of `tile_size` pixels, to bound the memory.
+    pub fn latent_to_image_tiled(&self, latents: &Tensor, vae_scale: f64, tile_size: usize, overlap: usize) -> Result<image::ImageBuffer<image::Rgb<u8>, Vec<u8>>> {
+        let image = decode_tiled(&(latents / vae_scale)?, tile_size, overlap, |tile| self.decode(tile))?;
+        tensor_to_image(&((image / 2.)? + 0.5)?)
     }
 
     /// Encode a tensor into a latent distribution.
-    pub fn encode(&self, tensor: &Tensor) -> candle::Result<DiagonalGaussianDistribution> {
+    pub fn encode(&self, tensor: &Tensor) -> Result<DiagonalGaussianDistribution> {
         Ok(self.vae.encode(tensor)?)
     }
 
     /// Decode a latent distribution into a tensor.
-    pub fn decode(&self, tensor: &Tensor) -> candle::Result<Tensor> {
+    pub fn decode(&self, tensor: &Tensor) -> Result<Tensor> {
         Ok(self.vae.decode(tensor)?)
     }
-}
\ No newline at end of file
+}
+/// Convert the first image of a `(batch, 3, height, width)` tensor with values in `[0, 1]` into an image.
+pub(crate) fn tensor_to_image(image: &Tensor) -> Result<image::ImageBuffer<image::Rgb<u8>, Vec<u8>>> {
+    let image = image.to_device(&Device::Cpu)?.to_dtype(DType::F32)?;
+    let image = (image.clamp(0f32, 1.)? * 255.)?.to_dtype(DType::U8)?.i(0)?;
+    let (channel, height, width) = image.dims3()?;
+    if channel != 3 {
+        return Err(StableDiffusionError::shape_mismatch(format!("expected 3 image channels, got {channel}")));
+    }
+    let image = image.permute((1, 2, 0))?.flatten_all()?;
+    let pixels = image.to_vec1::<u8>()?;
+    match image::ImageBuffer::from_raw(width as u32, height as u32, pixels) {
+        Some(image) => Ok(image),
+        None => Err(StableDiffusionError::shape_mismatch("the decoded pixels don't match the image size")),
+    }
+}
@@ -0,0 +1,279 @@
+//! Würstchen pipeline, generating image embeddings with a text-conditioned prior and decoding them with a second diffusion stage.
+
+use candle::{DType, Device, Tensor};
+use candle_transformers::models::stable_diffusion::build_clip_transformer;
+use candle_transformers::models::stable_diffusion::clip::{ClipTextTransformer, Config as ClipConfig};
+use candle_transformers::models::wuerstchen::ddpm::DDPMWScheduler;
+use candle_transformers::models::wuerstchen::diffnext::WDiffNeXt;
+use candle_transformers::models::wuerstchen::paella_vq::PaellaVQ;
+use candle_transformers::models::wuerstchen::prior::WPrior;
+
+use crate::vae::tensor_to_image;
+use crate::{File, GenerationParameters, GuidanceSchedule, Noise, Pipeline, PromptSchedule, PromptTerm, Repository, Result, StableDiffusionError, Tokenizer, TokenizerWeights};
+
+/// The number of channels of the image embeddings generated by the prior.
+const PRIOR_CIN: usize = 16;
+/// The number of channels of the latents of the decoder.
+const DECODER_CIN: usize = 4;
+/// The ratio between the image resolution and the resolution of the image embeddings.
+const RESOLUTION_MULTIPLE: f64 = 42.67;
+/// The ratio between the resolution of the decoder latents and the resolution of the image embeddings.
+const LATENT_DIM_SCALE: f64 = 10.67;
+/// The factor the decoder latents are scaled by before being decoded by the VQGAN.
+const VQGAN_SCALE: f64 = 0.3764;
+
+/// The `WuerstchenWeights` struct is used to specify the weights of the Würstchen models.
+pub struct WuerstchenWeights {
+    /// The weights of the prior.
+    pub prior: File,
+    /// The weights of the text encoder of the prior.
+    pub prior_clip: File,
+    /// The tokenizer of the text encoder of the prior.
+    pub prior_tokenizer: File,
+    /// The weights of the decoder.
+    pub decoder: File,
+    /// The weights of the text encoder of the decoder.
+    pub clip: File,
+    /// The tokenizer of the text encoder of the decoder.
+    pub tokenizer: File,
+    /// The weights of the VQGAN decoding the latents into images.
+    pub vqgan: File,
+}
+
+impl Default for WuerstchenWeights {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WuerstchenWeights {
+    /// Create a new `WuerstchenWeights` instance from the `warp-ai/wuerstchen-prior` and `warp-ai/wuerstchen` repositories.
+    pub fn new() -> Self {
+        Self::from_repositories("warp-ai/wuerstchen-prior", "warp-ai/wuerstchen")
+    }
+
+    /// Create a new `WuerstchenWeights` instance from the repositories of the prior and of the decoder.
+    pub fn from_repositories(prior_repository: impl Into<String>, decoder_repository: impl Into<String>) -> Self {
+        let prior_repository = prior_repository.into();
+        let decoder_repository = decoder_repository.into();
+        let prior_file = |path| File::Repository(Repository::new(&prior_repository, path));
+        let decoder_file = |path| File::Repository(Repository::new(&decoder_repository, path));
+        Self {
+            prior: prior_file("prior/diffusion_pytorch_model.safetensors"),
+            prior_clip: prior_file("text_encoder/model.safetensors"),
+            prior_tokenizer: prior_file("tokenizer/vocab.json"),
+            decoder: decoder_file("decoder/diffusion_pytorch_model.safetensors"),
+            clip: decoder_file("text_encoder/model.safetensors"),
+            tokenizer: decoder_file("tokenizer/vocab.json"),
+            vqgan: decoder_file("vqgan/diffusion_pytorch_model.safetensors"),
+        }
+    }
+
+    /// Sets the weights of the prior.
+    pub fn with_prior(self, prior: impl Into<File>) -> Self {
+        Self { prior: prior.into(), ..self }
+    }
+
+    /// Sets the weights of the decoder.
+    pub fn with_decoder(self, decoder: impl Into<File>) -> Self {
+        Self { decoder: decoder.into(), ..self }
+    }
+}
+
+/// The `WuerstchenParameters` struct is used to specify the parameters of the Würstchen pipeline.
+pub struct WuerstchenParameters {
+    pub weights: WuerstchenWeights,
+    pub device: Device,
+    pub dtype: DType,
+    pub decoder_steps: usize,
+}
+
+impl WuerstchenParameters {
+    /// Create a new `WuerstchenParameters` instance from weights, device, and data type.
+    pub fn new(weights: WuerstchenWeights, device: Device, dtype: DType) -> Self {
+        let decoder_steps = 12;
+        Self { weights, device, dtype, decoder_steps }
+    }
+
+    /// Sets the number of steps of the decoder.
+    pub fn with_decoder_steps(self, decoder_steps: usize) -> Self {
+        Self { decoder_steps, ..self }
+    }
+}
+
+/// The `Wuerstchen` struct is used to specify the Würstchen pipeline.
+///
+/// The number of steps and the guidance scale of the generation parameters apply to the prior,
+/// the decoder runs a fixed number of steps without guidance.
+pub struct Wuerstchen {
+    device: Device,
+    dtype: DType,
+    decoder_steps: usize,
+    prior_tokenizer: Tokenizer,
+    prior_clip: ClipTextTransformer,
+    tokenizer: Tokenizer,
+    clip: ClipTextTransformer,
+    prior: WPrior,
+    decoder: WDiffNeXt,
+    vqgan: PaellaVQ,
+}
+
+impl Wuerstchen {
+    /// Create a new `Wuerstchen` instance from parameters.
+    pub fn new(parameters: WuerstchenParameters) -> Result<Self> {
+        let WuerstchenParameters { weights, device, dtype, decoder_steps } = parameters;
+        if decoder_steps == 0 {
+            return Err(StableDiffusionError::invalid_parameters("the number of decoder steps must be positive"));
+        }
+        let use_flash_attn = cfg!(feature = "flash-attn");
+        let var_builder = |file: &File| -> Result<candle_nn::VarBuilder<'static>> {
+            Ok(unsafe { candle_nn::VarBuilder::from_mmaped_safetensors(&[file.fetch()?], dtype, &device)? })
+        };
+
+        let prior_config = ClipConfig::wuerstchen_prior();
+        let prior_tokenizer = Tokenizer::from_clip_config(&prior_config, TokenizerWeights::fetch(&weights.prior_tokenizer)?)?;
+        let prior_clip = build_clip_transformer(&prior_config, weights.prior_clip.fetch()?, &device, dtype)?;
+        let config = ClipConfig::wuerstchen();
+        let tokenizer = Tokenizer::from_clip_config(&config, TokenizerWeights::fetch(&weights.tokenizer)?)?;
+        let clip = build_clip_transformer(&config, weights.clip.fetch()?, &device, dtype)?;
+
+        let prior = WPrior::new(PRIOR_CIN, 1536, 1280, 64, 32, 24, use_flash_attn, var_builder(&weights.prior)?)?;
+        let decoder = WDiffNeXt::new(DECODER_CIN, DECODER_CIN, 64, 1024, 1024, 2, use_flash_attn, var_builder(&weights.decoder)?)?;
+        let vqgan = PaellaVQ::new(var_builder(&weights.vqgan)?)?;
+        Ok(Self { device, dtype, decoder_steps, prior_tokenizer, prior_clip, tokenizer, clip, prior, decoder, vqgan })
+    }
+
+    /// Generate an image from the models.
+    ///
+    /// Only the initial noise of the two stages is seeded, the noise added by the scheduler between the steps isn't.
+    pub fn generate(&self, args: impl Into<GenerationParameters>) -> Result<image::ImageBuffer<image::Rgb<u8>, Vec<u8>>> {
+        let args = args.into();
+        args.validate()?;
+        Self::check_supported(&args)?;
+        let width = args.width.unwrap_or(1024);
+        let height = args.height.unwrap_or(1024);
+        let n_steps = args.n_steps.unwrap_or(60);
+        let guidance_scale = args.guidance_scale.unwrap_or(4.0);
+        let use_guide_scale = guidance_scale > 1.0;
+        let noise = args.noise();
+
+        let image_embeddings = {
+            let _span = tracing::info_span!("prior", n_steps, guidance_scale).entered();
+            let uncond_prompt = if use_guide_scale { Some(args.uncond_prompt.as_str()) } else { None };
+            let text_embeddings = Self::encode(&self.prior_tokenizer, &self.prior_clip, &args.prompt, uncond_prompt, &self.device)?;
+            let latent_height = (height as f64 / RESOLUTION_MULTIPLE).ceil() as usize;
+            let latent_width = (width as f64 / RESOLUTION_MULTIPLE).ceil() as usize;
+            let latents = noise.generate((1, PRIOR_CIN, latent_height, latent_width), &self.device)?.to_dtype(self.dtype)?;
+            let latents = self.denoise(latents, n_steps, |latents, ratio| {
+                if use_guide_scale {
+                    let latent_model_input = Tensor::cat(&[latents, latents], 0)?;
+                    let ratio = Tensor::cat(&[ratio, ratio], 0)?;
+                    let noise_pred = self.prior.forward(&latent_model_input, &ratio, &text_embeddings)?.chunk(2, 0)?;
+                    let (noise_pred_text, noise_pred_uncond) = (&noise_pred[0], &noise_pred[1]);
+                    noise_pred_uncond + ((noise_pred_text - noise_pred_uncond)? * guidance_scale)?
+                } else {
+                    self.prior.forward(latents, ratio, &text_embeddings)
+                }
+            })?;
+            ((latents * 42.)? - 1.)?
+        };
+
+        let latents = {
+            let _span = tracing::info_span!("decoder", n_steps = self.decoder_steps).entered();
+            let text_embeddings = Self::encode(&self.tokenizer, &self.clip, &args.prompt, None, &self.device)?;
+            let (_, _, embeddings_height, embeddings_width) = image_embeddings.dims4()?;
+            let latent_height = (embeddings_height as f64 * LATENT_DIM_SCALE) as usize;
+            let latent_width = (embeddings_width as f64 * LATENT_DIM_SCALE) as usize;
+            let latents = Noise::new(noise.seed.wrapping_add(1))
+                .generate((1, DECODER_CIN, latent_height, latent_width), &self.device)?
+                .to_dtype(self.dtype)?;
+            self.denoise(latents, self.decoder_steps, |latents, ratio| {
+                self.decoder.forward(latents, ratio, &image_embeddings, Some(&text_embeddings))
+            })?
+        };
+
+        tracing::debug_span!("vqgan_decode").in_scope(|| {
+            let image = self.vqgan.decode(&(latents * VQGAN_SCALE)?)?;
+            tensor_to_image(&image)
+        })
+    }
+
+    /// Check that the generation parameters only use the features supported by the pipeline.
+    fn check_supported(args: &GenerationParameters) -> Result<()> {
+        let unsupported = [
+            ("style prompts", args.style_prompt.is_some() || args.uncond_style_prompt.is_some()),
+            ("image to image", args.img2img.is_some()),
+            ("masks", args.mask.is_some()),
+            ("seed resizing", args.seed_resize_from.is_some()),
+            ("prompt embeddings", args.prompt_embeds.is_some()),
+            ("ControlNets", !args.controls.is_empty()),
+            ("image prompts", !args.image_prompts.is_empty()),
+            ("regional prompts", !args.regions.is_empty()),
+            ("tiled diffusion", args.tiling.is_some()),
+            ("guidance variants", args.guidance_rescale != 0.0 || args.dynamic_thresholding.is_some() || args.guidance_schedule != GuidanceSchedule::Constant || args.guidance_interval.is_some()),
+            ("composable prompts", PromptTerm::parse(&args.prompt).len() > 1),
+            ("prompt scheduling", PromptSchedule::parse(&args.prompt, args.n_steps.unwrap_or(60)).texts.len() > 1),
+        ];
+        match unsupported.iter().find(|(_, used)| *used) {
+            Some((feature, _)) => Err(StableDiffusionError::invalid_parameters(format!("Würstchen doesn't support {feature}"))),
+            None => Ok(()),
+        }
+    }
+
+    /// Encode a prompt, and an unconditional prompt if any, into `[cond, uncond]` text embeddings.
+    fn encode(tokenizer: &Tokenizer, clip: &ClipTextTransformer, prompt: &str, uncond_prompt: Option<&str>, device: &Device) -> Result<Tensor> {
+        let encode = |text: &str| -> Result<Tensor> {
+            let tokens = tokenizer.tokenize(text)?;
+            // The embeddings are masked after the end of text token, which is also the padding token.
+            let end = tokens.iter().position(|token| *token == tokenizer.pad_id()).unwrap_or(tokens.len() - 1);
+            let tokens = Tensor::new(tokens.as_slice(), device)?.unsqueeze(0)?;
+            Ok(clip.forward_with_mask(&tokens, end)?)
+        };
+        let text_embeddings = encode(prompt)?;
+        match uncond_prompt {
+            Some(uncond_prompt) => Ok(Tensor::cat(&[text_embeddings, encode(uncond_prompt)?], 0)?),
+            None => Ok(text_embeddings),
+        }
+    }
+
+    /// Run the DDPM sampling of a stage, predicting the noise from the latents and the timestep ratio.
+    fn denoise(&self, mut latents: Tensor, n_steps: usize, predict: impl Fn(&Tensor, &Tensor) -> candle::Result<Tensor>) -> Result<Tensor> {
+        let scheduler = DDPMWScheduler::new(n_steps, Default::default())?;
+        let timesteps = scheduler.timesteps();
+        // The last timestep is the end of the schedule, there's nothing left to denoise.
+        for (index, &timestep) in timesteps[.. timesteps.len() - 1].iter().enumerate() {
+            let _span = tracing::debug_span!("denoising_step", step = index + 1, timestep).entered();
+            let ratio = Tensor::full(timestep as f32, 1, &self.device)?.to_dtype(self.dtype)?;
+            let noise_pred = predict(&latents, &ratio)?;
+            latents = scheduler.step(&noise_pred, timestep, &latents)?;
+        }
+        Ok(latents)
+    }
+}
+
+impl Pipeline for Wuerstchen {
+    fn generate(&self, args: GenerationParameters) -> Result<image::ImageBuffer<image::Rgb<u8>, Vec<u8>>> {
+        Wuerstchen::generate(self, args)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn image_to_image_is_unsupported() {
+        let parameters = GenerationParameters::new("A green apple");
+        assert!(Wuerstchen::check_supported(&parameters).is_ok());
+        let parameters = parameters.with_img2img(Some(image::ImageBuffer::new(64, 64)));
+        assert!(Wuerstchen::check_supported(&parameters).is_err());
+    }
+
+    #[test]
+    fn composite_prompts_are_unsupported() {
+        assert!(Wuerstchen::check_supported(&GenerationParameters::new("a [cat] in the snow")).is_ok());
+        assert!(Wuerstchen::check_supported(&GenerationParameters::new("a cat AND a dog")).is_err());
+        assert!(Wuerstchen::check_supported(&GenerationParameters::new("a [cat:dog:0.5]")).is_err());
+        assert!(Wuerstchen::check_supported(&GenerationParameters::new("a [cat|dog]")).is_err());
+    }
+}